@@ -6,7 +6,7 @@ use std::collections::{
 };
 
 use acceptxmr::{storage::InvoiceStorage, Invoice, InvoiceId, PaymentGatewayBuilder, SubIndex};
-use log::{error, info, LevelFilter};
+use log::{info, LevelFilter};
 use thiserror::Error;
 
 #[tokio::main]
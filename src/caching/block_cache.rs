@@ -1,66 +1,238 @@
 use std::{
     cmp::{max, min},
+    collections::BTreeMap,
+    io,
+    ops::RangeInclusive,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
 };
 
-use log::{debug, trace, warn};
+use log::{debug, error, info, trace, warn};
+use monero::{
+    consensus::{encode, Decodable, Encodable},
+    VarInt,
+};
+
+use super::{CacheSnapshot, CompactTransaction};
+use crate::{rpc::RpcClient, AcceptXmrError};
 
-use crate::{rpc::RpcClient, storage::InvoiceStorage, AcceptXmrError};
+/// The block cache catches up in batches when it falls more than this many
+/// blocks behind the blockchain tip.
+pub(crate) const CATCH_UP_THRESHOLD: u64 = 3;
+/// Maximum number of blocks fetched and scanned at once while catching up.
+const CATCH_UP_BATCH_SIZE: u64 = 100;
 
 pub(crate) struct BlockCache {
     pub height: Arc<AtomicU64>,
     pub daemon_height: Arc<AtomicU64>,
-    pub blocks: Vec<(monero::Hash, u64, monero::Block, Vec<monero::Transaction>)>,
+    /// Cached blocks, newest first.
+    pub blocks: Vec<CachedBlock>,
+    /// Reorg found during the last update, if any.
+    pub reorg: Option<Reorg>,
     cache_size: usize,
+    /// IDs of the most recent `reorg_depth` blocks, keyed by height. Used to
+    /// find the fork point of reorgs deeper than the cache.
+    pub hashes: BTreeMap<u64, monero::Hash>,
+    /// Reorg found on startup, which happened while the scanner was stopped.
+    /// Reported by the first update.
+    startup_reorg: Option<Reorg>,
+    reorg_depth: usize,
     rpc_client: RpcClient,
 }
 
+/// A block in the block cache, keeping only what is needed to scan it for owned
+/// outputs and to detect reorgs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CachedBlock {
+    pub id: monero::Hash,
+    pub height: u64,
+    pub header: monero::BlockHeader,
+    pub miner_tx: CompactTransaction,
+    pub transactions: Vec<CompactTransaction>,
+}
+
+impl CachedBlock {
+    pub fn new(
+        id: monero::Hash,
+        height: u64,
+        block: monero::Block,
+        transactions: Vec<monero::Transaction>,
+    ) -> CachedBlock {
+        CachedBlock {
+            id,
+            height,
+            header: block.header,
+            miner_tx: block.miner_tx.into(),
+            transactions: transactions.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Write the block to `w`, to be read back by
+    /// [`decode`](CachedBlock::decode).
+    pub fn encode<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.id.consensus_encode(w)?;
+        self.height.consensus_encode(w)?;
+        self.header.consensus_encode(w)?;
+        self.miner_tx.encode(w)?;
+        VarInt(self.transactions.len() as u64).consensus_encode(w)?;
+        for transaction in &self.transactions {
+            transaction.encode(w)?;
+        }
+        Ok(())
+    }
+
+    /// Read a block written by [`encode`](CachedBlock::encode).
+    pub fn decode<R: io::Read>(r: &mut R) -> Result<CachedBlock, encode::Error> {
+        let id = Decodable::consensus_decode(r)?;
+        let height = Decodable::consensus_decode(r)?;
+        let header = Decodable::consensus_decode(r)?;
+        let miner_tx = CompactTransaction::decode(r)?;
+        let transaction_count = VarInt::consensus_decode(r)?.0;
+        let transactions = (0..transaction_count)
+            .map(|_| CompactTransaction::decode(r))
+            .collect::<Result<_, _>>()?;
+
+        Ok(CachedBlock {
+            id,
+            height,
+            header,
+            miner_tx,
+            transactions,
+        })
+    }
+}
+
+/// A reorg found while updating the block cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Reorg {
+    /// Height of the lowest block replaced.
+    pub fork_height: u64,
+    /// IDs of the replaced blocks, from `fork_height` upwards.
+    pub old_blocks: Vec<monero::Hash>,
+    /// IDs of the blocks replacing them, from `fork_height` upwards.
+    pub new_blocks: Vec<monero::Hash>,
+    /// Whether the fork point lies below the tracked block hashes. If so, blocks
+    /// below `fork_height` may have been orphaned too.
+    pub beyond_window: bool,
+}
+
+impl Reorg {
+    fn new(fork_height: u64, beyond_window: bool) -> Reorg {
+        Reorg {
+            fork_height,
+            old_blocks: Vec::new(),
+            new_blocks: Vec::new(),
+            beyond_window,
+        }
+    }
+}
+
 impl BlockCache {
-    pub async fn init<S: InvoiceStorage>(
+    /// Initialize the block cache with the blocks up to `initial_height`. Blocks
+    /// from the `snapshot` are reused if the daemon's blocks at their heights
+    /// still have the same IDs, and only the rest are fetched. The block IDs
+    /// tracked in the `snapshot` are checked for reorgs which happened since it
+    /// was saved.
+    pub async fn init<E>(
         rpc_client: RpcClient,
         cache_size: usize,
+        reorg_depth: usize,
         initial_height: Arc<AtomicU64>,
         daemon_height: Arc<AtomicU64>,
-    ) -> Result<BlockCache, AcceptXmrError<S::Error>> {
-        let mut blocks = Vec::with_capacity(cache_size);
-        // TODO: Get blocks concurrently.
-        for i in 0..cache_size {
-            let height = initial_height.load(Ordering::Relaxed) - i as u64;
-            let (block_id, block) = rpc_client.block(height).await?;
-            let transactions = rpc_client.block_transactions(&block).await?;
-            blocks.push((block_id, height, block, transactions));
+        snapshot: Option<&CacheSnapshot>,
+    ) -> Result<BlockCache, AcceptXmrError<E>> {
+        let top_height = initial_height.load(Ordering::Relaxed);
+        let bottom_height = top_height.saturating_sub(cache_size as u64 - 1);
+        let heights = bottom_height..=top_height;
+
+        // Reuse blocks from the snapshot which are still part of the blockchain.
+        let mut blocks: Vec<Option<CachedBlock>> = vec![None; heights.clone().count()];
+        if let Some(snapshot) = snapshot.filter(|snapshot| !snapshot.blocks.is_empty()) {
+            let block_ids = rpc_client.block_ids(heights.clone()).await?;
+            for (cached_block, (height, block_id)) in
+                blocks.iter_mut().zip(heights.clone().zip(block_ids))
+            {
+                *cached_block = snapshot
+                    .blocks
+                    .iter()
+                    .find(|block| block.height == height && block.id == block_id)
+                    .cloned();
+            }
+            let reused = blocks.iter().flatten().count();
+            info!(
+                "Reusing {} of {} blocks from cache snapshot",
+                reused,
+                blocks.len()
+            );
+        }
+
+        // Fetch the rest, one run of consecutive heights at a time.
+        let mut stale_runs: Vec<RangeInclusive<u64>> = Vec::new();
+        for (height, _) in heights
+            .clone()
+            .zip(&blocks)
+            .filter(|(_, block)| block.is_none())
+        {
+            match stale_runs.last_mut() {
+                Some(run) if *run.end() + 1 == height => *run = *run.start()..=height,
+                _ => stale_runs.push(height..=height),
+            }
         }
+        for run in stale_runs {
+            let new_blocks = rpc_client.blocks_with_transactions(run.clone()).await?;
+            for ((height, cached_block), (block_id, block, transactions)) in heights
+                .clone()
+                .zip(blocks.iter_mut())
+                .skip_while(|(height, _)| height < run.start())
+                .zip(new_blocks)
+            {
+                *cached_block = Some(CachedBlock::new(block_id, height, block, transactions));
+            }
+        }
+
+        // Newest blocks go first.
+        let mut blocks: Vec<CachedBlock> = blocks.into_iter().flatten().collect();
+        blocks.reverse();
 
         let mut block_cache_summary = String::new();
         for (i, block) in blocks.iter().enumerate() {
             block_cache_summary += &format!(
                 "Index in cache: {}\nHeight: {}\nNumber of transactions: {}\nID: {}\n\n",
                 i,
-                block.1,
-                block.3.len(),
-                block.0
+                block.height,
+                block.transactions.len(),
+                block.id
             );
         }
         trace!("Block cache initialized. Summary:\n{}", block_cache_summary);
 
-        Ok(BlockCache {
+        let mut block_cache = BlockCache {
             height: initial_height,
             daemon_height,
             blocks,
+            reorg: None,
             cache_size,
+            hashes: snapshot
+                .map(|snapshot| snapshot.hashes.clone())
+                .unwrap_or_default(),
+            startup_reorg: None,
+            reorg_depth: max(reorg_depth, cache_size),
             rpc_client,
-        })
+        };
+        block_cache.check_tracked_hashes::<E>().await?;
+        block_cache.record_hashes();
+        Ok(block_cache)
     }
 
     /// Update the block cache with newest blocks from daemon and apply reorg if
     /// one has occurred. Returns number of blocks updated.
-    pub async fn skip_ahead<S: InvoiceStorage>(
-        &mut self,
-    ) -> Result<usize, AcceptXmrError<S::Error>> {
+    pub async fn skip_ahead<E>(&mut self) -> Result<usize, AcceptXmrError<E>> {
         trace!("Checking for block cache updates");
+        self.reorg = None;
+        // Drop blocks appended while fixing a deep reorg.
+        self.blocks.truncate(self.cache_size);
         let mut updated = 0;
         let cache_height = self.height.load(Ordering::Relaxed);
         let blockchain_height = self.rpc_client.daemon_height().await?;
@@ -74,11 +246,11 @@ impl BlockCache {
                 .rev()
             {
                 let height = blockchain_height - 1 - i;
-                let (block_id, block) = self.rpc_client.block(height).await?;
-                let transactions = self.rpc_client.block_transactions(&block).await?;
+                let (block_id, block, transactions) =
+                    self.rpc_client.block_with_transactions(height).await?;
                 self.blocks
-                    .insert(0, (block_id, height, block, transactions));
-                self.blocks.remove(self.blocks.len() - 1);
+                    .insert(0, CachedBlock::new(block_id, height, block, transactions));
+                self.blocks.truncate(self.cache_size);
                 self.height.store(height, Ordering::Relaxed);
                 updated += 1;
             }
@@ -88,36 +260,47 @@ impl BlockCache {
             blockchain_height - 1,
             blockchain_height,
         );
-            updated = max(updated, self.check_and_fix_reorg::<S>().await?);
+            updated = max(updated, self.check_and_fix_reorg::<E>().await?);
+            updated = max(updated, self.check_and_fix_deep_reorg::<E>().await?);
+            self.note_replaced_blocks();
+            self.record_hashes();
             self.log_cache_summary();
         }
+        self.report_startup_reorg();
         Ok(updated)
     }
 
     /// Advance block cache by 1 block if new block is available and apply reorg
-    /// if one has occurred. Returns number of blocks updated.
-    pub async fn update<S: InvoiceStorage>(&mut self) -> Result<usize, AcceptXmrError<S::Error>> {
+    /// if one has occurred. If the cache has fallen far behind the blockchain
+    /// tip, a whole batch of blocks is added instead (see
+    /// [`catch_up`](BlockCache::catch_up)). Returns number of blocks updated.
+    pub async fn update<E>(&mut self) -> Result<usize, AcceptXmrError<E>> {
         trace!("Checking for block cache updates");
+        self.reorg = None;
+        // Drop blocks appended while catching up or fixing a deep reorg.
+        self.blocks.truncate(self.cache_size);
         let mut updated = 0;
         let blockchain_height = self.rpc_client.daemon_height().await?;
         self.daemon_height
             .store(blockchain_height, Ordering::Relaxed);
-        if self.height.load(Ordering::Relaxed) < blockchain_height - 1 {
-            let (block_id, block) = self
+        let cache_height = self.height.load(Ordering::Relaxed);
+        if blockchain_height.saturating_sub(cache_height + 1) > CATCH_UP_THRESHOLD {
+            updated = self.catch_up::<E>(blockchain_height).await?;
+        } else if cache_height < blockchain_height - 1 {
+            let (block_id, block, transactions) = self
                 .rpc_client
-                .block(self.height.load(Ordering::Relaxed) + 1)
+                .block_with_transactions(self.height.load(Ordering::Relaxed) + 1)
                 .await?;
-            let transactions = self.rpc_client.block_transactions(&block).await?;
             self.blocks.insert(
                 0,
-                (
+                CachedBlock::new(
                     block_id,
                     self.height.load(Ordering::Relaxed) + 1,
                     block,
                     transactions,
                 ),
             );
-            self.blocks.remove(self.blocks.len() - 1);
+            self.blocks.truncate(self.cache_size);
             self.height.fetch_add(1, Ordering::Relaxed);
             debug!(
                 "Cache top block height updated to {}, blockchain top block height is {}, blockchain height is {}",
@@ -128,23 +311,60 @@ impl BlockCache {
             self.log_cache_summary();
             updated += 1;
         }
-        updated = max(updated, self.check_and_fix_reorg::<S>().await?);
+        updated = max(updated, self.check_and_fix_reorg::<E>().await?);
+        updated = max(updated, self.check_and_fix_deep_reorg::<E>().await?);
+        self.note_replaced_blocks();
+        self.record_hashes();
+        self.report_startup_reorg();
 
         Ok(updated)
     }
 
+    /// Fetch the next batch of blocks concurrently, adding all of them to the
+    /// cache so that they can be scanned together. Returns the number of blocks
+    /// added.
+    async fn catch_up<E>(&mut self, blockchain_height: u64) -> Result<usize, AcceptXmrError<E>> {
+        let cache_height = self.height.load(Ordering::Relaxed);
+        let new_height = min(blockchain_height - 1, cache_height + CATCH_UP_BATCH_SIZE);
+        let new_blocks = self
+            .rpc_client
+            .blocks_with_transactions(cache_height + 1..=new_height)
+            .await?;
+
+        // Newest blocks go first.
+        let mut blocks: Vec<_> = (cache_height + 1..=new_height)
+            .zip(new_blocks)
+            .map(|(height, (block_id, block, transactions))| {
+                CachedBlock::new(block_id, height, block, transactions)
+            })
+            .collect();
+        blocks.reverse();
+        blocks.append(&mut self.blocks);
+        self.blocks = blocks;
+        self.height.store(new_height, Ordering::Relaxed);
+        info!(
+            "Catching up with the blockchain. Cache top block height updated to {}, blockchain top block height is {}",
+            new_height,
+            blockchain_height - 1,
+        );
+        self.log_cache_summary();
+        Ok(usize::try_from(new_height - cache_height).unwrap_or(usize::MAX))
+    }
+
     /// Check for reorgs, and update blocks if one has occurred.
-    async fn check_and_fix_reorg<S: InvoiceStorage>(
-        &mut self,
-    ) -> Result<usize, AcceptXmrError<S::Error>> {
+    async fn check_and_fix_reorg<E>(&mut self) -> Result<usize, AcceptXmrError<E>> {
         let mut updated = 0;
         let cache_height = self.height.load(Ordering::Relaxed);
         for i in 0..self.blocks.len() - 1 {
-            if self.blocks[i].2.header.prev_id != self.blocks[i + 1].0 {
+            if self.blocks[i].header.prev_id != self.blocks[i + 1].id {
                 warn!("Blocks in cache not consecutive! A reorg may have occurred; repairing now");
-                let (block_id, block) = self.rpc_client.block(cache_height - 1 - i as u64).await?;
-                let transactions = self.rpc_client.block_transactions(&block).await?;
-                self.blocks[i + 1] = (block_id, cache_height - 1 - i as u64, block, transactions);
+                let (block_id, block, transactions) = self
+                    .rpc_client
+                    .block_with_transactions(cache_height - 1 - i as u64)
+                    .await?;
+                self.blocks[i + 1] =
+                    CachedBlock::new(block_id, cache_height - 1 - i as u64, block, transactions);
+                self.reorg = Some(Reorg::new(cache_height - 1 - i as u64, false));
                 updated = max(updated, 1);
                 updated += 1;
             }
@@ -152,16 +372,190 @@ impl BlockCache {
         Ok(updated)
     }
 
+    /// Check whether the bottom of the cache was replaced by a reorg, by
+    /// comparing it to the tracked block hashes. If so, walk back through
+    /// `prev_id`s to the fork point, appending the new blocks to the cache so
+    /// that they are scanned again. Returns the number of blocks updated,
+    /// counting from the top of the cache.
+    async fn check_and_fix_deep_reorg<E>(&mut self) -> Result<usize, AcceptXmrError<E>> {
+        let Some(block) = self.blocks.last() else {
+            return Ok(0);
+        };
+        let (mut block_id, mut height, mut prev_id) =
+            (block.id, block.height, block.header.prev_id);
+        let cached_blocks = self.blocks.len();
+        let mut beyond_window = false;
+        loop {
+            match self.hashes.get(&height) {
+                // Nothing tracked at this height, so there is nothing to compare to.
+                None if self.blocks.len() == cached_blocks => return Ok(0),
+                // Every tracked block has been replaced.
+                None => {
+                    beyond_window = true;
+                    break;
+                }
+                Some(tracked_id) if *tracked_id == block_id => break,
+                Some(_) if height == 0 => {
+                    beyond_window = true;
+                    break;
+                }
+                Some(_) => {
+                    height -= 1;
+                    let (new_block_id, new_block, transactions) =
+                        self.rpc_client.block_with_transactions(height).await?;
+                    if new_block_id != prev_id {
+                        warn!("Block at height {height} does not match the previous block ID of the block above it. The daemon may be reorganizing");
+                    }
+                    block_id = new_block_id;
+                    prev_id = new_block.header.prev_id;
+                    self.blocks.push(CachedBlock::new(
+                        new_block_id,
+                        height,
+                        new_block,
+                        transactions,
+                    ));
+                }
+            }
+        }
+
+        if self.blocks.len() == cached_blocks {
+            return Ok(0);
+        }
+        // The lowest block appended is part of both chains (unless the fork is beyond
+        // the tracked window), so it needn't be scanned again.
+        if !beyond_window {
+            self.blocks.pop();
+            height += 1;
+        }
+
+        let cache_height = self.height.load(Ordering::Relaxed);
+        if beyond_window {
+            error!(
+                "Blockchain reorg goes deeper than the {} tracked blocks! Rescanning from height {}, but transfers from orphaned blocks below this height may still be counted",
+                self.reorg_depth, height
+            );
+        } else {
+            warn!(
+                "Blockchain reorg deeper than the block cache found. Rescanning from fork point at height {height}"
+            );
+        }
+        self.reorg = Some(Reorg::new(height, beyond_window));
+        Ok(usize::try_from(cache_height - height + 1).unwrap_or(usize::MAX))
+    }
+
+    /// Compare the tracked block IDs restored from a snapshot to the daemon's
+    /// blocks at the same heights, to find reorgs which happened while the
+    /// scanner was stopped. Tracked IDs from the fork point upwards are
+    /// forgotten.
+    async fn check_tracked_hashes<E>(&mut self) -> Result<(), AcceptXmrError<E>> {
+        let (Some(&lowest), Some(&highest)) =
+            (self.hashes.keys().next(), self.hashes.keys().next_back())
+        else {
+            return Ok(());
+        };
+        // Blocks above the blockchain tip have been orphaned too.
+        let blockchain_top = self.daemon_height.load(Ordering::Relaxed).saturating_sub(1);
+        let heights = lowest..=min(highest, blockchain_top);
+        let block_ids: BTreeMap<u64, monero::Hash> = if heights.is_empty() {
+            BTreeMap::new()
+        } else {
+            heights
+                .clone()
+                .zip(self.rpc_client.block_ids(heights).await?)
+                .collect()
+        };
+
+        let Some(fork_height) = self
+            .hashes
+            .iter()
+            .find(|(height, block_id)| block_ids.get(height) != Some(block_id))
+            .map(|(height, _)| *height)
+        else {
+            return Ok(());
+        };
+        let beyond_window = fork_height == lowest;
+        if beyond_window {
+            error!(
+                "Blockchain reorg deeper than the {} tracked blocks happened while the scanner was stopped! Transfers from orphaned blocks below height {} may still be counted",
+                self.reorg_depth, fork_height
+            );
+        } else {
+            warn!(
+                "Blockchain reorg happened while the scanner was stopped. Fork point is at height {fork_height}"
+            );
+        }
+
+        let mut reorg = Reorg::new(fork_height, beyond_window);
+        reorg.old_blocks = self.hashes.split_off(&fork_height).into_values().collect();
+        reorg.new_blocks = block_ids.range(fork_height..).map(|(_, id)| *id).collect();
+        self.startup_reorg = Some(reorg);
+        Ok(())
+    }
+
+    /// Report the reorg found on startup, if any, as found during this update.
+    /// A reorg found by the update itself takes precedence.
+    fn report_startup_reorg(&mut self) {
+        if let Some(reorg) = self.startup_reorg.take() {
+            self.reorg.get_or_insert(reorg);
+        }
+    }
+
+    /// Number of recent block hashes tracked to find the fork point of reorgs.
+    pub fn reorg_depth(&self) -> usize {
+        self.reorg_depth
+    }
+
+    /// Note the IDs of the blocks replaced by the reorg found during this update
+    /// (if any), and of the blocks replacing them. Must be called before the
+    /// new IDs are recorded.
+    fn note_replaced_blocks(&mut self) {
+        let Some(reorg) = &mut self.reorg else {
+            return;
+        };
+        reorg.old_blocks = self
+            .hashes
+            .range(reorg.fork_height..)
+            .map(|(_, block_id)| *block_id)
+            .collect();
+        reorg.new_blocks = self
+            .blocks
+            .iter()
+            .rev()
+            .filter(|block| block.height >= reorg.fork_height)
+            .map(|block| block.id)
+            .collect();
+    }
+
+    /// Record the IDs of the blocks in the cache, forgetting any that fall
+    /// outside of the tracked window.
+    fn record_hashes(&mut self) {
+        let cache_height = self.height.load(Ordering::Relaxed);
+        // Hashes are only useful if they connect to the cache.
+        let bottom_height = self
+            .blocks
+            .last()
+            .map_or(cache_height, |block| block.height);
+        if !self.hashes.contains_key(&bottom_height.saturating_sub(1)) {
+            self.hashes.clear();
+        }
+        for block in &self.blocks {
+            self.hashes.insert(block.height, block.id);
+        }
+        self.hashes = self
+            .hashes
+            .split_off(&(cache_height + 1).saturating_sub(self.reorg_depth as u64));
+    }
+
     fn log_cache_summary(&self) {
         let mut block_cache_summary = String::new();
-        for i in 0..self.blocks.len() {
+        for (i, block) in self.blocks.iter().enumerate() {
             block_cache_summary += &format!(
                 "Index in cache: {}\nHeight: {}\nNumber of transactions: {}\nID: {}\nPrevious ID: {}\n\n",
                 i,
-                self.blocks[i].1,
-                self.blocks[i].3.len(),
-                self.blocks[i].0,
-                self.blocks[i].2.header.prev_id,
+                block.height,
+                block.transactions.len(),
+                block.id,
+                block.header.prev_id,
             );
         }
         trace!("Block cache summary:\n{}", block_cache_summary);
@@ -0,0 +1,221 @@
+use std::{io, ops::AddAssign};
+
+use monero::{
+    blockdata::transaction::{Error, TransactionPrefix},
+    consensus::{encode, Decodable, Encodable},
+    cryptonote::{
+        hash::Hashable,
+        onetime_key::{KeyGenerator, SubKeyChecker},
+        subaddress,
+    },
+    util::ringct::{RctSigBase, RctType},
+    Amount, PublicKey, Transaction, TxIn, VarInt,
+};
+
+/// The parts of a [`Transaction`] needed to scan it for owned outputs: its
+/// output keys, transaction public keys and encrypted amounts.
+///
+/// Inputs, ring signatures and range proofs make up most of a transaction's
+/// size, and are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompactTransaction {
+    /// Hash of the full transaction.
+    pub hash: monero::Hash,
+    /// Whether this is a miner transaction.
+    pub coinbase: bool,
+    /// The transaction prefix, without inputs.
+    pub prefix: TransactionPrefix,
+    /// Encrypted amounts and output commitments, for confidential transactions.
+    rct_sig_base: Option<RctSigBase>,
+}
+
+impl CompactTransaction {
+    /// Iterate over the transaction's outputs to find those owned by the
+    /// `checker`'s view pair, as [`Transaction::check_outputs_with`] would,
+    /// adding the outputs checked to `stats`.
+    ///
+    /// The key derivation is computed once per transaction public key, rather
+    /// than once per output. Outputs whose view tag doesn't match it are ruled
+    /// out, and the rest are fully checked using the same derivation.
+    pub fn check_outputs_with(
+        &self,
+        checker: &SubKeyChecker<'_>,
+        stats: &mut OutputStats,
+    ) -> Result<Vec<OwnedOutput>, Error> {
+        let outputs = &self.prefix.outputs;
+        stats.outputs += outputs.len();
+
+        // Use the additional public keys if there are any, one per output.
+        // Otherwise, every output uses the transaction public key.
+        let extra = self.prefix.extra.try_parse();
+        let additional_pubkeys = extra.tx_additional_pubkeys();
+        let has_additional_pubkeys = additional_pubkeys.is_some();
+        let tx_pubkeys = match additional_pubkeys {
+            Some(tx_pubkeys) => tx_pubkeys,
+            None => vec![extra.tx_pubkey().ok_or(Error::NoTxPublicKey)?],
+        };
+        let derivations: Vec<(PublicKey, KeyGenerator)> = tx_pubkeys
+            .into_iter()
+            .map(|tx_pubkey| (tx_pubkey, KeyGenerator::from_key(checker.keys, tx_pubkey)))
+            .collect();
+
+        let mut owned_outputs = Vec::new();
+        for (i, output) in outputs.iter().enumerate() {
+            let derivation = if has_additional_pubkeys {
+                derivations.get(i)
+            } else {
+                derivations.first()
+            };
+            let Some((tx_pubkey, key_generator)) = derivation else {
+                break;
+            };
+            let Some(key) = output.target.as_one_time_key() else {
+                continue;
+            };
+            let view_tag_matches = u8::try_from(i).map_or(true, |index| {
+                output.target.check_view_tag(key_generator.rv, index)
+            });
+            if !view_tag_matches {
+                stats.view_tag_rejections += 1;
+                continue;
+            }
+            let Some(sub_index) = checker.check_with_key_generator(key_generator.clone(), i, &key)
+            else {
+                continue;
+            };
+
+            owned_outputs.push(OwnedOutput {
+                index: i,
+                sub_index: *sub_index,
+                amount: self
+                    .open_amount(checker, tx_pubkey, i)?
+                    .or(match output.amount {
+                        VarInt(0) => None,
+                        VarInt(amount) => Some(Amount::from_pico(amount)),
+                    }),
+            });
+        }
+
+        Ok(owned_outputs)
+    }
+
+    /// Unblind the amount of the owned output at `index`. Returns `None` if
+    /// the amount isn't blinded.
+    fn open_amount(
+        &self,
+        checker: &SubKeyChecker<'_>,
+        tx_pubkey: &PublicKey,
+        index: usize,
+    ) -> Result<Option<Amount>, Error> {
+        let rct_sig_base = match &self.rct_sig_base {
+            Some(RctSigBase {
+                rct_type: RctType::Null,
+                ..
+            })
+            | None => return Ok(None),
+            Some(rct_sig_base) => rct_sig_base,
+        };
+        let ecdh_info = rct_sig_base
+            .ecdh_info
+            .get(index)
+            .ok_or(Error::MissingEcdhInfo)?;
+        let commitment = rct_sig_base
+            .out_pk
+            .get(index)
+            .ok_or(Error::MissingCommitment)?;
+        let commitment = PublicKey::from_slice(&commitment.mask.key)
+            .ok()
+            .and_then(|commitment| commitment.point.decompress())
+            .ok_or(Error::InvalidCommitment)?;
+        let opening = ecdh_info
+            .open_commitment(checker.keys, tx_pubkey, index, &commitment)
+            .ok_or(Error::InvalidCommitment)?;
+        Ok(Some(opening.amount))
+    }
+
+    /// Write the transaction to `w`, to be read back by
+    /// [`decode`](CompactTransaction::decode).
+    pub fn encode<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.hash.consensus_encode(w)?;
+        self.coinbase.consensus_encode(w)?;
+        self.prefix.consensus_encode(w)?;
+        self.rct_sig_base.is_some().consensus_encode(w)?;
+        if let Some(rct_sig_base) = &self.rct_sig_base {
+            rct_sig_base.consensus_encode(w)?;
+        }
+        Ok(())
+    }
+
+    /// Read a transaction written by [`encode`](CompactTransaction::encode).
+    pub fn decode<R: io::Read>(r: &mut R) -> Result<CompactTransaction, encode::Error> {
+        let hash = Decodable::consensus_decode(r)?;
+        let coinbase = Decodable::consensus_decode(r)?;
+        let prefix: TransactionPrefix = Decodable::consensus_decode(r)?;
+        let rct_sig_base = if bool::consensus_decode(r)? {
+            // Pseudo outputs were dropped, so there are no inputs to read them for.
+            RctSigBase::consensus_decode(r, 0, prefix.outputs.len())?
+        } else {
+            None
+        };
+
+        Ok(CompactTransaction {
+            hash,
+            coinbase,
+            prefix,
+            rct_sig_base,
+        })
+    }
+}
+
+/// An output of a [`CompactTransaction`] owned by a [`SubKeyChecker`]'s view
+/// pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OwnedOutput {
+    /// Index of the output in the transaction.
+    pub index: usize,
+    /// Index of the subaddress the output was sent to.
+    pub sub_index: subaddress::Index,
+    /// Amount of the output, or `None` if it couldn't be unblinded.
+    pub amount: Option<Amount>,
+}
+
+/// Numbers of outputs checked for ownership.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OutputStats {
+    /// Outputs checked.
+    pub outputs: usize,
+    /// Outputs ruled out by their view tag, without a full ownership check.
+    pub view_tag_rejections: usize,
+}
+
+impl AddAssign for OutputStats {
+    fn add_assign(&mut self, other: OutputStats) {
+        self.outputs += other.outputs;
+        self.view_tag_rejections += other.view_tag_rejections;
+    }
+}
+
+impl From<Transaction> for CompactTransaction {
+    fn from(tx: Transaction) -> Self {
+        let hash = tx.hash();
+        let Transaction {
+            mut prefix,
+            rct_signatures,
+            ..
+        } = tx;
+        let coinbase = matches!(prefix.inputs.first(), Some(TxIn::Gen { .. }));
+        prefix.inputs = Vec::new();
+        let rct_sig_base = rct_signatures.sig.map(|mut rct_sig_base| {
+            // Only used to verify ring signatures.
+            rct_sig_base.pseudo_outs = Vec::new();
+            rct_sig_base
+        });
+
+        CompactTransaction {
+            hash,
+            coinbase,
+            prefix,
+            rct_sig_base,
+        }
+    }
+}
@@ -1,7 +1,14 @@
 mod block_cache;
+mod compact_transaction;
+mod snapshot;
 mod subaddress_cache;
 mod txpool_cache;
 
-pub(crate) use block_cache::BlockCache;
-pub(crate) use subaddress_cache::SubaddressCache;
+pub(crate) use block_cache::{BlockCache, CachedBlock, Reorg, CATCH_UP_THRESHOLD};
+pub(crate) use compact_transaction::{CompactTransaction, OutputStats, OwnedOutput};
+pub(crate) use snapshot::CacheSnapshot;
+pub use subaddress_cache::SubaddressAllocation;
+pub(crate) use subaddress_cache::{
+    sub_key_checker_for, subaddress_count, SubaddressCache, SubaddressCacheConfig, SubaddressRange,
+};
 pub(crate) use txpool_cache::TxpoolCache;
@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind},
+    path::Path,
+};
+
+use log::{debug, error, warn};
+use monero::{
+    consensus::{Decodable, Encodable},
+    VarInt,
+};
+use tokio::{sync::Mutex, task};
+
+use super::{block_cache::CachedBlock, BlockCache, TxpoolCache};
+
+/// Version of the snapshot format. Snapshots of any other version are ignored.
+const SNAPSHOT_VERSION: u8 = 2;
+
+/// The contents of the block cache and txpool cache, saved to disk so that they
+/// needn't be fetched from the daemon again when the scanner restarts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CacheSnapshot {
+    /// Cached blocks, newest first.
+    pub blocks: Vec<CachedBlock>,
+    /// IDs of the blocks tracked to find the fork point of reorgs, keyed by
+    /// height.
+    pub hashes: BTreeMap<u64, monero::Hash>,
+    /// Hashes of the transactions in the txpool.
+    pub txpool: Vec<monero::Hash>,
+}
+
+impl CacheSnapshot {
+    /// Load the snapshot saved at `path`. Returns `None` if there is none, or
+    /// if it can't be read.
+    pub fn load(path: &Path) -> Option<CacheSnapshot> {
+        match CacheSnapshot::read(path) {
+            Ok(snapshot) => {
+                debug!(
+                    "Loaded {} blocks and {} txpool transactions from cache snapshot",
+                    snapshot.blocks.len(),
+                    snapshot.txpool.len()
+                );
+                Some(snapshot)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!(
+                    "Failed to read cache snapshot at {}, fetching caches from the daemon instead: {e}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Save the current contents of the caches to `path`, logging any failure.
+    ///
+    /// The caches are only locked while they are copied. The snapshot is
+    /// written on a blocking thread, away from the async runtime.
+    pub async fn save(
+        path: &Path,
+        block_cache: &Mutex<BlockCache>,
+        txpool_cache: &Mutex<TxpoolCache>,
+    ) {
+        let snapshot = {
+            let block_cache = block_cache.lock().await;
+            let txpool_cache = txpool_cache.lock().await;
+            CacheSnapshot {
+                blocks: block_cache.blocks.clone(),
+                hashes: block_cache.hashes.clone(),
+                txpool: txpool_cache.hashes().copied().collect(),
+            }
+        };
+        let path_buf = path.to_path_buf();
+        match task::spawn_blocking(move || snapshot.write(&path_buf)).await {
+            Ok(Ok(())) => debug!("Saved cache snapshot to {}", path.display()),
+            Ok(Err(e)) => error!("Failed to save cache snapshot to {}: {e}", path.display()),
+            Err(e) => error!(
+                "Cache snapshot writing thread failed while saving to {}: {e}",
+                path.display()
+            ),
+        }
+    }
+
+    fn read(path: &Path) -> io::Result<CacheSnapshot> {
+        let invalid = |e| io::Error::new(ErrorKind::InvalidData, e);
+        let mut r = BufReader::new(File::open(path)?);
+
+        let version = u8::consensus_decode(&mut r).map_err(invalid)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported snapshot version {version}"),
+            ));
+        }
+        let block_count = VarInt::consensus_decode(&mut r).map_err(invalid)?.0;
+        let blocks = (0..block_count)
+            .map(|_| CachedBlock::decode(&mut r))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+        let hash_count = VarInt::consensus_decode(&mut r).map_err(invalid)?.0;
+        let hashes = (0..hash_count)
+            .map(|_| {
+                Ok((
+                    u64::consensus_decode(&mut r)?,
+                    monero::Hash::consensus_decode(&mut r)?,
+                ))
+            })
+            .collect::<Result<_, monero::consensus::encode::Error>>()
+            .map_err(invalid)?;
+        let txpool = Decodable::consensus_decode(&mut r).map_err(invalid)?;
+
+        Ok(CacheSnapshot {
+            blocks,
+            hashes,
+            txpool,
+        })
+    }
+
+    /// Write the snapshot to a temporary file first, so that a snapshot cut
+    /// short (e.g. by power loss) never replaces a complete one.
+    fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&temp_path)?);
+
+        SNAPSHOT_VERSION.consensus_encode(&mut w)?;
+        VarInt(self.blocks.len() as u64).consensus_encode(&mut w)?;
+        for block in &self.blocks {
+            block.encode(&mut w)?;
+        }
+        VarInt(self.hashes.len() as u64).consensus_encode(&mut w)?;
+        for (height, block_id) in &self.hashes {
+            height.consensus_encode(&mut w)?;
+            block_id.consensus_encode(&mut w)?;
+        }
+        self.txpool.consensus_encode(&mut w)?;
+        w.into_inner()?.sync_all()?;
+
+        fs::rename(temp_path, path)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use monero::consensus::deserialize;
+    use tempfile::Builder;
+
+    use super::{CacheSnapshot, CachedBlock};
+
+    fn read_json(path: &str) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// Test block at the given height, as it would be cached.
+    fn cached_block(height: u64) -> CachedBlock {
+        let block = read_json(&format!("tests/rpc_resources/blocks/{height}/block.json"));
+        let block_id = monero::Hash::from_slice(
+            &hex::decode(block["result"]["block_header"]["hash"].as_str().unwrap()).unwrap(),
+        );
+        let block =
+            deserialize(&hex::decode(block["result"]["blob"].as_str().unwrap()).unwrap()).unwrap();
+        // Blocks without transactions have no "txs" field.
+        let transactions = read_json(&format!(
+            "tests/rpc_resources/blocks/{height}/transactions_0.json"
+        ))["txs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|tx| deserialize(&hex::decode(tx["as_hex"].as_str().unwrap()).unwrap()).unwrap())
+            .collect();
+        CachedBlock::new(block_id, height, block, transactions)
+    }
+
+    #[test]
+    fn round_trip() {
+        let temp_dir = Builder::new().prefix("temp_snapshot_").tempdir().unwrap();
+        let path = temp_dir.path().join("cache");
+
+        assert_eq!(CacheSnapshot::load(&path), None);
+
+        let snapshot = CacheSnapshot {
+            blocks: (2477657..=2477662).rev().map(cached_block).collect(),
+            hashes: (2477653..=2477662_u64)
+                .map(|height| (height, monero::Hash::new(height.to_be_bytes())))
+                .collect(),
+            txpool: vec![monero::Hash::new([1]), monero::Hash::new([2])],
+        };
+        assert!(snapshot
+            .blocks
+            .iter()
+            .any(|block| block.transactions.is_empty()));
+        snapshot.write(&path).unwrap();
+
+        assert_eq!(CacheSnapshot::load(&path), Some(snapshot));
+    }
+
+    #[test]
+    fn ignore_invalid_snapshot() {
+        let temp_dir = Builder::new().prefix("temp_snapshot_").tempdir().unwrap();
+        let path = temp_dir.path().join("cache");
+
+        let snapshot = CacheSnapshot {
+            blocks: vec![cached_block(2477657)],
+            hashes: BTreeMap::new(),
+            txpool: Vec::new(),
+        };
+        snapshot.write(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+
+        // Cut short.
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert_eq!(CacheSnapshot::load(&path), None);
+
+        // Unknown version.
+        bytes[0] += 1;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(CacheSnapshot::load(&path), None);
+    }
+}
@@ -1,5 +1,7 @@
 use std::{
     cmp,
+    collections::{BTreeSet, HashMap},
+    ops::RangeInclusive,
     sync::{
         atomic::{AtomicU32, Ordering},
         Arc,
@@ -7,8 +9,11 @@ use std::{
 };
 
 use indexmap::{IndexMap, IndexSet};
-use log::{debug, error, warn};
-use monero::{cryptonote::subaddress, ViewPair};
+use log::{debug, info, warn};
+use monero::{
+    cryptonote::{onetime_key::SubKeyChecker, subaddress},
+    ViewPair,
+};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha12Rng;
 
@@ -19,11 +24,110 @@ use crate::{
 
 const MIN_AVAILABLE_SUBADDRESSES: u32 = 100;
 
+/// Strategy used by the [`PaymentGateway`](crate::PaymentGateway) when picking
+/// a subaddress for a new [`Invoice`](crate::Invoice).
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum SubaddressAllocation {
+    /// Pick a random available subaddress. This is the default.
+    #[default]
+    Random,
+    /// Always pick the available subaddress with the lowest index. This keeps
+    /// used indices dense, so that a wallet using a modest subaddress
+    /// lookahead (e.g. `monero-wallet-cli`'s default) will find every payment.
+    Sequential,
+}
+
+/// Configuration options for the [`SubaddressCache`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SubaddressCacheConfig {
+    pub allocation: SubaddressAllocation,
+    pub wallet_lookahead: Option<SubIndex>,
+    /// Highest minor index to use before rolling over to the next account, if
+    /// account rollover is enabled.
+    pub account_rollover: Option<u32>,
+    /// Accounts managed elsewhere, which must be skipped when rolling over.
+    pub reserved_accounts: BTreeSet<u32>,
+    pub seed: Option<u64>,
+}
+
+/// The range of subaddress indices generated by a [`SubaddressCache`]. Shared
+/// with the scanner so it knows which subaddresses to look for.
+#[derive(Debug, Clone)]
+pub(crate) struct SubaddressRange {
+    /// Account the cache started in.
+    first_major: u32,
+    /// Highest account index generated so far.
+    highest_major: Arc<AtomicU32>,
+    /// Highest minor index generated so far, across all accounts.
+    highest_minor: Arc<AtomicU32>,
+}
+
+impl SubaddressRange {
+    pub fn new(major_index: u32) -> SubaddressRange {
+        SubaddressRange {
+            first_major: major_index,
+            highest_major: Arc::new(AtomicU32::new(major_index)),
+            highest_minor: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// Returns the major and minor index ranges covered, both inclusive.
+    pub fn bounds(&self) -> (RangeInclusive<u32>, RangeInclusive<u32>) {
+        (
+            self.first_major..=self.highest_major.load(Ordering::Relaxed),
+            0..=self.highest_minor.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Creates a [`SubKeyChecker`] covering every subaddress in the given ranges.
+pub(crate) fn sub_key_checker_for<'a>(
+    viewpair: &'a ViewPair,
+    ranges: &[SubaddressRange],
+) -> SubKeyChecker<'a> {
+    let mut table = HashMap::new();
+    for range in ranges {
+        let (majors, minors) = range.bounds();
+        for major in majors {
+            for minor in minors.clone() {
+                let index = subaddress::Index { major, minor };
+                table.insert(subaddress::get_spend_public_key(viewpair, index), index);
+            }
+        }
+    }
+    SubKeyChecker {
+        table,
+        keys: viewpair,
+    }
+}
+
+/// Returns the number of distinct subaddresses covered by the given ranges.
+pub(crate) fn subaddress_count(ranges: &[SubaddressRange]) -> usize {
+    // Ranges can overlap when an account was skipped while rolling over, so
+    // count each account only once.
+    let mut highest_minor_indices: HashMap<u32, u32> = HashMap::new();
+    for range in ranges {
+        let (majors, minors) = range.bounds();
+        for major in majors {
+            let highest_minor = highest_minor_indices.entry(major).or_default();
+            *highest_minor = cmp::max(*highest_minor, *minors.end());
+        }
+    }
+    highest_minor_indices
+        .values()
+        .map(|minor| *minor as usize + 1)
+        .sum()
+}
+
 pub(crate) struct SubaddressCache {
+    /// Account (major index) new subaddresses are currently generated in.
     major_index: u32,
-    highest_minor_index: Arc<AtomicU32>,
+    /// Highest minor index generated in the current account.
+    minor_index: u32,
+    range: SubaddressRange,
     available_subaddresses: IndexMap<SubIndex, String>,
     viewpair: ViewPair,
+    config: SubaddressCacheConfig,
     rng: ChaCha12Rng,
 }
 
@@ -31,9 +135,8 @@ impl SubaddressCache {
     pub fn init<S: InvoiceStorage>(
         invoice_storage: &Store<S>,
         viewpair: monero::ViewPair,
-        major_index: u32,
-        highest_minor_index: Arc<AtomicU32>,
-        seed: Option<u64>,
+        range: SubaddressRange,
+        config: SubaddressCacheConfig,
     ) -> Result<SubaddressCache, S::Error> {
         // Get currently used subindexes from database, so they won't be put in the list
         // of available subindexes.
@@ -46,64 +149,116 @@ impl SubaddressCache {
             })
             .collect::<Result<IndexSet<SubIndex>, S::Error>>()?;
 
+        // If accounts are rolled over, resume from the highest account in use.
+        let major_index = range.first_major;
+        let mut current_major_index = major_index;
+        if config.account_rollover.is_some() {
+            if let Some(max_sub_index) = used_sub_indexes
+                .iter()
+                .filter(|sub_index| !config.reserved_accounts.contains(&sub_index.major))
+                .max()
+            {
+                current_major_index = cmp::max(major_index, max_sub_index.major);
+            }
+        }
+        let max_minor_index = config.account_rollover.unwrap_or(u32::MAX);
+
         // Get highest index from list of used subindexes.
-        let max_used = if let Some(max_sub_index) = used_sub_indexes.iter().max() {
-            debug!(
-                "Highest subaddress index in the database: {}",
-                SubIndex::new(major_index, max_sub_index.minor)
-            );
+        let max_used = if let Some(max_sub_index) = used_sub_indexes
+            .iter()
+            .filter(|sub_index| sub_index.major == current_major_index)
+            .max()
+        {
+            debug!("Highest subaddress index in the database: {max_sub_index}");
             max_sub_index.minor
         } else {
             debug!("Highest subaddress index in the database: N/A");
             0
         };
 
+        if let Some(lookahead) = config.wallet_lookahead {
+            if let Some(max_sub_index) = used_sub_indexes
+                .iter()
+                .filter(|sub_index| sub_index.major == current_major_index)
+                .max()
+            {
+                warn_if_beyond_lookahead(*max_sub_index, lookahead);
+            }
+        }
+
         // Generate enough subaddresses to cover all pending invoices.
-        highest_minor_index.store(
-            cmp::max(MIN_AVAILABLE_SUBADDRESSES - 1, max_used),
-            Ordering::Relaxed,
+        let minor_index = cmp::max(
+            cmp::min(MIN_AVAILABLE_SUBADDRESSES - 1, max_minor_index),
+            max_used,
         );
         let mut available_subaddresses: IndexMap<SubIndex, String> = generate_range(
-            SubIndex::new(major_index, 0),
-            SubIndex::new(major_index, highest_minor_index.load(Ordering::Relaxed)),
+            SubIndex::new(current_major_index, 0),
+            SubIndex::new(current_major_index, minor_index),
             &viewpair,
         )
         .into_iter()
         .collect();
 
+        // Previous accounts were filled up to the rollover index before moving on.
+        let highest_minor = if current_major_index > major_index {
+            cmp::max(minor_index, max_minor_index)
+        } else {
+            minor_index
+        };
+        range
+            .highest_major
+            .store(current_major_index, Ordering::Relaxed);
+        range.highest_minor.store(highest_minor, Ordering::Relaxed);
+
         // Remove subaddresses that are present in the database.
         available_subaddresses.retain(|sub_index, _| !used_sub_indexes.contains(sub_index));
 
         // If a seed is supplied, seed the random number generator with it.
         let mut rng = ChaCha12Rng::from_entropy();
-        if let Some(s) = seed {
+        if let Some(s) = config.seed {
             rng = ChaCha12Rng::seed_from_u64(s);
         }
 
         Ok(SubaddressCache {
-            major_index,
-            highest_minor_index,
+            major_index: current_major_index,
+            minor_index,
+            range,
             available_subaddresses,
             viewpair,
+            config,
             rng,
         })
     }
 
-    pub fn remove_random(&mut self) -> (SubIndex, String) {
-        let map_index = self.rng.gen_range(0..self.available_subaddresses.len());
-
-        if let Some((sub_index, subaddress)) =
-            self.available_subaddresses.shift_remove_index(map_index)
+    /// Removes a subaddress from the cache according to the configured
+    /// [`SubaddressAllocation`] strategy, and returns it.
+    ///
+    /// Returns `None` if all subaddresses are in use.
+    pub fn remove_next(&mut self) -> Option<(SubIndex, String)> {
+        if self.available_subaddresses.is_empty() && self.extend_by(MIN_AVAILABLE_SUBADDRESSES) == 0
         {
-            if self.len() <= MIN_AVAILABLE_SUBADDRESSES as usize {
-                self.extend_by(MIN_AVAILABLE_SUBADDRESSES);
+            return None;
+        }
+        let map_index = match self.config.allocation {
+            SubaddressAllocation::Random => {
+                self.rng.gen_range(0..self.available_subaddresses.len())
             }
-            (sub_index, subaddress)
-        } else {
-            // Is this the best way to handle this error?
-            error!("Failed to retrieve subaddress by index from subaddress cache; retrying");
-            self.remove_random()
+            SubaddressAllocation::Sequential => self
+                .available_subaddresses
+                .keys()
+                .enumerate()
+                .min_by_key(|(_, sub_index)| **sub_index)
+                .map_or(0, |(i, _)| i),
+        };
+
+        let (sub_index, subaddress) = self.available_subaddresses.shift_remove_index(map_index)?;
+        if self.len() <= MIN_AVAILABLE_SUBADDRESSES as usize {
+            self.extend_by(MIN_AVAILABLE_SUBADDRESSES);
         }
+        if let Some(lookahead) = self.config.wallet_lookahead {
+            warn_if_beyond_lookahead(sub_index, lookahead);
+        }
+        Some((sub_index, subaddress))
     }
 
     pub fn insert(&mut self, sub_index: SubIndex, address: String) -> Option<String> {
@@ -114,36 +269,99 @@ impl SubaddressCache {
         self.available_subaddresses.len()
     }
 
+    /// Returns the account index new subaddresses are currently drawn from.
+    pub fn major_index(&self) -> u32 {
+        self.major_index
+    }
+
     /// Generates `n` subaddresses at the end of the current range, and appends
     /// them to the subaddress cache.
     ///
     /// If adding `n` additional subaddresses would extend the cache beyond the
-    /// maximum index of `(1, u32::MAX)`, generation stops prematurely.
+    /// maximum minor index of the current account, generation continues in the
+    /// next account when account rollover is enabled, and stops prematurely
+    /// otherwise.
     ///
     /// Returns the number of subaddresses appended to the subaddress cache.
     pub fn extend_by(&mut self, n: u32) -> u32 {
-        // TODO: Change this to use generate_range().
+        let max_minor_index = self.config.account_rollover.unwrap_or(u32::MAX);
         let mut count = 0;
-        for _ in 0..n {
-            if self.highest_minor_index.load(Ordering::Relaxed) == u32::MAX {
-                // We're at the max, time to quit.
-                return count;
+        while count < n {
+            if self.minor_index >= max_minor_index {
+                if !self.roll_over() {
+                    warn!(
+                        "Cannot generate additional subaddresses in account {}. Minor index is saturated",
+                        self.major_index
+                    );
+                    break;
+                }
+                count += 1;
+                continue;
             }
-            let sub_index = SubIndex::new(
+            let from = SubIndex::new(self.major_index, self.minor_index + 1);
+            let to = SubIndex::new(
                 self.major_index,
-                self.highest_minor_index.load(Ordering::Relaxed) + 1,
+                self.minor_index
+                    .saturating_add(n - count)
+                    .min(max_minor_index),
             );
-            let subaddress = format!(
-                "{}",
-                subaddress::get_subaddress(&self.viewpair, sub_index.into(), None)
-            );
-            self.available_subaddresses.insert(sub_index, subaddress);
-            self.highest_minor_index
-                .store(sub_index.minor, Ordering::Relaxed);
-            count += 1;
+            let subaddresses = generate_range(from, to, &self.viewpair);
+            count += u32::try_from(subaddresses.len()).unwrap_or(u32::MAX);
+            self.available_subaddresses.extend(subaddresses);
+            self.minor_index = to.minor;
+            self.range
+                .highest_minor
+                .fetch_max(to.minor, Ordering::Relaxed);
         }
         count
     }
+
+    /// Moves on to the next account if account rollover is enabled, generating
+    /// the first subaddress of that account. Returns `false` if the current
+    /// account cannot be rolled over.
+    fn roll_over(&mut self) -> bool {
+        if self.config.account_rollover.is_none() {
+            return false;
+        }
+        // Skip over accounts managed by other subaddress caches.
+        let mut next_major_index = self.major_index;
+        loop {
+            let Some(next) = next_major_index.checked_add(1) else {
+                return false;
+            };
+            next_major_index = next;
+            if !self.config.reserved_accounts.contains(&next_major_index) {
+                break;
+            }
+        }
+        info!(
+            "Subaddresses in account {} are running low. Rolling over to account {}",
+            self.major_index, next_major_index
+        );
+        self.major_index = next_major_index;
+        let first = generate_range(
+            SubIndex::new(next_major_index, 0),
+            SubIndex::new(next_major_index, 0),
+            &self.viewpair,
+        );
+        self.available_subaddresses.extend(first);
+        self.minor_index = 0;
+        self.range
+            .highest_major
+            .fetch_max(next_major_index, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Logs a warning if the given subaddress index falls outside of the wallet's
+/// subaddress lookahead, in which case the wallet will not see funds sent to it
+/// without first increasing its lookahead.
+fn warn_if_beyond_lookahead(sub_index: SubIndex, lookahead: SubIndex) {
+    if sub_index.major >= lookahead.major || sub_index.minor >= lookahead.minor {
+        warn!(
+            "Subaddress index {sub_index} is beyond the configured wallet lookahead of {lookahead}. Your wallet will not see funds sent to this subaddress unless its lookahead is increased"
+        );
+    }
 }
 
 /// Generates range of subaddresses between the two `SubIndex`s provided,
@@ -183,12 +401,115 @@ fn generate_range(
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod test {
-    use std::{cmp::Ordering, str::FromStr};
+    use std::{cmp::Ordering, str::FromStr, sync::atomic::Ordering as AtomicOrdering};
 
     use test_case::test_case;
 
-    use super::generate_range;
-    use crate::SubIndex;
+    use super::{
+        generate_range, sub_key_checker_for, subaddress_count, SubaddressAllocation,
+        SubaddressCache, SubaddressCacheConfig, SubaddressRange,
+    };
+    use crate::{
+        storage::{stores::InMemory, Store},
+        SubIndex,
+    };
+
+    fn test_viewpair() -> monero::ViewPair {
+        let private_view_key = "ad2093a5705b9f33e6f0f0c1bc1f5f639c756cdfc168c8f2ac6127ccbdab3a03";
+        let primary_address = "4613YiHLM6JMH4zejMB2zJY5TwQCxL8p65ufw8kBP5yxX9itmuGLqp1dS4tkVoTxjyH3aYhYNrtGHbQzJQP5bFus3KHVdmf";
+        monero::ViewPair {
+            view: monero::PrivateKey::from_str(private_view_key).unwrap(),
+            spend: monero::Address::from_str(primary_address)
+                .unwrap()
+                .public_spend,
+        }
+    }
+
+    fn init_cache(config: SubaddressCacheConfig) -> SubaddressCache {
+        let store = Store::new(InMemory::new());
+        SubaddressCache::init(&store, test_viewpair(), SubaddressRange::new(0), config).unwrap()
+    }
+
+    #[test]
+    fn sequential_allocation() {
+        let mut cache = init_cache(SubaddressCacheConfig {
+            allocation: SubaddressAllocation::Sequential,
+            ..SubaddressCacheConfig::default()
+        });
+
+        for minor in 0..250 {
+            assert_eq!(cache.remove_next().unwrap().0, SubIndex::new(0, minor));
+        }
+
+        // Returned subaddresses should be reused first.
+        let (sub_index, address) = (SubIndex::new(0, 3), "address".to_string());
+        cache.insert(sub_index, address.clone());
+        assert_eq!(cache.remove_next(), Some((sub_index, address)));
+        assert_eq!(cache.remove_next().unwrap().0, SubIndex::new(0, 250));
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut cache = init_cache(SubaddressCacheConfig::default());
+        // Pretend the account is nearly full.
+        cache.available_subaddresses.clear();
+        cache.minor_index = u32::MAX - 2;
+
+        for _ in 0..2 {
+            assert_eq!(cache.remove_next().unwrap().0.major, 0);
+        }
+        assert_eq!(cache.remove_next(), None);
+        assert_eq!(cache.major_index(), 0);
+    }
+
+    #[test]
+    fn account_rollover() {
+        let mut cache = init_cache(SubaddressCacheConfig {
+            allocation: SubaddressAllocation::Sequential,
+            account_rollover: Some(9),
+            ..SubaddressCacheConfig::default()
+        });
+
+        for major in 0..3 {
+            for minor in 0..10 {
+                assert_eq!(cache.remove_next().unwrap().0, SubIndex::new(major, minor));
+            }
+        }
+        let (majors, minors) = cache.range.bounds();
+        assert_eq!(minors, 0..=9);
+        assert!(*majors.end() >= 3);
+    }
+
+    #[test]
+    fn account_rollover_skips_reserved() {
+        let mut cache = init_cache(SubaddressCacheConfig {
+            allocation: SubaddressAllocation::Sequential,
+            account_rollover: Some(9),
+            reserved_accounts: [1, 2].into(),
+            ..SubaddressCacheConfig::default()
+        });
+
+        for _ in 0..10 {
+            assert_eq!(cache.remove_next().unwrap().0.major, 0);
+        }
+        assert_eq!(cache.remove_next().unwrap().0, SubIndex::new(3, 0));
+    }
+
+    #[test]
+    fn count_overlapping_ranges() {
+        let range_1 = SubaddressRange::new(0);
+        range_1.highest_major.store(2, AtomicOrdering::Relaxed);
+        range_1.highest_minor.store(9, AtomicOrdering::Relaxed);
+        let range_2 = SubaddressRange::new(1);
+        range_2.highest_minor.store(99, AtomicOrdering::Relaxed);
+
+        let ranges = [range_1, range_2];
+        assert_eq!(subaddress_count(&ranges), 10 + 100 + 10);
+        assert_eq!(
+            sub_key_checker_for(&test_viewpair(), &ranges).table.len(),
+            subaddress_count(&ranges)
+        );
+    }
 
     #[test_case(SubIndex::new(0, 0), SubIndex::new(0, 100))]
     #[test_case(SubIndex::new(0, 0), SubIndex::new(0, 0))]
@@ -198,14 +519,7 @@ mod test {
     #[test_case(SubIndex::new(0, u32::MAX - 100), SubIndex::new(1, 0))]
     #[test_case(SubIndex::new(0, u32::MAX - 100), SubIndex::new(0, u32::MAX))]
     fn test_generated_range(from: SubIndex, to: SubIndex) {
-        let private_view_key = "ad2093a5705b9f33e6f0f0c1bc1f5f639c756cdfc168c8f2ac6127ccbdab3a03";
-        let primary_address = "4613YiHLM6JMH4zejMB2zJY5TwQCxL8p65ufw8kBP5yxX9itmuGLqp1dS4tkVoTxjyH3aYhYNrtGHbQzJQP5bFus3KHVdmf";
-        let viewpair = monero::ViewPair {
-            view: monero::PrivateKey::from_str(private_view_key).unwrap(),
-            spend: monero::Address::from_str(primary_address)
-                .unwrap()
-                .public_spend,
-        };
+        let viewpair = test_viewpair();
 
         let subaddresses = generate_range(from, to, &viewpair);
         let expected_num_generated = match from.major.cmp(&to.major) {
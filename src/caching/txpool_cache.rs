@@ -1,43 +1,61 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 use log::trace;
 use monero::cryptonote::hash::Hashable;
 use tokio::join;
 
-use crate::{invoice::Transfer, rpc::RpcClient, storage::InvoiceStorage, AcceptXmrError, SubIndex};
+use super::{CacheSnapshot, CompactTransaction};
+use crate::{rpc::RpcClient, AcceptXmrError};
 
 pub(crate) struct TxpoolCache {
     rpc_client: RpcClient,
-    transactions: HashMap<monero::Hash, monero::Transaction>,
-    discovered_transfers: HashMap<monero::Hash, Vec<(SubIndex, Transfer)>>,
+    /// Hashes of the transactions in the txpool.
+    transactions: HashSet<monero::Hash>,
 }
 
 impl TxpoolCache {
-    pub async fn init<S: InvoiceStorage>(
+    /// Initialize the txpool cache with the transactions currently in the
+    /// txpool. If a `snapshot` is given, transactions added to the txpool since
+    /// it was saved are left out, so that the next update returns them to be
+    /// scanned.
+    pub async fn init<E>(
         rpc_client: RpcClient,
-    ) -> Result<TxpoolCache, AcceptXmrError<S::Error>> {
-        let txs = rpc_client.txpool().await?;
-        let transactions = txs.iter().map(|tx| (tx.hash(), tx.clone())).collect();
+        snapshot: Option<&CacheSnapshot>,
+    ) -> Result<TxpoolCache, AcceptXmrError<E>> {
+        let transactions = if let Some(snapshot) = snapshot {
+            let txpool_hashes = rpc_client.txpool_hashes().await?;
+            snapshot
+                .txpool
+                .iter()
+                .filter(|hash| txpool_hashes.contains(hash))
+                .copied()
+                .collect()
+        } else {
+            rpc_client
+                .txpool()
+                .await?
+                .iter()
+                .map(Hashable::hash)
+                .collect()
+        };
 
         Ok(TxpoolCache {
             rpc_client,
             transactions,
-            discovered_transfers: HashMap::new(),
         })
     }
 
     /// Update the txpool cache with newest [tansactions](monero::Transaction)
-    /// from daemon txpool. Returns transactions received.
-    pub async fn update<S: InvoiceStorage>(
-        &mut self,
-    ) -> Result<Vec<monero::Transaction>, AcceptXmrError<S::Error>> {
+    /// from daemon txpool. Returns transactions received, compacted for
+    /// scanning.
+    pub async fn update<E>(&mut self) -> Result<Vec<CompactTransaction>, AcceptXmrError<E>> {
         trace!("Checking for new transactions in txpool");
 
         let txpool_hashes = self.rpc_client.txpool_hashes().await?;
         trace!("Transactions in txpool: {}", txpool_hashes.len());
         let mut new_hashes = Vec::new();
         for hash in &txpool_hashes {
-            if !self.transactions.contains_key(hash) {
+            if !self.transactions.contains(hash) {
                 new_hashes.push(*hash);
             }
         }
@@ -48,30 +66,40 @@ impl TxpoolCache {
         // TODO: Find a way to do this without cloning.
         let rpc_client = self.rpc_client.clone();
         let (new_transactions, _) = join!(rpc_client.transactions_by_hashes(&new_hashes), async {
-            self.transactions.retain(|k, _| txpool_hashes.contains(k));
-            self.discovered_transfers
-                .retain(|k, _| txpool_hashes.contains(k));
+            self.transactions
+                .retain(|hash| txpool_hashes.contains(hash));
         });
         let new_transactions = new_transactions?;
 
         self.transactions
-            .extend(new_transactions.iter().map(|tx| (tx.hash(), tx.clone())));
+            .extend(new_transactions.iter().map(Hashable::hash));
 
-        Ok(new_transactions)
+        Ok(new_transactions
+            .into_iter()
+            .map(CompactTransaction::from)
+            .collect())
     }
 
-    pub fn discovered_transfers(&self) -> &HashMap<monero::Hash, Vec<(SubIndex, Transfer)>> {
-        &self.discovered_transfers
+    /// Fetch the transactions in the txpool cache from the daemon, compacted
+    /// for scanning.
+    pub async fn transactions<E>(&self) -> Result<Vec<CompactTransaction>, AcceptXmrError<E>> {
+        let hashes: Vec<monero::Hash> = self.transactions.iter().copied().collect();
+        Ok(self
+            .rpc_client
+            .transactions_by_hashes(&hashes)
+            .await?
+            .into_iter()
+            .map(CompactTransaction::from)
+            .collect())
     }
 
-    pub fn insert_transfers(
-        &mut self,
-        transfers: &HashMap<monero::Hash, Vec<(SubIndex, Transfer)>>,
-    ) {
-        self.discovered_transfers.extend(transfers.clone());
-        trace!(
-            "Txpool contains {} transfers for tracked invoices",
-            self.discovered_transfers.len(),
-        );
+    /// Returns `true` if the transaction with the given hash is in the txpool.
+    pub fn contains(&self, hash: &monero::Hash) -> bool {
+        self.transactions.contains(hash)
+    }
+
+    /// Hashes of the transactions in the txpool.
+    pub fn hashes(&self) -> impl ExactSizeIterator<Item = &monero::Hash> {
+        self.transactions.iter()
     }
 }
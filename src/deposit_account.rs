@@ -0,0 +1,181 @@
+use crate::{invoice::Transfer, Invoice, InvoiceId, SubIndex};
+
+/// A deposit account holds a stable subaddress for a given user, and keeps a
+/// ledger of the deposits made to it. Deposit accounts are created by the
+/// [`PaymentGateway`](crate::PaymentGateway).
+///
+/// Each deposit account is backed by a watched address, so it is stored and
+/// scanned for alongside invoices, and can be subscribed to using its
+/// [ID](DepositAccount::id). Updates received by a
+/// [`Subscriber`](crate::Subscriber) can be converted back into a
+/// `DepositAccount` using `DepositAccount::try_from`.
+///
+/// The ledger follows the blockchain: if a reorg removes a deposit from the
+/// chain, the corresponding credit is reversed (removed from the ledger) on
+/// the next scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepositAccount {
+    address: Invoice,
+}
+
+impl DepositAccount {
+    pub(crate) fn new(address: Invoice) -> DepositAccount {
+        DepositAccount { address }
+    }
+
+    /// Returns the ID of the user this deposit account belongs to.
+    #[must_use]
+    pub fn user_id(&self) -> &str {
+        self.address.user_id().unwrap_or_default()
+    }
+
+    /// Returns the ID of the watched address backing this deposit account.
+    #[must_use]
+    pub fn id(&self) -> InvoiceId {
+        self.address.id()
+    }
+
+    /// Returns the base 58 encoded subaddress of this deposit account.
+    #[must_use]
+    pub fn address(&self) -> &str {
+        self.address.address()
+    }
+
+    /// Returns the [subaddress index](SubIndex) of this deposit account.
+    #[must_use]
+    pub fn index(&self) -> SubIndex {
+        self.address.index()
+    }
+
+    /// Returns the ledger of credits to this deposit account, oldest first.
+    /// Credits still in the txpool come last, with zero confirmations.
+    /// [Time locked](Transfer::is_time_locked) transfers are not credited.
+    #[must_use]
+    pub fn ledger(&self) -> Vec<LedgerEntry> {
+        let mut transfers = self.address.transfers().to_vec();
+        transfers.sort_by(Transfer::cmp_by_height);
+        transfers
+            .into_iter()
+            .filter(|transfer| !transfer.is_time_locked())
+            .map(|transfer| LedgerEntry {
+                amount: transfer.amount(),
+                height: transfer.height(),
+                confirmations: transfer.confirmations(self.address.current_height()),
+            })
+            .collect()
+    }
+
+    /// Returns the balance of this deposit account in piconeros, counting only
+    /// credits with at least `min_confirmations` confirmations.
+    #[must_use]
+    pub fn balance(&self, min_confirmations: u64) -> u64 {
+        self.ledger()
+            .iter()
+            .filter(|entry| entry.confirmations >= min_confirmations)
+            .map(|entry| entry.amount)
+            .sum()
+    }
+
+    /// Returns the last daemon height at which this deposit account was
+    /// updated.
+    #[must_use]
+    pub fn current_height(&self) -> u64 {
+        self.address.current_height()
+    }
+}
+
+impl TryFrom<Invoice> for DepositAccount {
+    type Error = Invoice;
+
+    /// Converts a watched address belonging to a deposit account into a
+    /// `DepositAccount`. Returns the invoice unchanged if it does not belong to
+    /// a deposit account.
+    fn try_from(invoice: Invoice) -> Result<Self, Self::Error> {
+        if invoice.user_id().is_some() {
+            Ok(DepositAccount::new(invoice))
+        } else {
+            Err(invoice)
+        }
+    }
+}
+
+/// A credit to a [`DepositAccount`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct LedgerEntry {
+    amount: u64,
+    height: Option<u64>,
+    confirmations: u64,
+}
+
+impl LedgerEntry {
+    /// Returns the amount credited in piconeros.
+    #[must_use]
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    /// Returns the block height of the credit, or `None` if it is still in the
+    /// txpool.
+    #[must_use]
+    pub fn height(&self) -> Option<u64> {
+        self.height
+    }
+
+    /// Returns the number of confirmations the credit has received.
+    #[must_use]
+    pub fn confirmations(&self) -> u64 {
+        self.confirmations
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use test_case::test_case;
+
+    use super::DepositAccount;
+    use crate::{invoice::Transfer, Invoice, SubIndex};
+
+    fn deposit_account() -> DepositAccount {
+        let mut invoice = Invoice::new_deposit_address(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            "user".to_string(),
+        );
+        invoice.current_height = 110;
+        invoice.transfers = vec![
+            Transfer::new(1, None),
+            Transfer::new(10, Some(109)),
+            Transfer::new(100, Some(101)),
+        ];
+        DepositAccount::try_from(invoice).unwrap()
+    }
+
+    #[test_case(0 => 111)]
+    #[test_case(1 => 110)]
+    #[test_case(9 => 100)]
+    #[test_case(10 => 0)]
+    fn balance(min_confirmations: u64) -> u64 {
+        deposit_account().balance(min_confirmations)
+    }
+
+    #[test]
+    fn ledger_order() {
+        let ledger = deposit_account().ledger();
+        let confirmations: Vec<u64> = ledger.iter().map(|e| e.confirmations()).collect();
+        assert_eq!(confirmations, vec![9, 1, 0]);
+        assert_eq!(ledger[2].height(), None);
+    }
+
+    #[test]
+    fn not_a_deposit_account() {
+        let invoice = Invoice::new_watched(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            "donations".to_string(),
+        );
+        assert!(DepositAccount::try_from(invoice).is_err());
+    }
+}
@@ -3,15 +3,29 @@ use std::{
     collections::HashMap,
     fmt,
     fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[cfg(feature = "bincode")]
-use bincode::{Decode, Encode};
+use bincode::{
+    de::{read::Reader, Decoder},
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    impl_borrow_decode, Decode, Encode,
+};
 use monero::cryptonote::subaddress;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 const PICONEROS_PER_XMR: u64 = 1_000_000_000_000;
+/// Number of blocks before outputs of a miner transaction can be spent.
+const COINBASE_UNLOCK_WINDOW: u64 = 60;
+/// Number of blocks before outputs of any transaction can be spent.
+const DEFAULT_SPENDABLE_AGE: u64 = 10;
+/// Unlock times below this are block heights, and above it unix timestamps.
+const MAX_BLOCK_NUMBER: u64 = 500_000_000;
+/// Leeway given to unlock times which are unix timestamps, in seconds.
+const LOCKED_TX_ALLOWED_DELTA_SECONDS: u64 = 120;
 
 /// Representation of an invoice. `Invoice`s are created by the
 /// [`PaymentGateway`](crate::PaymentGateway).
@@ -22,9 +36,16 @@ const PICONEROS_PER_XMR: u64 = 1_000_000_000_000;
 ///
 /// To receive updates for a given `Invoice`, use a
 /// [`Subscriber`](crate::pubsub::Subscriber).
+///
+/// An `Invoice` may also be a watched address (see
+/// [`PaymentGateway::watch_address`](crate::PaymentGateway::watch_address)),
+/// in which case it requests no particular amount, never expires, and records
+/// every transfer received.
+///
+/// The `bincode` encoding of an `Invoice` is versioned. Invoices encoded by
+/// versions of this crate predating it can still be decoded.
 #[derive(Debug, Clone, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[cfg_attr(feature = "bincode", derive(Encode, Decode))]
 pub struct Invoice {
     address: String,
     index: SubIndex,
@@ -37,6 +58,14 @@ pub struct Invoice {
     expiration_height: u64,
     pub(crate) transfers: Vec<Transfer>,
     pub(crate) description: String,
+    watched: bool,
+    user_id: Option<String>,
+    pub(crate) predecessor: Option<InvoiceId>,
+    pub(crate) successor: Option<InvoiceId>,
+    pub(crate) recurrence: Option<Recurrence>,
+    open_amount: bool,
+    pub(crate) dust_threshold: Option<u64>,
+    pub(crate) dust: Vec<Transfer>,
 }
 
 impl Invoice {
@@ -56,15 +85,108 @@ impl Invoice {
             creation_height,
             amount_requested,
             amount_paid: 0,
-            /// The height at which the `Invoice` was fully paid. Will be `None`
-            /// if not yet fully paid, or if the required XMR is still in the
-            /// txpool (which has no height).
+            // The height at which the `Invoice` was fully paid. Will be `None`
+            // if not yet fully paid, or if the required XMR is still in the
+            // txpool (which has no height).
             paid_height: None,
             confirmations_required,
             current_height: 0,
             expiration_height,
             transfers: Vec::new(),
             description,
+            watched: false,
+            user_id: None,
+            predecessor: None,
+            successor: None,
+            recurrence: None,
+            open_amount: false,
+            dust_threshold: None,
+            dust: Vec::new(),
+        }
+    }
+
+    /// Create an invoice accepting any amount, or any amount above the given
+    /// minimum.
+    pub(crate) fn new_open_amount(
+        address: String,
+        index: SubIndex,
+        creation_height: u64,
+        minimum_amount: Option<u64>,
+        confirmations_required: u64,
+        expiration_in: u64,
+        description: String,
+    ) -> Invoice {
+        Invoice {
+            open_amount: true,
+            ..Invoice::new(
+                address,
+                index,
+                creation_height,
+                minimum_amount.unwrap_or_default(),
+                confirmations_required,
+                expiration_in,
+                description,
+            )
+        }
+    }
+
+    /// Create a watched address, which never expires and requests no
+    /// particular amount.
+    pub(crate) fn new_watched(
+        address: String,
+        index: SubIndex,
+        creation_height: u64,
+        description: String,
+    ) -> Invoice {
+        Invoice {
+            expiration_height: u64::MAX,
+            watched: true,
+            ..Invoice::new(address, index, creation_height, 0, 0, 0, description)
+        }
+    }
+
+    /// Create a watched address belonging to the deposit account of the given
+    /// user.
+    pub(crate) fn new_deposit_address(
+        address: String,
+        index: SubIndex,
+        creation_height: u64,
+        user_id: String,
+    ) -> Invoice {
+        Invoice {
+            user_id: Some(user_id),
+            ..Invoice::new_watched(address, index, creation_height, String::new())
+        }
+    }
+
+    /// Create an invoice for one billing period of a recurring invoice. Periods
+    /// counted in blocks expire at the end of the period, while periods counted
+    /// in wall-clock time never expire by height.
+    pub(crate) fn new_recurring(
+        address: String,
+        index: SubIndex,
+        creation_height: u64,
+        amount_requested: u64,
+        confirmations_required: u64,
+        description: String,
+        recurrence: Recurrence,
+    ) -> Invoice {
+        let expiration_height = match recurrence.billing_period {
+            BillingPeriod::Blocks(_) => recurrence.period_end,
+            BillingPeriod::Time(_) => u64::MAX,
+        };
+        Invoice {
+            expiration_height,
+            recurrence: Some(recurrence),
+            ..Invoice::new(
+                address,
+                index,
+                creation_height,
+                amount_requested,
+                confirmations_required,
+                0,
+                description,
+            )
         }
     }
 
@@ -78,9 +200,14 @@ impl Invoice {
     /// Monero URIs can be thought of as fancy addresses that pre-fill the
     /// amount field for the user (and sometimes the description field as
     /// well). They are supported by all major wallets.
+    ///
+    /// The URI of a watched address or open-amount invoice contains no amount.
     #[must_use]
     #[allow(clippy::cast_precision_loss)]
     pub fn uri(&self) -> String {
+        if self.watched || self.open_amount {
+            return format!("monero:{}", &self.address);
+        }
         let piconeros_due = self.amount_requested.saturating_sub(self.amount_paid);
         let whole_xmr_due = piconeros_due / PICONEROS_PER_XMR;
         let fractional_xmr_due =
@@ -95,19 +222,68 @@ impl Invoice {
 
     /// Returns `true` if the `Invoice` has received the required number of
     /// confirmations.
+    ///
+    /// [Time locked](Transfer::is_time_locked) transfers never count towards
+    /// the amount paid, so they can't confirm an invoice.
+    ///
+    /// Outputs of miner (coinbase) transactions can't be spent until they are
+    /// 60 blocks deep, so an invoice paid with any coinbase transfers is not
+    /// confirmed until each of them has 60 confirmations.
     #[must_use]
     pub fn is_confirmed(&self) -> bool {
         self.confirmations().map_or(false, |confirmations| {
             confirmations >= self.confirmations_required
-        })
+        }) && self.coinbase_unlocked()
+    }
+
+    /// Returns the amount in piconeros received by this `Invoice` which can
+    /// currently be spent, according to each transfer's
+    /// [unlock height](Transfer::unlock_height) and
+    /// [unlock time](Transfer::unlock_time).
+    #[must_use]
+    pub fn spendable_amount(&self) -> u64 {
+        let now = unix_time();
+        self.transfers
+            .iter()
+            .filter(|transfer| transfer.is_unlocked(self.current_height, now))
+            .map(|transfer| transfer.amount)
+            .sum()
+    }
+
+    /// Returns `true` if enough of the amount received by this `Invoice` can be
+    /// spent to cover the amount requested. Monero outputs can't be spent until
+    /// they are at least 10 blocks deep, or later if their transaction is time
+    /// locked, regardless of the confirmations required by the invoice.
+    #[must_use]
+    pub fn is_spendable(&self) -> bool {
+        let spendable_amount = self.spendable_amount();
+        spendable_amount > 0 && spendable_amount >= self.amount_requested
+    }
+
+    /// Returns `true` if every coinbase transfer to this invoice is past the
+    /// coinbase unlock window.
+    fn coinbase_unlocked(&self) -> bool {
+        self.transfers
+            .iter()
+            .filter(|transfer| transfer.coinbase)
+            .all(|transfer| {
+                transfer.height.is_some()
+                    && transfer.confirmations(self.current_height) >= COINBASE_UNLOCK_WINDOW
+            })
     }
 
     /// Returns `true` if the `Invoice`'s current block is greater than or equal
     /// to its expiration block.
+    ///
+    /// An invoice belonging to a billing period of a recurring invoice also
+    /// expires if its period ended before it was paid in full.
     #[must_use]
     pub fn is_expired(&self) -> bool {
-        // At or passed the expiration block, AND not paid in full.
-        (self.current_height >= self.expiration_height) && self.paid_height.is_none()
+        // At or passed the expiration block (or end of the billing period), AND not
+        // paid in full.
+        (self.current_height >= self.expiration_height
+            || self.recurrence.map_or(false, |recurrence| recurrence.ended))
+            && self.paid_height.is_none()
     }
 
     /// Returns the base 58 encoded subaddress of this `Invoice`.
@@ -137,7 +313,8 @@ impl Invoice {
         self.creation_height
     }
 
-    /// Returns the amount of monero requested in piconeros.
+    /// Returns the amount of monero requested in piconeros. For open-amount
+    /// invoices, this is the minimum amount accepted.
     #[must_use]
     pub fn amount_requested(&self) -> u64 {
         self.amount_requested
@@ -224,8 +401,23 @@ impl Invoice {
     /// Returns the number of confirmations this `Invoice` has received since it
     /// was paid in full. Returns `None` if the `Invoice` has not yet been paid
     /// in full.
+    ///
+    /// The transfers counted towards the amount requested are the oldest ones
+    /// which add up to it, and the invoice has as many confirmations as the
+    /// least confirmed of them. Later top-ups don't affect the confirmations,
+    /// while a counted transfer removed by a reorg is replaced by the next
+    /// oldest. See [`counted_transfers`](Invoice::counted_transfers).
+    ///
+    /// Open-amount invoices are paid in full once they have received at least
+    /// their minimum amount. Without a minimum, they are paid by their first
+    /// transfer in a block, so transfers still in the txpool don't pay them.
     #[must_use]
     pub fn confirmations(&self) -> Option<u64> {
+        if self.open_amount && self.amount_requested == 0 {
+            return self
+                .paid_height
+                .map(|paid_at| self.current_height.saturating_sub(paid_at));
+        }
         if self.amount_paid >= self.amount_requested {
             self.paid_height.map_or(Some(0), |paid_at| {
                 Some(self.current_height.saturating_sub(paid_at))
@@ -289,6 +481,110 @@ impl Invoice {
     pub fn description(&self) -> &str {
         &self.description
     }
+
+    /// Returns the [`Transfer`]s received by this invoice.
+    #[must_use]
+    pub fn transfers(&self) -> &[Transfer] {
+        &self.transfers
+    }
+
+    /// Returns `true` if this is a watched address rather than a regular
+    /// invoice. Watched addresses never expire, and record every transfer
+    /// received.
+    #[must_use]
+    pub fn is_watched(&self) -> bool {
+        self.watched
+    }
+
+    /// Returns the user ID of the [`DepositAccount`](crate::DepositAccount)
+    /// this watched address belongs to, if any.
+    #[must_use]
+    pub fn user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    /// Returns the ID of the invoice this `Invoice` was reissued from, if any.
+    /// See [`PaymentGateway::reissue_invoice`](crate::PaymentGateway::reissue_invoice).
+    #[must_use]
+    pub fn predecessor(&self) -> Option<InvoiceId> {
+        self.predecessor
+    }
+
+    /// Returns the ID of the invoice this `Invoice` was reissued as, if any.
+    /// See [`PaymentGateway::reissue_invoice`](crate::PaymentGateway::reissue_invoice).
+    #[must_use]
+    pub fn successor(&self) -> Option<InvoiceId> {
+        self.successor
+    }
+
+    /// Returns the outputs received by this invoice which were ignored as dust.
+    /// Dust does not count towards the [amount paid](Invoice::amount_paid).
+    #[must_use]
+    pub fn dust(&self) -> &[Transfer] {
+        &self.dust
+    }
+
+    /// Returns the minimum output amount in piconeros accepted by this invoice,
+    /// if it overrides the payment gateway's
+    /// [dust threshold](crate::PaymentGatewayBuilder::dust_threshold).
+    #[must_use]
+    pub fn dust_threshold(&self) -> Option<u64> {
+        self.dust_threshold
+    }
+
+    /// Returns `true` if this `Invoice` accepts any amount, or any amount above
+    /// its [minimum](Invoice::amount_requested). See
+    /// [`PaymentGateway::new_open_invoice`](crate::PaymentGateway::new_open_invoice).
+    #[must_use]
+    pub fn is_open_amount(&self) -> bool {
+        self.open_amount
+    }
+
+    /// Returns the billing period this `Invoice` belongs to, if it is part of a
+    /// recurring invoice. See
+    /// [`PaymentGateway::new_recurring_invoice`](crate::PaymentGateway::new_recurring_invoice).
+    #[must_use]
+    pub fn recurrence(&self) -> Option<&Recurrence> {
+        self.recurrence.as_ref()
+    }
+
+    /// Returns the transfers counted towards the amount requested, i.e. the
+    /// oldest transfers which add up to it, oldest first. Returns every
+    /// counted transfer if the invoice is not yet paid in full.
+    ///
+    /// [Time locked](Transfer::is_time_locked) transfers are never counted.
+    #[must_use]
+    pub fn counted_transfers(&self) -> Vec<Transfer> {
+        let mut total: u64 = 0;
+        let mut counted = Vec::new();
+        for transfer in self.transfers.iter().filter(|t| !t.is_time_locked()) {
+            total += transfer.amount;
+            counted.push(*transfer);
+            if total >= self.amount_requested {
+                break;
+            }
+        }
+        counted
+    }
+
+    /// Recalculate the amount paid and the height at which the invoice was
+    /// paid in full from its transfers. Time locked transfers are recorded, but
+    /// not counted.
+    pub(crate) fn recalculate_paid(&mut self) {
+        // Zero it out first.
+        self.paid_height = None;
+        self.amount_paid = 0;
+        // Count the oldest transfers first, so that the invoice is paid at the height
+        // of the newest transfer needed to pay it.
+        self.transfers.sort_by(Transfer::cmp_by_height);
+        // Now add up the transfers.
+        for transfer in self.transfers.iter().filter(|t| !t.is_time_locked()) {
+            self.amount_paid += transfer.amount;
+            if self.amount_paid >= self.amount_requested && self.paid_height.is_none() {
+                self.paid_height = transfer.height;
+            }
+        }
+    }
 }
 
 impl fmt::Display for Invoice {
@@ -336,19 +632,19 @@ impl fmt::Display for Invoice {
 }
 
 /// This custom `PartialEq` implementation is necessary so that the order of
-/// `Transfer`s can be ignored while comparing `Invoice`s.
+/// `Transfer`s (and dust) can be ignored while comparing `Invoice`s.
 impl PartialEq for Invoice {
     fn eq(&self, other: &Self) -> bool {
-        let mut lhs_transfers = HashMap::new();
-        let mut rhs_transfers = HashMap::new();
-        for i in &self.transfers {
-            *lhs_transfers.entry(i).or_insert(0) += 1;
-        }
-        for i in &other.transfers {
-            *rhs_transfers.entry(i).or_insert(0) += 1;
+        fn count(transfers: &[Transfer]) -> HashMap<&Transfer, usize> {
+            let mut counts = HashMap::new();
+            for i in transfers {
+                *counts.entry(i).or_insert(0) += 1;
+            }
+            counts
         }
 
-        lhs_transfers == rhs_transfers
+        count(&self.transfers) == count(&other.transfers)
+            && count(&self.dust) == count(&other.dust)
             && self.address == other.address
             && self.index == other.index
             && self.creation_height == other.creation_height
@@ -359,6 +655,147 @@ impl PartialEq for Invoice {
             && self.current_height == other.current_height
             && self.expiration_height == other.expiration_height
             && self.description == other.description
+            && self.watched == other.watched
+            && self.user_id == other.user_id
+            && self.predecessor == other.predecessor
+            && self.successor == other.successor
+            && self.recurrence == other.recurrence
+            && self.open_amount == other.open_amount
+            && self.dust_threshold == other.dust_threshold
+    }
+}
+
+/// Prefixes the versioned `bincode` encoding of an [`Invoice`]. The encoding
+/// of the unversioned layout starts with the varint length of the address,
+/// which never begins with this byte.
+#[cfg(feature = "bincode")]
+const INVOICE_FORMAT_MARKER: u8 = 0xff;
+/// Current version of the `bincode` encoding of an [`Invoice`].
+#[cfg(feature = "bincode")]
+const INVOICE_FORMAT_VERSION: u8 = 1;
+
+#[cfg(feature = "bincode")]
+impl Encode for Invoice {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        INVOICE_FORMAT_MARKER.encode(encoder)?;
+        INVOICE_FORMAT_VERSION.encode(encoder)?;
+        self.address.encode(encoder)?;
+        self.index.encode(encoder)?;
+        self.creation_height.encode(encoder)?;
+        self.amount_requested.encode(encoder)?;
+        self.amount_paid.encode(encoder)?;
+        self.paid_height.encode(encoder)?;
+        self.confirmations_required.encode(encoder)?;
+        self.current_height.encode(encoder)?;
+        self.expiration_height.encode(encoder)?;
+        self.transfers.encode(encoder)?;
+        self.description.encode(encoder)?;
+        self.watched.encode(encoder)?;
+        self.user_id.encode(encoder)?;
+        self.predecessor.encode(encoder)?;
+        self.successor.encode(encoder)?;
+        self.recurrence.encode(encoder)?;
+        self.open_amount.encode(encoder)?;
+        self.dust_threshold.encode(encoder)?;
+        self.dust.encode(encoder)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<Context> Decode<Context> for Invoice {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        // Invoices encoded before the encoding was versioned have no marker.
+        // Readers which can't peek can only decode versioned invoices.
+        if matches!(decoder.reader().peek_read(1), Some(bytes) if bytes != [INVOICE_FORMAT_MARKER])
+        {
+            return LegacyInvoice::decode(decoder).map(Invoice::from);
+        }
+        if u8::decode(decoder)? != INVOICE_FORMAT_MARKER {
+            return Err(DecodeError::Other(
+                "unversioned invoices can only be decoded from a slice",
+            ));
+        }
+        match u8::decode(decoder)? {
+            INVOICE_FORMAT_VERSION => Ok(Invoice {
+                address: Decode::decode(decoder)?,
+                index: Decode::decode(decoder)?,
+                creation_height: Decode::decode(decoder)?,
+                amount_requested: Decode::decode(decoder)?,
+                amount_paid: Decode::decode(decoder)?,
+                paid_height: Decode::decode(decoder)?,
+                confirmations_required: Decode::decode(decoder)?,
+                current_height: Decode::decode(decoder)?,
+                expiration_height: Decode::decode(decoder)?,
+                transfers: Decode::decode(decoder)?,
+                description: Decode::decode(decoder)?,
+                watched: Decode::decode(decoder)?,
+                user_id: Decode::decode(decoder)?,
+                predecessor: Decode::decode(decoder)?,
+                successor: Decode::decode(decoder)?,
+                recurrence: Decode::decode(decoder)?,
+                open_amount: Decode::decode(decoder)?,
+                dust_threshold: Decode::decode(decoder)?,
+                dust: Decode::decode(decoder)?,
+            }),
+            _ => Err(DecodeError::Other("unsupported invoice format version")),
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl_borrow_decode!(Invoice);
+
+/// Layout of an [`Invoice`] encoded before the encoding was versioned.
+#[cfg(feature = "bincode")]
+#[cfg_attr(test, derive(Encode))]
+#[derive(Decode)]
+pub(crate) struct LegacyInvoice {
+    pub address: String,
+    pub index: SubIndex,
+    pub creation_height: u64,
+    pub amount_requested: u64,
+    pub amount_paid: u64,
+    pub paid_height: Option<u64>,
+    pub confirmations_required: u64,
+    pub current_height: u64,
+    pub expiration_height: u64,
+    pub transfers: Vec<LegacyTransfer>,
+    pub description: String,
+}
+
+/// Layout of a [`Transfer`] encoded before the encoding of [`Invoice`] was
+/// versioned. Time locked transfers were not recorded then.
+#[cfg(feature = "bincode")]
+#[cfg_attr(test, derive(Encode))]
+#[derive(Decode)]
+pub(crate) struct LegacyTransfer {
+    pub amount: u64,
+    pub height: Option<u64>,
+}
+
+#[cfg(feature = "bincode")]
+impl From<LegacyInvoice> for Invoice {
+    fn from(legacy: LegacyInvoice) -> Invoice {
+        Invoice {
+            amount_paid: legacy.amount_paid,
+            paid_height: legacy.paid_height,
+            current_height: legacy.current_height,
+            expiration_height: legacy.expiration_height,
+            transfers: legacy
+                .transfers
+                .into_iter()
+                .map(|transfer| Transfer::new(transfer.amount, transfer.height))
+                .collect(),
+            ..Invoice::new(
+                legacy.address,
+                legacy.index,
+                legacy.creation_height,
+                legacy.amount_requested,
+                legacy.confirmations_required,
+                0,
+                legacy.description,
+            )
+        }
     }
 }
 
@@ -407,6 +844,132 @@ impl Display for InvoiceId {
     }
 }
 
+/// The length of each billing period of a recurring invoice.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "bincode", derive(Encode, Decode))]
+pub enum BillingPeriod {
+    /// A period lasting the given number of blocks.
+    Blocks(u64),
+    /// A period lasting the given wall-clock time.
+    Time(Duration),
+}
+
+impl BillingPeriod {
+    /// Returns `true` if the period has zero length.
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            BillingPeriod::Blocks(blocks) => *blocks == 0,
+            BillingPeriod::Time(duration) => duration.as_secs() == 0,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            BillingPeriod::Blocks(blocks) => *blocks,
+            BillingPeriod::Time(duration) => duration.as_secs(),
+        }
+    }
+}
+
+/// Describes the billing period an [`Invoice`] covers, when it is part of a
+/// recurring invoice.
+///
+/// A recurring invoice is a chain of invoices, one per billing period, linked
+/// through [`Invoice::predecessor`] and [`Invoice::successor`]. Payments are
+/// credited to the period whose invoice received them.
+///
+/// The start and end of a period are block heights for
+/// [`BillingPeriod::Blocks`], or seconds since the unix epoch for
+/// [`BillingPeriod::Time`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "bincode", derive(Encode, Decode))]
+pub struct Recurrence {
+    billing_period: BillingPeriod,
+    period_index: u64,
+    period_start: u64,
+    period_end: u64,
+    reuse_subaddress: bool,
+    pub(crate) ended: bool,
+}
+
+impl Recurrence {
+    /// Describe the first billing period, starting at the given height or
+    /// time.
+    pub(crate) fn new(billing_period: BillingPeriod, reuse_subaddress: bool, start: u64) -> Self {
+        Recurrence {
+            billing_period,
+            period_index: 0,
+            period_start: start,
+            period_end: start.saturating_add(billing_period.len()),
+            reuse_subaddress,
+            ended: false,
+        }
+    }
+
+    /// Describe the billing period following this one.
+    pub(crate) fn next(&self) -> Self {
+        Recurrence {
+            period_index: self.period_index + 1,
+            period_start: self.period_end,
+            period_end: self.period_end.saturating_add(self.billing_period.len()),
+            ended: false,
+            ..*self
+        }
+    }
+
+    /// Returns `true` if the billing period is over at the given height and
+    /// unix time.
+    pub(crate) fn is_over(&self, height: u64, unix_time: u64) -> bool {
+        match self.billing_period {
+            BillingPeriod::Blocks(_) => height >= self.period_end,
+            BillingPeriod::Time(_) => unix_time >= self.period_end,
+        }
+    }
+
+    /// Returns the length of each billing period.
+    #[must_use]
+    pub fn billing_period(&self) -> BillingPeriod {
+        self.billing_period
+    }
+
+    /// Returns the index of this billing period. The first period has index
+    /// `0`.
+    #[must_use]
+    pub fn period_index(&self) -> u64 {
+        self.period_index
+    }
+
+    /// Returns the block height or unix time at which this billing period
+    /// starts.
+    #[must_use]
+    pub fn period_start(&self) -> u64 {
+        self.period_start
+    }
+
+    /// Returns the block height or unix time at which this billing period
+    /// ends.
+    #[must_use]
+    pub fn period_end(&self) -> u64 {
+        self.period_end
+    }
+
+    /// Returns `true` if every billing period uses the same subaddress, rather
+    /// than a fresh one.
+    #[must_use]
+    pub fn reuses_subaddress(&self) -> bool {
+        self.reuse_subaddress
+    }
+
+    /// Returns `true` if this billing period has ended and the next one has
+    /// been issued.
+    #[must_use]
+    pub fn has_ended(&self) -> bool {
+        self.ended
+    }
+}
+
 /// A subaddress index.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -472,16 +1035,96 @@ impl From<SubIndex> for subaddress::Index {
 #[derive(Debug, Clone, PartialEq, Copy, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "bincode", derive(Encode, Decode))]
-pub(crate) struct Transfer {
+pub struct Transfer {
     /// Amount transferred in piconeros.
-    pub amount: u64,
+    pub(crate) amount: u64,
     /// Block height of the transfer, or None if the outputs are in the txpool.
-    pub height: Option<u64>,
+    pub(crate) height: Option<u64>,
+    /// Whether the outputs belong to a miner (coinbase) transaction.
+    pub(crate) coinbase: bool,
+    /// Unlock time of the transaction the outputs belong to.
+    pub(crate) unlock_time: u64,
 }
 
 impl Transfer {
+    /// Returns the amount transferred in piconeros.
+    #[must_use]
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    /// Returns the block height of the transfer, or `None` if the transfer is
+    /// still in the txpool.
+    #[must_use]
+    pub fn height(&self) -> Option<u64> {
+        self.height
+    }
+
+    /// Returns `true` if the transfer was received in a miner (coinbase)
+    /// transaction. Coinbase outputs can't be spent until they are 60 blocks
+    /// deep.
+    #[must_use]
+    pub fn is_coinbase(&self) -> bool {
+        self.coinbase
+    }
+
+    /// Returns the height at which the transfer can be spent, or `None` if the
+    /// transfer is still in the txpool. Outputs can be spent once they are 10
+    /// blocks deep, or once the height given by their transaction's unlock time
+    /// is reached, whichever is later.
+    #[must_use]
+    pub fn unlock_height(&self) -> Option<u64> {
+        let unlock_height = self.height? + DEFAULT_SPENDABLE_AGE;
+        if self.unlock_time < MAX_BLOCK_NUMBER {
+            Some(max(unlock_height, self.unlock_time))
+        } else {
+            Some(unlock_height)
+        }
+    }
+
+    /// Returns the number of confirmations the transfer has received, given the
+    /// current height (e.g. [`Invoice::current_height`]). Transfers in the
+    /// txpool have zero confirmations.
+    #[must_use]
+    pub fn confirmations(&self, current_height: u64) -> u64 {
+        self.height
+            .map_or(0, |height| current_height.saturating_sub(height))
+    }
+
+    /// Returns the unlock time of the transaction the transfer belongs to. This
+    /// is `0` for transactions which aren't time locked, a block height if less
+    /// than 500,000,000, and a unix timestamp otherwise.
+    #[must_use]
+    pub fn unlock_time(&self) -> u64 {
+        self.unlock_time
+    }
+
+    /// Returns `true` if the transfer belongs to a time locked transaction, i.e.
+    /// a transaction other than a miner transaction with a non-zero unlock
+    /// time. Time locked transfers are recorded, and count towards
+    /// [`Invoice::spendable_amount`] once unlocked, but never towards
+    /// [`Invoice::amount_paid`].
+    #[must_use]
+    pub fn is_time_locked(&self) -> bool {
+        !self.coinbase && self.unlock_time != 0
+    }
+
+    /// Returns `true` if the transfer can be spent at the given height and unix
+    /// time.
+    pub(crate) fn is_unlocked(&self, current_height: u64, unix_time: u64) -> bool {
+        self.unlock_height()
+            .map_or(false, |unlock_height| current_height >= unlock_height)
+            && (self.unlock_time < MAX_BLOCK_NUMBER
+                || unix_time + LOCKED_TX_ALLOWED_DELTA_SECONDS >= self.unlock_time)
+    }
+
     pub(crate) fn new(amount: u64, height: Option<u64>) -> Transfer {
-        Transfer { amount, height }
+        Transfer {
+            amount,
+            height,
+            coinbase: false,
+            unlock_time: 0,
+        }
     }
 
     /// Compare two transfers by height. Newer is greater.
@@ -499,13 +1142,21 @@ impl Transfer {
     }
 }
 
+/// Returns the current unix time in seconds.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
 #[cfg(test)]
 #[allow(clippy::expect_used)]
 mod tests {
     use log::LevelFilter;
     use test_case::test_case;
 
-    use crate::{Invoice, SubIndex};
+    use super::{Recurrence, Transfer};
+    use crate::{BillingPeriod, Invoice, SubIndex};
 
     fn init_logger() {
         let _ = env_logger::builder()
@@ -542,6 +1193,247 @@ mod tests {
         amount.to_string()
     }
 
+    #[test]
+    fn watched_address() {
+        init_logger();
+
+        let mut watched_address = Invoice::new_watched(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            12345,
+            "test_description".to_string(),
+        );
+        watched_address.current_height = u64::MAX - 1;
+
+        assert!(watched_address.is_watched());
+        assert!(!watched_address.is_expired());
+        assert_eq!(watched_address.uri(), "monero:testAddress");
+    }
+
+    #[test]
+    fn open_amount_invoice() {
+        init_logger();
+
+        let mut invoice = Invoice::new_open_amount(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            None,
+            1,
+            10,
+            "test_description".to_string(),
+        );
+        assert!(invoice.is_open_amount());
+        assert_eq!(invoice.uri(), "monero:testAddress");
+
+        // Not paid until a transfer is received.
+        invoice.current_height = 105;
+        assert_eq!(invoice.confirmations(), None);
+        assert!(!invoice.is_confirmed());
+
+        // Not paid by a transfer in the txpool.
+        invoice.transfers = vec![Transfer::new(5, None)];
+        invoice.recalculate_paid();
+        assert_eq!(invoice.amount_paid(), 5);
+        assert_eq!(invoice.confirmations(), None);
+        assert!(!invoice.is_confirmed());
+
+        // Paid by the first transfer in a block, whatever its amount.
+        invoice.transfers.push(Transfer::new(5, Some(104)));
+        invoice.recalculate_paid();
+        assert_eq!(invoice.amount_paid(), 10);
+        assert_eq!(invoice.confirmations(), Some(1));
+        assert!(invoice.is_confirmed());
+        invoice.transfers.push(Transfer::new(5, Some(103)));
+        invoice.recalculate_paid();
+        assert_eq!(invoice.confirmations(), Some(2));
+
+        // With a minimum, paid once the transfers add up to it.
+        let mut invoice = Invoice::new_open_amount(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            Some(10),
+            1,
+            10,
+            "test_description".to_string(),
+        );
+        invoice.current_height = 105;
+        invoice.transfers = vec![Transfer::new(5, Some(101))];
+        invoice.recalculate_paid();
+        assert_eq!(invoice.confirmations(), None);
+        invoice.transfers.push(Transfer::new(5, Some(102)));
+        invoice.recalculate_paid();
+        assert_eq!(invoice.confirmations(), Some(3));
+    }
+
+    #[test]
+    fn recurring_invoice() {
+        init_logger();
+
+        let recurrence = Recurrence::new(BillingPeriod::Blocks(10), true, 100);
+        let mut invoice = Invoice::new_recurring(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            1,
+            0,
+            "test_description".to_string(),
+            recurrence,
+        );
+        assert_eq!(invoice.expiration_height(), 110);
+        assert!(!recurrence.is_over(109, 0));
+        assert!(recurrence.is_over(110, 0));
+
+        let next = recurrence.next();
+        assert_eq!(next.period_index(), 1);
+        assert_eq!(next.period_start(), 110);
+        assert_eq!(next.period_end(), 120);
+
+        // A period which ended unpaid is expired, regardless of height.
+        invoice.current_height = 105;
+        assert!(!invoice.is_expired());
+        invoice.recurrence = Some(Recurrence {
+            ended: true,
+            ..recurrence
+        });
+        assert!(invoice.is_expired());
+    }
+
+    #[test]
+    fn coinbase_unlock() {
+        init_logger();
+
+        let mut invoice = Invoice::new(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            10,
+            1,
+            10,
+            "test_description".to_string(),
+        );
+        let mut transfer = Transfer::new(10, Some(101));
+        transfer.coinbase = true;
+        invoice.transfers = vec![transfer];
+        invoice.recalculate_paid();
+
+        // Confirmed once the coinbase output has unlocked.
+        invoice.current_height = 160;
+        assert_eq!(invoice.confirmations(), Some(59));
+        assert!(!invoice.is_confirmed());
+        invoice.current_height = 161;
+        assert!(invoice.is_confirmed());
+    }
+
+    #[test]
+    fn spendable() {
+        init_logger();
+
+        let mut invoice = Invoice::new(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            10,
+            0,
+            10,
+            "test_description".to_string(),
+        );
+        let mut locked = Transfer::new(4, Some(101));
+        locked.unlock_time = 120;
+        invoice.transfers = vec![Transfer::new(6, Some(102)), locked, Transfer::new(1, None)];
+        invoice.recalculate_paid();
+        // Transfers are sorted oldest first.
+        assert_eq!(invoice.transfers[0].unlock_height(), Some(120));
+        assert_eq!(invoice.transfers[1].unlock_height(), Some(112));
+        assert_eq!(invoice.transfers[2].unlock_height(), None);
+
+        // The time locked transfer doesn't count towards the amount paid, but it
+        // counts towards the amount spendable once unlocked.
+        assert_eq!(invoice.amount_paid(), 7);
+        invoice.current_height = 111;
+        assert!(!invoice.is_confirmed());
+        assert_eq!(invoice.spendable_amount(), 0);
+        invoice.current_height = 112;
+        assert_eq!(invoice.spendable_amount(), 6);
+        assert!(!invoice.is_spendable());
+        invoice.current_height = 120;
+        assert_eq!(invoice.spendable_amount(), 10);
+        assert!(invoice.is_spendable());
+    }
+
+    #[test]
+    fn time_locked_transfers_never_confirm() {
+        init_logger();
+
+        // Locked until a far future height, and until a far future timestamp.
+        for unlock_time in [100_000_000, u64::MAX] {
+            let mut invoice = Invoice::new(
+                "testAddress".to_string(),
+                SubIndex::new(0, 1),
+                100,
+                10,
+                1,
+                10,
+                "test_description".to_string(),
+            );
+            let mut locked = Transfer::new(10, Some(101));
+            locked.unlock_time = unlock_time;
+            invoice.transfers = vec![locked];
+            invoice.recalculate_paid();
+            invoice.current_height = 3_000_000;
+
+            assert!(invoice.transfers()[0].is_time_locked());
+            assert_eq!(invoice.amount_paid(), 0);
+            assert!(invoice.counted_transfers().is_empty());
+            assert_eq!(invoice.confirmations(), None);
+            assert!(!invoice.is_confirmed());
+            assert!(!invoice.is_spendable());
+        }
+    }
+
+    #[test]
+    fn per_transfer_confirmations() {
+        init_logger();
+
+        let mut invoice = Invoice::new(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            10,
+            3,
+            10,
+            "test_description".to_string(),
+        );
+        invoice.current_height = 110;
+        // Received out of order, with a top-up still in the txpool.
+        invoice.transfers = vec![
+            Transfer::new(5, None),
+            Transfer::new(5, Some(105)),
+            Transfer::new(5, Some(102)),
+        ];
+        invoice.recalculate_paid();
+
+        // Paid by the two oldest transfers.
+        let counted: Vec<u64> = invoice
+            .counted_transfers()
+            .iter()
+            .map(|transfer| transfer.confirmations(invoice.current_height()))
+            .collect();
+        assert_eq!(counted, vec![8, 5]);
+        assert_eq!(invoice.confirmations(), Some(5));
+        assert!(invoice.is_confirmed());
+        assert_eq!(invoice.transfers()[2].confirmations(110), 0);
+
+        // A reorg removing a counted transfer brings in the next one.
+        invoice
+            .transfers
+            .retain(|transfer| transfer.height != Some(102));
+        invoice.recalculate_paid();
+        assert_eq!(invoice.confirmations(), Some(0));
+        assert!(!invoice.is_confirmed());
+    }
+
     #[test]
     fn expires_in() {
         init_logger();
@@ -558,4 +1450,60 @@ mod tests {
 
         assert_eq!(invoice.expiration_in(), 10);
     }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn decode_legacy_encoding() {
+        use super::{LegacyInvoice, LegacyTransfer};
+
+        init_logger();
+
+        let legacy = LegacyInvoice {
+            address: "testAddress".to_string(),
+            index: SubIndex::new(0, 1),
+            creation_height: 100,
+            amount_requested: 10,
+            amount_paid: 10,
+            paid_height: Some(105),
+            confirmations_required: 5,
+            current_height: 110,
+            expiration_height: 120,
+            transfers: vec![LegacyTransfer {
+                amount: 10,
+                height: Some(105),
+            }],
+            description: "test_description".to_string(),
+        };
+        let bytes = bincode::encode_to_vec(legacy, bincode::config::standard())
+            .expect("failed to encode legacy invoice");
+
+        let mut expected = Invoice::new(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            100,
+            10,
+            5,
+            20,
+            "test_description".to_string(),
+        );
+        expected.transfers = vec![Transfer::new(10, Some(105))];
+        expected.amount_paid = 10;
+        expected.paid_height = Some(105);
+        expected.current_height = 110;
+
+        let (invoice, _): (Invoice, _) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard())
+                .expect("failed to decode legacy invoice");
+        assert_eq!(invoice, expected);
+        assert!(invoice.is_confirmed());
+
+        // Re-encoded invoices use the current format.
+        let bytes = bincode::encode_to_vec(&invoice, bincode::config::standard())
+            .expect("failed to encode invoice");
+        assert_eq!(bytes[..2], [0xff, 1]);
+        let (decoded, _): (Invoice, _) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard())
+                .expect("failed to decode invoice");
+        assert_eq!(decoded, expected);
+    }
 }
@@ -112,6 +112,14 @@
 //! The `sqlite` feature enables the [`Sqlite`](storage::stores::Sqlite) invoice
 //! storage implementation. The `bincode` feature will also be enabled by this
 //! feature.
+//!
+//! ### `zmq`
+//!
+//! The `zmq` feature enables subscribing to the daemon's ZMQ notifications
+//! using
+//! [`PaymentGatewayBuilder::zmq_url`](PaymentGatewayBuilder::zmq_url), so that
+//! new blocks and transactions are scanned as soon as they are announced. This
+//! feature builds and links `libzmq`.
 
 #![warn(clippy::pedantic)]
 #![warn(missing_docs)]
@@ -125,19 +133,28 @@
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
 mod caching;
+mod deposit_account;
 mod invoice;
 mod payment_gateway;
 mod pubsub;
+mod rescan;
 mod rpc;
 mod scanner;
+mod shared_scanner;
 pub mod storage;
+#[cfg(feature = "zmq")]
+mod zmq_notifier;
 
 use std::fmt::Debug;
 
-pub use invoice::{Invoice, InvoiceId, SubIndex};
+pub use caching::SubaddressAllocation;
+pub use deposit_account::{DepositAccount, LedgerEntry};
+pub use invoice::{BillingPeriod, Invoice, InvoiceId, Recurrence, SubIndex, Transfer};
 pub use payment_gateway::{PaymentGateway, PaymentGatewayBuilder, PaymentGatewayStatus};
-pub use pubsub::{Subscriber, SubscriberError};
+pub use pubsub::{GatewayEvent, Subscriber, SubscriberError};
+pub use rescan::{RescanReport, RescanTarget, RescannedInvoice};
 use rpc::RpcError;
+pub use shared_scanner::{SharedScanner, SharedScannerBuilder};
 use thiserror::Error;
 
 /// Library's custom error type.
@@ -155,6 +172,30 @@ pub enum AcceptXmrError<E> {
     /// Failure to unblind the amount of an owned output.
     #[error("unable to unblind amount of owned output sent to subaddress index {0}")]
     Unblind(SubIndex),
+    /// All subaddresses available to the payment gateway are in use.
+    #[error("no subaddresses available; all subaddress indices in account {0} are in use")]
+    SubaddressesExhausted(u32),
+    /// No invoice with the given ID is being tracked.
+    #[error("invoice {0} is not tracked by the payment gateway")]
+    InvoiceNotFound(InvoiceId),
+    /// The invoice was already reissued.
+    #[error("invoice {invoice_id} was already reissued as invoice {successor}")]
+    AlreadyReissued {
+        /// ID of the invoice which was to be reissued.
+        invoice_id: InvoiceId,
+        /// ID of the invoice it was already reissued as.
+        successor: InvoiceId,
+    },
+    /// The invoice can't be reissued, because it has been paid in full or
+    /// hasn't expired yet.
+    #[error("invoice {0} cannot be reissued, because it is paid in full or has not expired")]
+    NotReissuable(InvoiceId),
+    /// A recurring invoice was requested with a zero-length billing period.
+    #[error("billing periods of recurring invoices must be at least one block or one second long")]
+    EmptyBillingPeriod,
+    /// The payment gateway was not configured to use the requested account.
+    #[error("account {0} is not tracked by the payment gateway")]
+    UnknownAccount(u32),
     /// Failure to parse private view key.
     #[error("failed to parse {datatype} from \"{input}\": {error}")]
     Parse {
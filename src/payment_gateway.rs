@@ -1,9 +1,13 @@
 use std::{
+    cmp::{max, min},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
-    ops::Deref,
+    num::NonZeroUsize,
+    ops::{Deref, RangeInclusive},
+    path::PathBuf,
     str::FromStr,
     sync::{
-        atomic::{self, AtomicU32, AtomicU64},
+        atomic::{self, AtomicBool, AtomicU64},
         mpsc::{channel, Receiver, Sender, TryRecvError},
         Arc, Mutex, PoisonError,
     },
@@ -13,25 +17,40 @@ use std::{
 
 use hyper::Uri;
 use log::{debug, error, info, warn};
-use monero::cryptonote::onetime_key::SubKeyChecker;
-use tokio::{join, runtime::Runtime, time};
+use tokio::{join, runtime::Runtime};
 
 use crate::{
-    caching::SubaddressCache,
+    caching::{
+        sub_key_checker_for, subaddress_count, SubaddressAllocation, SubaddressCache,
+        SubaddressCacheConfig, SubaddressRange, CATCH_UP_THRESHOLD,
+    },
+    invoice::{unix_time, Recurrence, Transfer},
     pubsub::{Publisher, Subscriber},
+    rescan::{self, reconciled_invoice},
     rpc::RpcClient,
-    scanner::{Scanner, ScannerHandle},
+    scanner::{OutputChecker, ScanTimer, Scanner, ScannerHandle},
     storage::{InvoiceStorage, Store},
-    AcceptXmrError, Invoice, InvoiceId,
+    AcceptXmrError, BillingPeriod, DepositAccount, GatewayEvent, Invoice, InvoiceId, RescanReport,
+    RescanTarget, SubIndex,
 };
 
-const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_millis(1000);
-const DEFAULT_DAEMON: &str = "http://node.moneroworld.com:18089";
+pub(crate) const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_millis(1000);
+pub(crate) const DEFAULT_DAEMON: &str = "http://node.moneroworld.com:18089";
 /// Timeout for RPC connection formation.
-const DEFAULT_RPC_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const DEFAULT_RPC_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
 /// Timeout for total call completion.
-const DEFAULT_RPC_TOTAL_TIMEOUT: Duration = Duration::from_secs(10);
-const DEFAULT_BLOCK_CACHE_SIZE: usize = 10;
+pub(crate) const DEFAULT_RPC_TOTAL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of blocks or batches of transactions requested at once.
+pub(crate) const DEFAULT_RPC_CONCURRENCY: usize = 16;
+pub(crate) const DEFAULT_BLOCK_CACHE_SIZE: usize = 10;
+/// Number of recent block hashes tracked to find the fork point of reorgs.
+pub(crate) const DEFAULT_REORG_DEPTH: usize = 60;
+
+/// Number of threads checking transactions for owned outputs, unless
+/// configured otherwise. One per CPU core.
+pub(crate) fn default_scan_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
 
 /// The `PaymentGateway` allows you to track new [`Invoice`](Invoice)s, remove
 /// old `Invoice`s from tracking, and subscribe to `Invoice`s that are already
@@ -41,21 +60,39 @@ pub struct PaymentGateway<S: InvoiceStorage>(pub(crate) Arc<PaymentGatewayInner<
 #[doc(hidden)]
 pub struct PaymentGatewayInner<S: InvoiceStorage> {
     rpc_client: RpcClient,
-    viewpair: monero::ViewPair,
+    pub(crate) viewpair: monero::ViewPair,
     scan_interval: Duration,
-    invoice_store: Store<S>,
-    subaddresses: Mutex<SubaddressCache>,
+    /// URL of the daemon's ZMQ publisher, if subscribing to its notifications.
+    zmq_url: Option<String>,
+    /// Number of threads checking transactions for owned outputs.
+    scan_threads: usize,
+    /// Where to save the block and txpool caches, if they are saved to disk.
+    cache_path: Option<PathBuf>,
+    pub(crate) invoice_store: Store<S>,
+    /// Subaddress caches, keyed by the account index they started in.
+    subaddresses: Mutex<BTreeMap<u32, SubaddressCache>>,
+    /// Default account index.
     major_index: u32,
-    highest_minor_index: Arc<AtomicU32>,
-    block_cache_height: Arc<AtomicU64>,
-    cached_daemon_height: Arc<AtomicU64>,
+    /// IDs of the watched addresses backing each deposit account, keyed by
+    /// user ID.
+    deposit_accounts: Mutex<HashMap<String, InvoiceId>>,
+    pub(crate) subaddress_ranges: Vec<SubaddressRange>,
+    /// Outputs smaller than this many piconeros are ignored as dust.
+    pub(crate) dust_threshold: u64,
+    /// Number of recent block hashes tracked to find the fork point of reorgs.
+    reorg_depth: usize,
+    pub(crate) block_cache_height: Arc<AtomicU64>,
+    pub(crate) cached_daemon_height: Arc<AtomicU64>,
     scanner_handle: Mutex<Option<ScannerHandle<S>>>,
+    /// Whether the payment gateway is attached to a
+    /// [`SharedScanner`](crate::SharedScanner).
+    pub(crate) attached: AtomicBool,
     /// Send commands to the scanning thread.
     scanner_command_sender: (
         Mutex<Sender<MessageToScanner>>,
         Arc<Mutex<Receiver<MessageToScanner>>>,
     ),
-    publisher: Arc<Publisher>,
+    pub(crate) publisher: Arc<Publisher>,
 }
 
 impl<S: InvoiceStorage> Clone for PaymentGateway<S> {
@@ -96,36 +133,35 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
     ///   necessary data from the monero daemon while starting.
     ///
     /// * Returns an [`AcceptXmrError::AlreadyRunning`] error if the payment
-    ///   gateway is already running.
+    ///   gateway is already running, or is attached to a
+    ///   [`SharedScanner`](crate::SharedScanner).
     ///
     /// * Returns an [`AcceptXmrError::Threading`] error if there was an error
     ///   creating the scanning thread.
-    #[allow(clippy::range_plus_one)]
     pub async fn run(&self) -> Result<(), AcceptXmrError<S::Error>> {
         // Determine if the scanning thread is already running.
-        {
-            let scanner_handle = self
-                .scanner_handle
-                .lock()
-                .unwrap_or_else(PoisonError::into_inner);
-            if let Some(handle) = scanner_handle.as_ref() {
-                if !handle.is_finished() {
-                    return Err(AcceptXmrError::AlreadyRunning);
-                }
-            };
+        if self.scanner_running() || self.attached.load(atomic::Ordering::Relaxed) {
+            return Err(AcceptXmrError::AlreadyRunning);
         }
 
         // Gather info needed by the scanner.
         let rpc_client = self.rpc_client.clone();
         let viewpair = self.viewpair;
         let scan_interval = self.scan_interval;
-        let major_index = self.major_index;
-        let highest_minor_index = self.highest_minor_index.clone();
+        let zmq_url = self.zmq_url.clone();
+        let output_checker = OutputChecker::new(self.scan_threads)?;
+        let cache_path = self.cache_path.clone();
+        let subaddress_ranges = self.subaddress_ranges.clone();
         let block_cache_height = self.block_cache_height.clone();
         let cached_daemon_height = self.cached_daemon_height.clone();
         let publisher = self.publisher.clone();
         let invoice_store = self.invoice_store.clone();
         let command_receiver = self.scanner_command_sender.1.clone();
+        let dust_threshold = self.dust_threshold;
+        let reorg_depth = self.reorg_depth;
+        // A weak reference, so that the scanning thread doesn't keep the payment
+        // gateway alive.
+        let payment_gateway = Arc::downgrade(&self.0);
 
         // Create scanner.
         debug!("Creating blockchain scanner");
@@ -133,9 +169,13 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
             rpc_client,
             invoice_store,
             DEFAULT_BLOCK_CACHE_SIZE,
+            reorg_depth,
             block_cache_height,
             cached_daemon_height,
             publisher,
+            dust_threshold,
+            output_checker,
+            cache_path,
         )
         .await?;
 
@@ -148,13 +188,11 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
                 let tokio_runtime = Runtime::new()?;
                 tokio_runtime.block_on(async move {
                     // Create persistent sub key checker for efficient tx output checking.
-                    let mut sub_key_checker = SubKeyChecker::new(
-                        &viewpair,
-                        1..2,
-                        0..highest_minor_index.load(atomic::Ordering::Relaxed) + 1,
-                    );
-                    // Scan for transactions once every scan_interval.
-                    let mut blockscan_interval = time::interval(scan_interval);
+                    let mut sub_key_checker = sub_key_checker_for(&viewpair, &subaddress_ranges);
+                    // Scan for transactions once every scan_interval, or when the daemon
+                    // announces them.
+                    let mut scan_timer = ScanTimer::new(scan_interval, zmq_url.as_deref());
+                    let mut fetch = true;
                     loop {
                         // If we're received the stop signal, stop.
                         match command_receiver.lock().unwrap_or_else(PoisonError::into_inner).try_recv() {
@@ -170,20 +208,23 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
                             }
                         }
                         // Update sub key checker if necessary.
-                        if sub_key_checker.table.len()
-                            <= highest_minor_index.load(atomic::Ordering::Relaxed) as usize
-                        {
-                            sub_key_checker = SubKeyChecker::new(
-                                &viewpair,
-                                major_index..major_index.saturating_add(1),
-                                0..highest_minor_index.load(atomic::Ordering::Relaxed).saturating_add(1),
-                            );
+                        if sub_key_checker.table.len() < subaddress_count(&subaddress_ranges) {
+                            sub_key_checker = sub_key_checker_for(&viewpair, &subaddress_ranges);
                         }
                         // Scan!
-                        if let (_, Err(e)) = join!(blockscan_interval.tick(), scanner.scan(&sub_key_checker)) {
+                        let (fetch_next, result) = join!(scan_timer.tick(), scanner.scan(&sub_key_checker, fetch));
+                        fetch = fetch_next;
+                        if let Err(e) = result {
                             error!("Payment gateway encountered an error while scanning for payments: {}", e);
                         };
+                        // Issue the next billing period of recurring invoices.
+                        if let Some(payment_gateway) = payment_gateway.upgrade().map(PaymentGateway) {
+                            if let Err(e) = payment_gateway.renew_recurring_invoices().await {
+                                error!("Payment gateway encountered an error while renewing recurring invoices: {e}");
+                            }
+                        }
                     }
+                    scanner.save_caches().await;
                 });
                 Ok(())
             })?.into());
@@ -191,8 +232,19 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
         Ok(())
     }
 
+    /// Returns `true` if this payment gateway's own scanning thread is
+    /// running.
+    pub(crate) fn scanner_running(&self) -> bool {
+        self.scanner_handle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map_or(false, |handle| !handle.is_finished())
+    }
+
     /// Returns the enum [`PaymentGatewayStatus`] describing whether the payment
-    /// gateway is running, not running, or has experienced an error.
+    /// gateway is running, catching up with the blockchain, not running, or
+    /// has experienced an error.
     #[must_use]
     pub fn status(&self) -> PaymentGatewayStatus<S> {
         let scanner_handle = self
@@ -215,7 +267,21 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
                     }
                 }
             }
-            Some(_) => PaymentGatewayStatus::Running,
+            Some(_) => {
+                let scanned_height = self.block_cache_height.load(atomic::Ordering::Relaxed);
+                let tip_height = self
+                    .cached_daemon_height
+                    .load(atomic::Ordering::Relaxed)
+                    .saturating_sub(1);
+                if tip_height.saturating_sub(scanned_height) > CATCH_UP_THRESHOLD {
+                    PaymentGatewayStatus::CatchingUp {
+                        scanned_height,
+                        tip_height,
+                    }
+                } else {
+                    PaymentGatewayStatus::Running
+                }
+            }
         }
     }
 
@@ -265,10 +331,16 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
     /// the ID of the new invoice. Use a [`Subscriber`] to receive updates
     /// on the new invoice invoice as they occur.
     ///
+    /// The invoice's subaddress is taken from the default account, as set by
+    /// [`PaymentGatewayBuilder::account_index`].
+    ///
     /// # Errors
     ///
-    /// Returns an error if there are any underlying issues modifying data in
-    /// the database.
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available to the payment gateway is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying data in the database.
     pub fn new_invoice(
         &self,
         piconeros: u64,
@@ -276,15 +348,39 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
         expiration_in: u64,
         description: String,
     ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
-        let amount = piconeros;
-
-        // Get subaddress in base58, and subaddress index.
-        let (sub_index, subaddress) = self
-            .subaddresses
-            .lock()
-            .unwrap_or_else(PoisonError::into_inner)
-            .remove_random();
+        self.new_invoice_for_account(
+            self.major_index,
+            piconeros,
+            confirmations_required,
+            expiration_in,
+            description,
+        )
+    }
 
+    /// Adds a new [`Invoice`] using a subaddress from the given account, and
+    /// returns the ID of the new invoice. The account must have been configured
+    /// using [`PaymentGatewayBuilder::account_index`] or
+    /// [`PaymentGatewayBuilder::accounts`].
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::UnknownAccount`] error if the payment
+    ///   gateway was not configured to use the given account.
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available in the account is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying data in the database.
+    pub fn new_invoice_for_account(
+        &self,
+        account_index: u32,
+        piconeros: u64,
+        confirmations_required: u64,
+        expiration_in: u64,
+        description: String,
+    ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        let (sub_index, subaddress) = self.next_subaddress(account_index)?;
         let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
 
         // Create invoice object.
@@ -292,26 +388,502 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
             subaddress,
             sub_index,
             creation_height,
-            amount,
+            piconeros,
+            confirmations_required,
+            expiration_in,
+            description,
+        );
+        self.track(invoice)
+    }
+
+    /// Adds a new [`Invoice`] to the payment gateway for tracking, with its own
+    /// dust threshold, and returns the ID of the new invoice. Outputs smaller
+    /// than `dust_threshold` piconeros received by the invoice are recorded as
+    /// [dust](Invoice::dust) rather than as payments, overriding the payment
+    /// gateway's [dust threshold](PaymentGatewayBuilder::dust_threshold).
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available to the payment gateway is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying data in the database.
+    pub fn new_invoice_with_dust_threshold(
+        &self,
+        piconeros: u64,
+        confirmations_required: u64,
+        expiration_in: u64,
+        dust_threshold: u64,
+        description: String,
+    ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        let (sub_index, subaddress) = self.next_subaddress(self.major_index)?;
+        let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
+
+        let mut invoice = Invoice::new(
+            subaddress,
+            sub_index,
+            creation_height,
+            piconeros,
+            confirmations_required,
+            expiration_in,
+            description,
+        );
+        invoice.dust_threshold = Some(dust_threshold);
+        self.track(invoice)
+    }
+
+    /// Adds a new open-amount [`Invoice`] to the payment gateway for tracking,
+    /// and returns the ID of the new invoice. Open-amount invoices let the payer
+    /// choose how much to pay, which suits tip jars and donation buttons.
+    ///
+    /// If `minimum_amount` is `None`, the invoice is paid by the first transfer
+    /// it receives in a block, rather than by transfers still in the txpool.
+    /// Otherwise, it is paid once the transfers it receives add up
+    /// to at least `minimum_amount` piconeros. The invoice keeps accepting
+    /// transfers until it expires, so its [`amount_paid`](Invoice::amount_paid)
+    /// at expiry is the total received. Its [URI](Invoice::uri) contains no
+    /// amount.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available to the payment gateway is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying data in the database.
+    pub fn new_open_invoice(
+        &self,
+        minimum_amount: Option<u64>,
+        confirmations_required: u64,
+        expiration_in: u64,
+        description: String,
+    ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        let (sub_index, subaddress) = self.next_subaddress(self.major_index)?;
+        let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
+
+        let invoice = Invoice::new_open_amount(
+            subaddress,
+            sub_index,
+            creation_height,
+            minimum_amount,
+            confirmations_required,
+            expiration_in,
+            description,
+        );
+        self.track(invoice)
+    }
+
+    /// Reissues an invoice, carrying over any credit it received. Only invoices
+    /// which expired before being paid in full can be reissued.
+    ///
+    /// The new invoice uses a new subaddress from the same account, and
+    /// requests only the amount still outstanding on the old invoice (i.e. its
+    /// `amount_requested` less its `amount_paid`). The two invoices are linked:
+    /// the new invoice's [`predecessor`](Invoice::predecessor) is the old
+    /// invoice, and the old invoice's [`successor`](Invoice::successor) is the
+    /// new one. The old invoice remains tracked until it is removed.
+    ///
+    /// Returns the ID of the new invoice.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceNotFound`] error if no invoice
+    ///   with the given ID is being tracked.
+    ///
+    /// * Returns an [`AcceptXmrError::AlreadyReissued`] error if the invoice
+    ///   has already been reissued.
+    ///
+    /// * Returns an [`AcceptXmrError::NotReissuable`] error if the invoice has
+    ///   been paid in full, or has not expired yet.
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available in the account is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying or retrieving data in the database.
+    pub fn reissue_invoice(
+        &self,
+        invoice_id: InvoiceId,
+        confirmations_required: u64,
+        expiration_in: u64,
+        description: String,
+    ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        let old = self
+            .get_invoice(invoice_id)?
+            .ok_or(AcceptXmrError::InvoiceNotFound(invoice_id))?;
+        check_reissuable(&old)?;
+
+        let (sub_index, subaddress) =
+            self.next_subaddress(self.account_of(invoice_id.sub_index))?;
+        let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
+
+        let mut invoice = Invoice::new(
+            subaddress,
+            sub_index,
+            creation_height,
+            old.amount_requested().saturating_sub(old.amount_paid()),
             confirmations_required,
             expiration_in,
             description,
         );
+        invoice.predecessor = Some(invoice_id);
+        invoice.dust_threshold = old.dust_threshold;
+        let new_invoice_id = self.track(invoice)?;
+
+        // Link the old invoice to the new one, unless it was paid or reissued in
+        // the meantime.
+        if let Err(e) =
+            self.invoice_store
+                .link_successor(invoice_id, new_invoice_id, check_reissuable)
+        {
+            self.invoice_store
+                .remove(new_invoice_id)
+                .map_err(AcceptXmrError::InvoiceStorage)?;
+            return Err(e);
+        }
+        debug!("Reissued invoice {invoice_id} as {new_invoice_id}");
+
+        Ok(new_invoice_id)
+    }
+
+    /// Adds a new recurring [`Invoice`] to the payment gateway for tracking,
+    /// and returns the ID of the invoice for its first billing period. Each
+    /// billing period lasts the given number of blocks or wall-clock time (see
+    /// [`BillingPeriod`]), and has its own invoice requesting `piconeros`.
+    ///
+    /// When a billing period ends, the payment gateway issues an invoice for the
+    /// next period, using either the same subaddress (if `reuse_subaddress` is
+    /// `true`) or a fresh one from the default account. The two invoices are
+    /// linked through [`Invoice::successor`] and [`Invoice::predecessor`], and
+    /// [`Invoice::recurrence`] describes the period each invoice covers. A
+    /// payment is credited to the period in which it is received.
+    ///
+    /// A final update is published for each billing period as it ends. If the
+    /// period went unpaid, [`Invoice::is_expired`] returns `true` for that
+    /// update, and a [`GatewayEvent::BillingPeriodUnpaid`] is sent to
+    /// [event subscribers](PaymentGateway::subscribe_events). Invoices for new
+    /// periods can be received using
+    /// [`subscribe_all`](PaymentGateway::subscribe_all).
+    ///
+    /// Billing periods are only renewed while the payment gateway is running.
+    /// To cancel a recurring invoice, remove the invoice of its current period
+    /// using [`remove_invoice`](PaymentGateway::remove_invoice).
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::EmptyBillingPeriod`] error if the billing
+    ///   period is shorter than one block or one second.
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available to the payment gateway is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying data in the database.
+    pub fn new_recurring_invoice(
+        &self,
+        piconeros: u64,
+        confirmations_required: u64,
+        billing_period: BillingPeriod,
+        reuse_subaddress: bool,
+        description: String,
+    ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        if billing_period.is_empty() {
+            return Err(AcceptXmrError::EmptyBillingPeriod);
+        }
+        let (sub_index, subaddress) = self.next_subaddress(self.major_index)?;
+        let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
+        let period_start = match billing_period {
+            BillingPeriod::Blocks(_) => creation_height,
+            BillingPeriod::Time(_) => unix_time(),
+        };
+
+        let invoice = Invoice::new_recurring(
+            subaddress,
+            sub_index,
+            creation_height,
+            piconeros,
+            confirmations_required,
+            description,
+            Recurrence::new(billing_period, reuse_subaddress, period_start),
+        );
+        self.track(invoice)
+    }
 
-        // Insert invoice into database for tracking.
+    /// Issues the next billing period of every recurring invoice whose current
+    /// period is over, and publishes a final update for each period that ended.
+    pub(crate) async fn renew_recurring_invoices(&self) -> Result<(), AcceptXmrError<S::Error>> {
+        let now = unix_time();
+        let mut ended_periods = Vec::new();
+        for invoice_or_err in self
+            .invoice_store
+            .lock()
+            .try_iter()
+            .map_err(AcceptXmrError::InvoiceStorage)?
+        {
+            let invoice = invoice_or_err.map_err(AcceptXmrError::InvoiceStorage)?;
+            if invoice.successor().is_some() {
+                continue;
+            }
+            if let Some(recurrence) = invoice.recurrence {
+                if recurrence.is_over(invoice.current_height(), now) {
+                    ended_periods.push((invoice, recurrence));
+                }
+            }
+        }
+
+        for (mut ended, mut recurrence) in ended_periods {
+            let (sub_index, subaddress) = if recurrence.reuses_subaddress() {
+                (ended.index(), ended.address().to_string())
+            } else {
+                self.next_subaddress(self.account_of(ended.index()))?
+            };
+            let next = recurrence.next();
+            let creation_height = match recurrence.billing_period() {
+                BillingPeriod::Blocks(_) => next.period_start(),
+                // Invoices sharing a subaddress need distinct creation heights.
+                BillingPeriod::Time(_) => max(
+                    self.cached_daemon_height.load(atomic::Ordering::Relaxed),
+                    ended.creation_height() + 1,
+                ),
+            };
+
+            let mut invoice = Invoice::new_recurring(
+                subaddress,
+                sub_index,
+                creation_height,
+                ended.amount_requested(),
+                ended.confirmations_required(),
+                ended.description().to_string(),
+                next,
+            );
+            invoice.predecessor = Some(ended.id());
+            invoice.current_height = ended.current_height();
+            invoice.dust_threshold = ended.dust_threshold;
+            // Transfers to a shared subaddress made since the new period started
+            // belong to the new period.
+            if recurrence.reuses_subaddress() {
+                let period_start = Transfer::new(0, Some(creation_height - 1));
+                let (moved, kept) = ended
+                    .transfers
+                    .iter()
+                    .partition(|transfer| transfer.cmp_by_height(&period_start).is_gt());
+                invoice.transfers = moved;
+                ended.transfers = kept;
+                let (moved, kept) = ended
+                    .dust
+                    .iter()
+                    .partition(|transfer| transfer.cmp_by_height(&period_start).is_gt());
+                invoice.dust = moved;
+                ended.dust = kept;
+                invoice.recalculate_paid();
+                ended.recalculate_paid();
+            }
+            let successor = self.track(invoice)?;
+
+            recurrence.ended = true;
+            ended.recurrence = Some(recurrence);
+            ended.successor = Some(successor);
+            self.invoice_store
+                .update(ended.clone())
+                .map_err(AcceptXmrError::InvoiceStorage)?;
+            debug!("Renewed recurring invoice {} as {successor}", ended.id());
+            self.publisher.send_updates(&ended).await;
+            if ended.amount_paid() < ended.amount_requested() {
+                info!(
+                    "Billing period {} of recurring invoice {} ended unpaid",
+                    recurrence.period_index(),
+                    ended.id()
+                );
+                self.publisher
+                    .send_event(&GatewayEvent::BillingPeriodUnpaid {
+                        invoice_id: ended.id(),
+                        period_index: recurrence.period_index(),
+                        amount_paid: ended.amount_paid(),
+                        successor,
+                    })
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reserves a subaddress from the default account as a watched address,
+    /// and returns its ID. Unlike an invoice, a watched address never expires
+    /// and accepts any number of payments of any amount, making it suitable as
+    /// a static donation or deposit address.
+    ///
+    /// Watched addresses are stored and scanned for alongside invoices. Use a
+    /// [`Subscriber`] to receive an update each time a transfer to the address
+    /// is received, confirmed, or removed by a reorg. The transfers received
+    /// are available from [`Invoice::transfers`]. To stop watching the address,
+    /// pass its ID to [`remove_invoice`](PaymentGateway::remove_invoice).
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if every
+    ///   subaddress available to the payment gateway is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying data in the database.
+    pub fn watch_address(
+        &self,
+        description: String,
+    ) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        let (sub_index, subaddress) = self.next_subaddress(self.major_index)?;
+        let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
+
+        let watched_address =
+            Invoice::new_watched(subaddress, sub_index, creation_height, description);
+        self.track(watched_address)
+    }
+
+    /// Returns the [`DepositAccount`] of the given user, creating it if it does
+    /// not exist yet. Each user's deposit account has a stable subaddress,
+    /// taken from the default account, which accepts any number of deposits.
+    ///
+    /// To receive updates as deposits arrive, [subscribe](PaymentGateway::subscribe)
+    /// to the deposit account's [ID](DepositAccount::id). To stop tracking the
+    /// deposit account, pass its ID to
+    /// [`remove_invoice`](PaymentGateway::remove_invoice).
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::SubaddressesExhausted`] error if the
+    ///   deposit account does not exist yet, and every subaddress available to
+    ///   the payment gateway is in use.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there are any
+    ///   underlying issues modifying or retrieving data in the database.
+    pub fn deposit_account(
+        &self,
+        user_id: &str,
+    ) -> Result<DepositAccount, AcceptXmrError<S::Error>> {
+        let mut deposit_accounts = self
+            .deposit_accounts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(deposit_account) = self.find_deposit_account(&deposit_accounts, user_id)? {
+            return Ok(deposit_account);
+        }
+
+        let (sub_index, subaddress) = self.next_subaddress(self.major_index)?;
+        let creation_height = self.cached_daemon_height.load(atomic::Ordering::Relaxed);
+        let deposit_address = Invoice::new_deposit_address(
+            subaddress,
+            sub_index,
+            creation_height,
+            user_id.to_string(),
+        );
+        let invoice_id = self.track(deposit_address.clone())?;
+        deposit_accounts.insert(user_id.to_string(), invoice_id);
+        debug!("Created deposit account for user {user_id}");
+
+        Ok(DepositAccount::new(deposit_address))
+    }
+
+    /// Returns the [`DepositAccount`] of the given user, or `None` if the user
+    /// has no deposit account.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are any underlying issues retrieving data from
+    /// the database.
+    pub fn get_deposit_account(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<DepositAccount>, AcceptXmrError<S::Error>> {
+        let deposit_accounts = self
+            .deposit_accounts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.find_deposit_account(&deposit_accounts, user_id)
+    }
+
+    /// Returns the balance in piconeros of the given user's deposit account,
+    /// counting only deposits with at least `min_confirmations` confirmations.
+    /// Returns `0` if the user has no deposit account.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are any underlying issues retrieving data from
+    /// the database.
+    pub fn balance(
+        &self,
+        user_id: &str,
+        min_confirmations: u64,
+    ) -> Result<u64, AcceptXmrError<S::Error>> {
+        Ok(self
+            .get_deposit_account(user_id)?
+            .map_or(0, |deposit_account| {
+                deposit_account.balance(min_confirmations)
+            }))
+    }
+
+    fn find_deposit_account(
+        &self,
+        deposit_accounts: &HashMap<String, InvoiceId>,
+        user_id: &str,
+    ) -> Result<Option<DepositAccount>, AcceptXmrError<S::Error>> {
+        let Some(invoice_id) = deposit_accounts.get(user_id) else {
+            return Ok(None);
+        };
+        Ok(self
+            .get_invoice(*invoice_id)?
+            .and_then(|invoice| DepositAccount::try_from(invoice).ok()))
+    }
+
+    /// Returns the account whose subaddress cache the given subaddress belongs
+    /// to. Accounts which aren't tracked directly belong to the default
+    /// account, which may have rolled over into them.
+    fn account_of(&self, sub_index: SubIndex) -> u32 {
+        if self
+            .subaddresses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(&sub_index.major)
+        {
+            sub_index.major
+        } else {
+            self.major_index
+        }
+    }
+
+    /// Take the next available subaddress from the given account.
+    fn next_subaddress(
+        &self,
+        account_index: u32,
+    ) -> Result<(SubIndex, String), AcceptXmrError<S::Error>> {
+        let mut subaddresses = self
+            .subaddresses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let account = subaddresses
+            .get_mut(&account_index)
+            .ok_or(AcceptXmrError::UnknownAccount(account_index))?;
+        let major_index = account.major_index();
+        account
+            .remove_next()
+            .ok_or(AcceptXmrError::SubaddressesExhausted(major_index))
+    }
+
+    /// Insert invoice into database for tracking, and return its ID.
+    fn track(&self, invoice: Invoice) -> Result<InvoiceId, AcceptXmrError<S::Error>> {
+        let invoice_id = invoice.id();
         self.invoice_store
-            .insert(invoice.clone())
+            .insert(invoice)
             .map_err(AcceptXmrError::InvoiceStorage)?;
         debug!(
             "Now tracking invoice to subaddress index {}",
-            invoice.index()
+            invoice_id.sub_index
         );
 
-        self.publisher.insert_invoice(invoice.id());
+        self.publisher.insert_invoice(invoice_id);
 
         // Return invoice id so the user can build identify their invoice, and make a
         // subscriber for it if desired.
-        Ok(invoice.id())
+        Ok(invoice_id)
     }
 
     /// Remove (i.e. stop tracking) invoice, returning the old invoice if it
@@ -331,16 +903,38 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
             .map_err(AcceptXmrError::InvoiceStorage)?
         {
             Some(old) => {
-                if !(old.is_expired()
+                if !(old.is_watched()
+                    || old.is_expired()
                     || old.is_confirmed() && old.creation_height() < old.current_height())
                 {
                     warn!("Removed an invoice which was neither expired, nor fully confirmed and a block or more old. Was this intentional?");
                 }
-                // Put the subaddress back in the subaddress cache.
-                self.subaddresses
-                    .lock()
-                    .unwrap_or_else(PoisonError::into_inner)
-                    .insert(invoice_id.sub_index, old.address().to_string());
+                // Put the subaddress back in the subaddress cache, unless another
+                // invoice (e.g. another billing period of a recurring invoice) still
+                // uses it.
+                if !self
+                    .invoice_store
+                    .contains_sub_index(invoice_id.sub_index)
+                    .map_err(AcceptXmrError::InvoiceStorage)?
+                {
+                    let account_index = self.account_of(invoice_id.sub_index);
+                    if let Some(account) = self
+                        .subaddresses
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .get_mut(&account_index)
+                    {
+                        account.insert(invoice_id.sub_index, old.address().to_string());
+                    }
+                }
+
+                // Forget the deposit account, if this was one.
+                if let Some(user_id) = old.user_id() {
+                    self.deposit_accounts
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(user_id);
+                }
 
                 // Kill any related subscriptions.
                 self.publisher.remove_invoice(invoice_id);
@@ -351,6 +945,122 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
         }
     }
 
+    /// Rescan the blocks at `heights` for transfers to the given `targets`, and
+    /// reconcile the transfers found with the stored invoices. This recovers
+    /// payments the scanner missed, e.g. because of a bug, an outage, or a
+    /// daemon which served incomplete blocks.
+    ///
+    /// Each targeted invoice's transfers from the rescanned blocks are replaced
+    /// by those found, so transfers which aren't in those blocks after all are
+    /// removed too. Changed invoices are saved and published to subscribers
+    /// like any other update, and listed in the returned [`RescanReport`].
+    ///
+    /// Blocks above the scanner's current height are skipped, because the
+    /// scanner will scan them shortly anyway. The rescan downloads and scans
+    /// blocks separately from the scanner, so live scanning carries on while it
+    /// runs, and only pauses while the changes are saved.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceNotFound`] error if a targeted
+    ///   invoice doesn't exist.
+    ///
+    /// * Returns an [`AcceptXmrError::Rpc`] error if there was an issue getting
+    ///   the blocks from the monero daemon.
+    ///
+    /// * Returns an [`AcceptXmrError::InvoiceStorage`] error if there was an
+    ///   underlying issue with the database.
+    pub async fn rescan(
+        &self,
+        heights: RangeInclusive<u64>,
+        targets: &[RescanTarget],
+    ) -> Result<RescanReport, AcceptXmrError<S::Error>> {
+        for target in targets {
+            if let RescanTarget::Invoice(invoice_id) = *target {
+                if self
+                    .invoice_store
+                    .get(invoice_id)
+                    .map_err(AcceptXmrError::InvoiceStorage)?
+                    .is_none()
+                {
+                    return Err(AcceptXmrError::InvoiceNotFound(invoice_id));
+                }
+            }
+        }
+        let heights = *heights.start()..=min(*heights.end(), self.cache_height());
+        if heights.is_empty() || targets.is_empty() {
+            return Ok(RescanReport::new(None, Vec::new()));
+        }
+
+        let sub_indices: Vec<SubIndex> = targets
+            .iter()
+            .map(|target| target.sub_index())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        info!(
+            "Rescanning blocks {} to {} for {} subaddresses",
+            heights.start(),
+            heights.end(),
+            sub_indices.len()
+        );
+        let transfers = rescan::scan(
+            &self.rpc_client,
+            self.viewpair,
+            &sub_indices,
+            heights.clone(),
+        )
+        .await?;
+
+        // Hold off the scanner's updates, so that neither overwrites the other.
+        let _updates = self.invoice_store.lock_updates().await;
+        let mut updates = Vec::new();
+        for invoice_or_err in self
+            .invoice_store
+            .lock()
+            .try_iter()
+            .map_err(AcceptXmrError::InvoiceStorage)?
+        {
+            let old_invoice = invoice_or_err.map_err(AcceptXmrError::InvoiceStorage)?;
+            if !targets.iter().any(|target| target.covers(old_invoice.id())) {
+                continue;
+            }
+            if let Some(rescanned) =
+                reconciled_invoice(&old_invoice, &transfers, &heights, self.dust_threshold)
+            {
+                updates.push((old_invoice.is_spendable(), rescanned));
+            }
+        }
+
+        let mut rescanned_invoices = Vec::new();
+        for (was_spendable, rescanned) in updates {
+            let invoice = rescanned.invoice();
+            self.invoice_store
+                .update(invoice.clone())
+                .map_err(AcceptXmrError::InvoiceStorage)?;
+            // Dust is recorded, but never published on its own.
+            if rescanned.added().is_empty() && rescanned.removed().is_empty() {
+                continue;
+            }
+            info!(
+                "Rescan of invoice {} added {} and removed {} transfers",
+                invoice.id(),
+                rescanned.added().len(),
+                rescanned.removed().len()
+            );
+            self.publisher.send_updates(invoice).await;
+            if invoice.is_spendable() && !was_spendable {
+                self.publisher.send_spendable(invoice).await;
+            }
+            rescanned_invoices.push(rescanned);
+        }
+        self.invoice_store
+            .flush()
+            .map_err(AcceptXmrError::InvoiceStorage)?;
+
+        Ok(RescanReport::new(Some(heights), rescanned_invoices))
+    }
+
     /// Returns a `Subscriber` for the given invoice ID. If a tracked invoice
     /// exists for that ID, the subscriber can be used to receive updates to
     /// for that invoice.
@@ -365,6 +1075,21 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
         self.publisher.subscribe_all()
     }
 
+    /// Returns a `Subscriber` which receives each invoice once, when enough of
+    /// the amount it received becomes spendable to cover the amount requested.
+    /// See [`Invoice::is_spendable`].
+    #[must_use]
+    pub fn subscribe_spendable(&self) -> Subscriber {
+        self.publisher.subscribe_spendable()
+    }
+
+    /// Returns a `Subscriber` for [`GatewayEvent`]s, such as blockchain reorgs
+    /// deeper than the payment gateway can repair.
+    #[must_use]
+    pub fn subscribe_events(&self) -> Subscriber<GatewayEvent> {
+        self.publisher.subscribe_events()
+    }
+
     /// Get current height of daemon using a monero daemon remote procedure
     /// call.
     ///
@@ -407,6 +1132,21 @@ impl<S: InvoiceStorage + 'static> PaymentGateway<S> {
     }
 }
 
+/// Checks that an invoice can be reissued: it must have expired without being
+/// paid in full, and must not have been reissued already.
+fn check_reissuable<E>(invoice: &Invoice) -> Result<(), AcceptXmrError<E>> {
+    if let Some(successor) = invoice.successor() {
+        return Err(AcceptXmrError::AlreadyReissued {
+            invoice_id: invoice.id(),
+            successor,
+        });
+    }
+    if invoice.amount_paid() >= invoice.amount_requested() || !invoice.is_expired() {
+        return Err(AcceptXmrError::NotReissuable(invoice.id()));
+    }
+    Ok(())
+}
+
 /// A builder for the payment gateway. Used to configure your desired monero
 /// daemon, scan interval, view key, etc.
 ///
@@ -439,11 +1179,21 @@ pub struct PaymentGatewayBuilder<S> {
     daemon_password: Option<String>,
     rpc_timeout: Duration,
     rpc_connection_timeout: Duration,
+    rpc_concurrency: usize,
     private_view_key: String,
     primary_address: String,
     scan_interval: Duration,
+    zmq_url: Option<String>,
+    scan_threads: usize,
+    cache_path: Option<PathBuf>,
     invoice_store: S,
     major_index: u32,
+    additional_accounts: BTreeSet<u32>,
+    subaddress_allocation: SubaddressAllocation,
+    wallet_lookahead: Option<SubIndex>,
+    account_rollover: Option<u32>,
+    dust_threshold: u64,
+    reorg_depth: usize,
     seed: Option<u64>,
 }
 
@@ -461,11 +1211,21 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
             daemon_password: None,
             rpc_timeout: DEFAULT_RPC_TOTAL_TIMEOUT,
             rpc_connection_timeout: DEFAULT_RPC_CONNECTION_TIMEOUT,
+            rpc_concurrency: DEFAULT_RPC_CONCURRENCY,
             private_view_key,
             primary_address,
             scan_interval: DEFAULT_SCAN_INTERVAL,
+            zmq_url: None,
+            scan_threads: default_scan_threads(),
+            cache_path: None,
             invoice_store: store,
             major_index: 0,
+            additional_accounts: BTreeSet::new(),
+            subaddress_allocation: SubaddressAllocation::default(),
+            wallet_lookahead: None,
+            account_rollover: None,
+            dust_threshold: 0,
+            reorg_depth: DEFAULT_REORG_DEPTH,
             seed: None,
         }
     }
@@ -536,6 +1296,16 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
         self
     }
 
+    /// Maximum number of blocks, or batches of up to 100 transactions,
+    /// requested from the daemon at once. Raising this speeds up startup and
+    /// the scanning of large blocks when the daemon is far away, at the cost
+    /// of more load on the daemon. Defaults to 16.
+    #[must_use]
+    pub fn rpc_concurrency(mut self, requests: usize) -> PaymentGatewayBuilder<S> {
+        self.rpc_concurrency = requests;
+        self
+    }
+
     /// Set the minimum scan interval. New blocks and transactions will be
     /// scanned for relevant outputs at most every `interval`. Defaults to 1
     /// second.
@@ -545,8 +1315,61 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
         self
     }
 
+    /// Subscribe to the daemon's ZMQ notifications, published at `url` (e.g.
+    /// `tcp://127.0.0.1:18083`, as set by monerod's `--zmq-pub` option).
+    ///
+    /// New blocks and txpool transactions are then fetched and scanned as soon
+    /// as the daemon announces them. Otherwise, the daemon is only polled every
+    /// 30 seconds in case notifications are missed, while new invoices are
+    /// still picked up every [scan interval](PaymentGatewayBuilder::scan_interval).
+    /// Whenever the connection is down, the payment gateway falls back to
+    /// polling the daemon every scan interval.
+    #[cfg(feature = "zmq")]
+    #[must_use]
+    pub fn zmq_url(mut self, url: String) -> PaymentGatewayBuilder<S> {
+        self.zmq_url = Some(url);
+        self
+    }
+
+    /// Number of threads checking new blocks and txpool transactions for
+    /// payments. Checking for owned outputs is CPU-bound, so spreading the
+    /// work across several threads speeds up scanning of full blocks and large
+    /// txpools. With 1 thread, transactions are checked on the scanning thread
+    /// itself. Defaults to the number of CPU cores.
+    ///
+    /// Ignored while the payment gateway is attached to a
+    /// [`SharedScanner`](crate::SharedScanner), which uses its own
+    /// [`scan_threads`](crate::SharedScannerBuilder::scan_threads).
+    #[must_use]
+    pub fn scan_threads(mut self, threads: usize) -> PaymentGatewayBuilder<S> {
+        self.scan_threads = threads;
+        self
+    }
+
+    /// File to save the block cache and txpool cache to, so that they needn't
+    /// all be fetched from the daemon again on [`run`](PaymentGateway::run).
+    /// The caches are saved whenever a new block is scanned, and when the
+    /// scanner stops. On startup, cached blocks are checked against the
+    /// daemon's block IDs, and only those that differ are fetched. Txpool
+    /// transactions received while the scanner was stopped are scanned too.
+    ///
+    /// By default, the caches are not saved, and are fetched in full on every
+    /// run.
+    ///
+    /// Ignored while the payment gateway is attached to a
+    /// [`SharedScanner`](crate::SharedScanner), which uses its own
+    /// [`cache_path`](crate::SharedScannerBuilder::cache_path).
+    #[must_use]
+    pub fn cache_path(mut self, path: String) -> PaymentGatewayBuilder<S> {
+        self.cache_path = Some(PathBuf::from(path));
+        self
+    }
+
     /// Seed for random number generator. Use only for reproducible testing. Do
     /// not set in a production environment.
+    ///
+    /// Additional [`accounts`](PaymentGatewayBuilder::accounts) mix their index
+    /// into the seed, so each uses its subaddresses in a different order.
     #[must_use]
     pub fn seed(mut self, seed: u64) -> PaymentGatewayBuilder<S> {
         warn!("Seed set to {}. Some operations intended to be random (like the order in which subaddresses are used) will be predictable.", seed);
@@ -556,12 +1379,131 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
 
     /// Set the account index (i.e. subaddress major index) the payment gateway
     /// should use. Defaults to account index 0.
+    ///
+    /// If multiple accounts are tracked, this is the account used by
+    /// [`new_invoice`](PaymentGateway::new_invoice).
     #[must_use]
     pub fn account_index(mut self, index: u32) -> PaymentGatewayBuilder<S> {
         self.major_index = index;
         self
     }
 
+    /// Track additional accounts (i.e. subaddress major indices) alongside the
+    /// one set by [`account_index`](PaymentGatewayBuilder::account_index). Each
+    /// account gets its own pool of subaddresses, and all of them are scanned
+    /// together. Use
+    /// [`new_invoice_for_account`](PaymentGateway::new_invoice_for_account) to
+    /// create invoices in a specific account.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use acceptxmr::{PaymentGatewayBuilder, storage::stores::InMemory};
+    ///
+    /// let private_view_key =
+    ///     "ad2093a5705b9f33e6f0f0c1bc1f5f639c756cdfc168c8f2ac6127ccbdab3a03";
+    /// let primary_address =
+    ///     "4613YiHLM6JMH4zejMB2zJY5TwQCxL8p65ufw8kBP5yxX9itmuGLqp1dS4tkVoTxjyH3aYhYNrtGHbQzJQP5bFus3KHVdmf";
+    ///
+    /// // One storefront per account.
+    /// let payment_gateway = PaymentGatewayBuilder::new(
+    ///     private_view_key.to_string(),
+    ///     primary_address.to_string(),
+    ///     InMemory::new()
+    /// )
+    /// .account_index(1)
+    /// .accounts([2, 3])
+    /// .build()?;
+    ///
+    /// let invoice_id = payment_gateway.new_invoice_for_account(3, 1_000_000, 2, 10, "for pizza".to_string())?;
+    /// assert_eq!(invoice_id.sub_index.major, 3);
+    /// #   Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn accounts(mut self, indices: impl IntoIterator<Item = u32>) -> PaymentGatewayBuilder<S> {
+        self.additional_accounts.extend(indices);
+        self
+    }
+
+    /// Set the strategy used to pick a subaddress for each new invoice.
+    /// Defaults to [`SubaddressAllocation::Random`].
+    ///
+    /// [`SubaddressAllocation::Sequential`] always picks the lowest available
+    /// subaddress index, keeping used indices dense so that your wallet will
+    /// find incoming payments without needing a large subaddress lookahead.
+    #[must_use]
+    pub fn subaddress_allocation(
+        mut self,
+        allocation: SubaddressAllocation,
+    ) -> PaymentGatewayBuilder<S> {
+        self.subaddress_allocation = allocation;
+        self
+    }
+
+    /// Set the subaddress lookahead (major and minor) of the wallet you will
+    /// use to spend received funds. `monero-wallet-cli` defaults to `50:200`.
+    ///
+    /// When configured, the payment gateway will log a warning whenever it
+    /// allocates a subaddress index your wallet would not see without first
+    /// increasing its lookahead.
+    #[must_use]
+    pub fn wallet_lookahead(mut self, major: u32, minor: u32) -> PaymentGatewayBuilder<S> {
+        self.wallet_lookahead = Some(SubIndex::new(major, minor));
+        self
+    }
+
+    /// Automatically move on to the next account (i.e. subaddress major index)
+    /// once every minor index up to and including `max_minor_index` has been
+    /// handed out in the current account. Subaddresses from all accounts used
+    /// this way will be scanned for payments.
+    ///
+    /// Only the default account (see
+    /// [`account_index`](PaymentGatewayBuilder::account_index)) is rolled
+    /// over. Accounts added using
+    /// [`accounts`](PaymentGatewayBuilder::accounts) are skipped.
+    ///
+    /// By default, accounts are never rolled over, and
+    /// [`new_invoice`](PaymentGateway::new_invoice) returns an
+    /// [`AcceptXmrError::SubaddressesExhausted`] error once the account's
+    /// subaddresses run out.
+    #[must_use]
+    pub fn account_rollover(mut self, max_minor_index: u32) -> PaymentGatewayBuilder<S> {
+        self.account_rollover = Some(max_minor_index);
+        self
+    }
+
+    /// Ignore received outputs smaller than `piconeros`. Such outputs are
+    /// recorded as [dust](Invoice::dust), which doesn't count towards an
+    /// invoice's amount paid and doesn't trigger updates to subscribers. This
+    /// protects against dust attacks, in which tiny outputs are sent to
+    /// subaddresses to spam updates or link addresses together.
+    ///
+    /// Individual invoices can override this threshold using
+    /// [`new_invoice_with_dust_threshold`](PaymentGateway::new_invoice_with_dust_threshold).
+    /// By default, no outputs are ignored.
+    #[must_use]
+    pub fn dust_threshold(mut self, piconeros: u64) -> PaymentGatewayBuilder<S> {
+        self.dust_threshold = piconeros;
+        self
+    }
+
+    /// Number of recent blocks whose hashes are tracked to detect blockchain
+    /// reorgs. When a reorg occurs, the payment gateway walks back to the fork
+    /// point and rolls back invoices' transfers from orphaned blocks. If the
+    /// fork goes deeper than this, a
+    /// [`GatewayEvent::ReorgBeyondWindow`](crate::GatewayEvent::ReorgBeyondWindow)
+    /// is sent to [event subscribers](PaymentGateway::subscribe_events).
+    ///
+    /// Values smaller than the block cache (10 blocks) are raised to its size.
+    /// Defaults to 60 blocks.
+    #[must_use]
+    pub fn reorg_depth(mut self, blocks: usize) -> PaymentGatewayBuilder<S> {
+        self.reorg_depth = blocks;
+        self
+    }
+
     /// Build the payment gateway.
     ///
     /// # Errors
@@ -583,6 +1525,7 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
             self.daemon_username,
             self.daemon_password,
             self.seed,
+            self.rpc_concurrency,
         );
 
         let invoice_store = Store::new(self.invoice_store);
@@ -604,16 +1547,51 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
                 .public_spend,
         };
 
-        let highest_minor_index = Arc::new(AtomicU32::new(0));
-        let subaddresses = SubaddressCache::init(
-            &invoice_store,
-            viewpair,
-            self.major_index,
-            highest_minor_index.clone(),
-            self.seed,
-        )
-        .map_err(AcceptXmrError::InvoiceStorage)?;
-        debug!("Generated {} initial subaddresses", subaddresses.len());
+        let mut accounts = self.additional_accounts;
+        accounts.remove(&self.major_index);
+        let mut subaddresses = BTreeMap::new();
+        let mut subaddress_ranges = Vec::new();
+        for major_index in accounts.iter().copied().chain([self.major_index]) {
+            // Only the default account rolls over, skipping all other accounts.
+            let (account_rollover, reserved_accounts) = if major_index == self.major_index {
+                (self.account_rollover, accounts.clone())
+            } else {
+                (None, BTreeSet::new())
+            };
+            // Mix the account into the seed of additional accounts, so they
+            // don't all hand out the same sequence of minor indices. The
+            // default account keeps the seed as given.
+            let seed = self.seed.map(|seed| {
+                if major_index == self.major_index {
+                    seed
+                } else {
+                    seed ^ ((u64::from(major_index) + 1) << 32)
+                }
+            });
+            let range = SubaddressRange::new(major_index);
+            let subaddress_cache = SubaddressCache::init(
+                &invoice_store,
+                viewpair,
+                range.clone(),
+                SubaddressCacheConfig {
+                    allocation: self.subaddress_allocation,
+                    wallet_lookahead: self.wallet_lookahead,
+                    account_rollover,
+                    reserved_accounts,
+                    seed,
+                },
+            )
+            .map_err(AcceptXmrError::InvoiceStorage)?;
+            debug!(
+                "Generated {} initial subaddresses for account {}",
+                subaddress_cache.len(),
+                major_index
+            );
+            subaddresses.insert(major_index, subaddress_cache);
+            subaddress_ranges.push(range);
+        }
+
+        let deposit_accounts = recover_deposit_accounts(&invoice_store)?;
 
         let (scanner_cmd_tx, scanner_cmd_rx) = channel();
         let scanner_command_sender = (
@@ -625,24 +1603,60 @@ impl<S: InvoiceStorage> PaymentGatewayBuilder<S> {
             rpc_client,
             viewpair,
             scan_interval: self.scan_interval,
+            zmq_url: self.zmq_url,
+            scan_threads: self.scan_threads,
+            cache_path: self.cache_path,
             invoice_store,
             subaddresses: Mutex::new(subaddresses),
             major_index: self.major_index,
-            highest_minor_index,
+            deposit_accounts: Mutex::new(deposit_accounts),
+            subaddress_ranges,
+            dust_threshold: self.dust_threshold,
+            reorg_depth: self.reorg_depth,
             block_cache_height: Arc::new(atomic::AtomicU64::new(0)),
             cached_daemon_height: Arc::new(atomic::AtomicU64::new(0)),
             scanner_handle: Mutex::new(None),
+            attached: AtomicBool::new(false),
             scanner_command_sender,
             publisher: Arc::new(Publisher::new()),
         })))
     }
 }
 
+/// Recover the IDs of the watched addresses backing each deposit account from
+/// storage, keyed by user ID.
+fn recover_deposit_accounts<S: InvoiceStorage>(
+    invoice_store: &Store<S>,
+) -> Result<HashMap<String, InvoiceId>, AcceptXmrError<S::Error>> {
+    let mut deposit_accounts = HashMap::new();
+    for invoice in invoice_store
+        .lock()
+        .try_iter()
+        .map_err(AcceptXmrError::InvoiceStorage)?
+    {
+        let invoice = invoice.map_err(AcceptXmrError::InvoiceStorage)?;
+        if let Some(user_id) = invoice.user_id() {
+            deposit_accounts.insert(user_id.to_string(), invoice.id());
+        }
+    }
+    Ok(deposit_accounts)
+}
+
 /// Enumeration of possible payment gateway states.
 #[derive(Debug)]
 pub enum PaymentGatewayStatus<S: InvoiceStorage> {
     /// The payment gateway is scanning for incoming payments.
     Running,
+    /// The payment gateway is scanning for incoming payments, but has fallen
+    /// behind the blockchain (e.g. after downtime) and is catching up by
+    /// scanning blocks in large batches. Invoices are only up to date as of
+    /// `scanned_height`.
+    CatchingUp {
+        /// Height of the newest block scanned so far.
+        scanned_height: u64,
+        /// Height of the newest block in the blockchain.
+        tip_height: u64,
+    },
     /// The payment gateway is not scanning for incoming payments.
     NotRunning,
     /// The payment gateway encountered an error while scanning for incoming
@@ -695,4 +1709,37 @@ mod tests {
             "http://example.com:18081/"
         );
     }
+
+    #[test]
+    fn seed_differs_per_account() {
+        // Setup.
+        init_logger();
+        let store = InMemory::new();
+
+        let payment_gateway: PaymentGateway<InMemory> = PaymentGatewayBuilder::<InMemory>::new(
+            PRIVATE_VIEW_KEY.to_string(),
+            PRIMARY_ADDRESS.to_string(),
+            store,
+        )
+        .account_index(1)
+        .accounts([2, 3])
+        .seed(1)
+        .build()
+        .unwrap();
+
+        // Each account picks its subaddresses in a different order.
+        let minor_indices: Vec<u32> = [1, 2, 3]
+            .into_iter()
+            .map(|major_index| {
+                payment_gateway
+                    .new_invoice_for_account(major_index, 1, 1, 10, String::new())
+                    .unwrap()
+                    .sub_index
+                    .minor
+            })
+            .collect();
+        assert_ne!(minor_indices[0], minor_indices[1]);
+        assert_ne!(minor_indices[0], minor_indices[2]);
+        assert_ne!(minor_indices[1], minor_indices[2]);
+    }
 }
@@ -1,4 +1,4 @@
-//! Subscribers should be used to receive invoice updates.
+//! Subscribers should be used to receive invoice updates and gateway events.
 
 /// Max size of subscriber backlog.
 const SUBSCRIPTION_BUFFER_LEN: usize = 2048;
@@ -25,17 +25,20 @@ use crate::{Invoice, InvoiceId};
 
 /// A means of receiving updates on a given invoice. Subscribers are returned by
 /// [`PaymentGateways`](crate::PaymentGateway) when subscribing to a invoice.
-pub struct Subscriber(Receiver<Invoice>);
+///
+/// A `Subscriber<GatewayEvent>` instead receives [`GatewayEvent`]s, as returned
+/// by [`PaymentGateway::subscribe_events`](crate::PaymentGateway::subscribe_events).
+pub struct Subscriber<T = Invoice>(Receiver<T>);
 
-impl Subscriber {
-    pub(crate) fn new(receiver: Receiver<Invoice>) -> Subscriber {
+impl<T> Subscriber<T> {
+    pub(crate) fn new(receiver: Receiver<T>) -> Subscriber<T> {
         Subscriber(receiver)
     }
 
     /// Waits for a invoice update from this subscriber.
     ///
     /// Returns `None` if the channel is closed.
-    pub async fn recv(&mut self) -> Option<Invoice> {
+    pub async fn recv(&mut self) -> Option<T> {
         self.0.recv().await
     }
 
@@ -46,7 +49,7 @@ impl Subscriber {
     /// # Panics
     ///
     /// This function panics if called within an asynchronous execution context.
-    pub fn blocking_recv(&mut self) -> Option<Invoice> {
+    pub fn blocking_recv(&mut self) -> Option<T> {
         self.0.blocking_recv()
     }
 
@@ -56,7 +59,7 @@ impl Subscriber {
     /// # Errors
     ///
     /// Returns an error if the channel is closed or if there is no update.
-    pub fn try_recv(&mut self) -> Result<Invoice, SubscriberError> {
+    pub fn try_recv(&mut self) -> Result<T, SubscriberError> {
         Ok(self.0.try_recv()?)
     }
 
@@ -67,16 +70,13 @@ impl Subscriber {
     /// # Errors
     ///
     /// Returns an error if no update is received in time.
-    pub async fn recv_timeout(
-        &mut self,
-        timeout: Duration,
-    ) -> Result<Option<Invoice>, SubscriberError> {
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<T>, SubscriberError> {
         Ok(tokio::time::timeout(timeout, self.0.recv()).await?)
     }
 }
 
-impl Future for Subscriber {
-    type Output = Option<Invoice>;
+impl<T> Future for Subscriber<T> {
+    type Output = Option<T>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         self.0.poll_recv(cx)
@@ -86,6 +86,8 @@ impl Future for Subscriber {
 pub(crate) struct Publisher {
     invoice_subs: Mutex<HashMap<InvoiceId, IndexMap<SenderId, Sender<Invoice>>>>,
     global_subs: Mutex<IndexMap<SenderId, Sender<Invoice>>>,
+    spendable_subscribers: Mutex<IndexMap<SenderId, Sender<Invoice>>>,
+    event_subscribers: Mutex<IndexMap<SenderId, Sender<GatewayEvent>>>,
 }
 
 impl Publisher {
@@ -93,6 +95,8 @@ impl Publisher {
         Publisher {
             invoice_subs: Mutex::new(HashMap::new()),
             global_subs: Mutex::new(IndexMap::new()),
+            spendable_subscribers: Mutex::new(IndexMap::new()),
+            event_subscribers: Mutex::new(IndexMap::new()),
         }
     }
 
@@ -118,6 +122,26 @@ impl Publisher {
         Subscriber::new(rx)
     }
 
+    pub fn subscribe_spendable(&self) -> Subscriber {
+        let (tx, rx) = channel(SUBSCRIPTION_BUFFER_LEN);
+        let mut spendable_subscribers = self
+            .spendable_subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        spendable_subscribers.insert(SenderId::new(), tx);
+        Subscriber::new(rx)
+    }
+
+    pub fn subscribe_events(&self) -> Subscriber<GatewayEvent> {
+        let (tx, rx) = channel(SUBSCRIPTION_BUFFER_LEN);
+        let mut event_subscribers = self
+            .event_subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        event_subscribers.insert(SenderId::new(), tx);
+        Subscriber::new(rx)
+    }
+
     pub fn insert_invoice(&self, invoice_id: InvoiceId) {
         let mut invoice_subs = self
             .invoice_subs
@@ -140,30 +164,45 @@ impl Publisher {
     }
 
     pub async fn send_updates(&self, invoice: &Invoice) {
+        self.send_to(Channel::Invoice(invoice.id()), invoice).await;
+        self.send_to(Channel::Global, invoice).await;
+    }
+
+    /// Notify subscribers that the given invoice just became spendable.
+    pub async fn send_spendable(&self, invoice: &Invoice) {
+        self.send_to(Channel::Spendable, invoice).await;
+    }
+
+    /// Notify event subscribers of something happening to the payment gateway.
+    pub async fn send_event(&self, event: &GatewayEvent) {
         let mut index = 0;
-        let mut sender_id;
-        let mut closed = false;
         loop {
-            match self.get_sender_by_index(Some(invoice.id()), index) {
-                Some((id, sender)) => {
-                    sender_id = id;
-                    if sender.send(invoice.clone()).await.is_err() {
-                        closed = true;
-                    }
-                }
-                None => break,
-            }
-            if closed {
-                self.remove_sender(Some(invoice.id()), sender_id);
+            let Some((sender_id, sender)) = self
+                .event_subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_index(index)
+                .map(|(id, s)| (*id, s.clone()))
+            else {
+                break;
+            };
+            if sender.send(event.clone()).await.is_err() {
+                self.event_subscribers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&sender_id);
             } else {
                 index += 1;
             }
         }
+    }
 
-        index = 0;
-        closed = false;
+    async fn send_to(&self, channel: Channel, invoice: &Invoice) {
+        let mut index = 0;
+        let mut sender_id;
+        let mut closed = false;
         loop {
-            match self.get_sender_by_index(None, index) {
+            match self.get_sender_by_index(channel, index) {
                 Some((id, sender)) => {
                     sender_id = id;
                     if sender.send(invoice.clone()).await.is_err() {
@@ -173,7 +212,8 @@ impl Publisher {
                 None => break,
             }
             if closed {
-                self.remove_sender(None, sender_id);
+                self.remove_sender(channel, sender_id);
+                closed = false;
             } else {
                 index += 1;
             }
@@ -182,53 +222,131 @@ impl Publisher {
 
     fn get_sender_by_index(
         &self,
-        invoice_id: Option<InvoiceId>,
+        channel: Channel,
         index: usize,
     ) -> Option<(SenderId, Sender<Invoice>)> {
-        if let Some(id) = invoice_id {
-            let mut invoice_subs = self
-                .invoice_subs
-                .lock()
-                .unwrap_or_else(PoisonError::into_inner);
+        match channel {
+            Channel::Invoice(id) => {
+                let mut invoice_subs = self
+                    .invoice_subs
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
 
-            invoice_subs
-                .get_mut(&id)
-                .and_then(|map| map.get_index(index))
-                .map(|(id, s)| (*id, s.clone()))
-        } else {
-            let global_subs = self
-                .global_subs
-                .lock()
-                .unwrap_or_else(PoisonError::into_inner);
+                invoice_subs
+                    .get_mut(&id)
+                    .and_then(|map| map.get_index(index))
+                    .map(|(id, s)| (*id, s.clone()))
+            }
+            Channel::Global | Channel::Spendable => {
+                let subs = self
+                    .subs_for(channel)
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
 
-            global_subs.get_index(index).map(|(id, s)| (*id, s.clone()))
+                subs.get_index(index).map(|(id, s)| (*id, s.clone()))
+            }
         }
     }
 
-    /// It's important that this function is only called within `send_updates`,
+    /// It's important that this function is only called within `send_to`,
     /// because changing the order of senders could cause some [`Subscriber`]s
     /// to miss updates if done at the wrong time.
-    fn remove_sender(&self, invoice_id: Option<InvoiceId>, sender_id: SenderId) {
-        if let Some(id) = invoice_id {
-            let mut invoice_subs = self
-                .invoice_subs
-                .lock()
-                .unwrap_or_else(PoisonError::into_inner);
-
-            invoice_subs
-                .get_mut(&id)
-                .and_then(|map| map.remove(&sender_id));
-        } else {
-            let mut global_subs = self
-                .global_subs
-                .lock()
-                .unwrap_or_else(PoisonError::into_inner);
+    fn remove_sender(&self, channel: Channel, sender_id: SenderId) {
+        match channel {
+            Channel::Invoice(id) => {
+                let mut invoice_subs = self
+                    .invoice_subs
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+
+                invoice_subs
+                    .get_mut(&id)
+                    .and_then(|map| map.remove(&sender_id));
+            }
+            Channel::Global | Channel::Spendable => {
+                let mut subs = self
+                    .subs_for(channel)
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+
+                subs.remove(&sender_id);
+            }
+        }
+    }
 
-            global_subs.remove(&sender_id);
+    /// Returns the subscribers of a channel which isn't specific to an invoice.
+    fn subs_for(&self, channel: Channel) -> &Mutex<IndexMap<SenderId, Sender<Invoice>>> {
+        match channel {
+            Channel::Spendable => &self.spendable_subscribers,
+            Channel::Invoice(_) | Channel::Global => &self.global_subs,
         }
     }
 }
 
+/// The set of subscribers an update is sent to.
+#[derive(Clone, Copy)]
+enum Channel {
+    /// Subscribers to a specific invoice.
+    Invoice(InvoiceId),
+    /// Subscribers to all invoices.
+    Global,
+    /// Subscribers to invoices becoming spendable.
+    Spendable,
+}
+
+/// Something happening to a payment gateway, outside of updates to individual
+/// invoices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GatewayEvent {
+    /// A blockchain reorg replaced blocks which had already been scanned.
+    /// Transfers from the replaced blocks were rolled back, and the new blocks
+    /// scanned in their place. Updates to the affected invoices are sent to
+    /// their subscribers before this event.
+    Reorg {
+        /// Height of the lowest block replaced.
+        fork_height: u64,
+        /// IDs of the replaced blocks as hex strings, from `fork_height`
+        /// upwards.
+        old_blocks: Vec<String>,
+        /// IDs of the blocks replacing them as hex strings, from `fork_height`
+        /// upwards.
+        new_blocks: Vec<String>,
+        /// Invoices which had transfers removed by the reorg, or moved to a
+        /// different block. Funds released against these transfers may not
+        /// have been received after all.
+        invoices: Vec<InvoiceId>,
+    },
+    /// Critical: a blockchain reorg replaced every block whose hash the payment
+    /// gateway tracks, so its fork point could not be found. Sent after the
+    /// corresponding [`GatewayEvent::Reorg`]. Transfers at or
+    /// above `rescanned_from` were rolled back and scanned again, but transfers
+    /// from orphaned blocks below it may still be counted towards invoices.
+    ///
+    /// Consider raising the
+    /// [`reorg_depth`](crate::PaymentGatewayBuilder::reorg_depth) if this
+    /// occurs.
+    ReorgBeyondWindow {
+        /// Lowest height scanned again.
+        rescanned_from: u64,
+        /// Number of block hashes tracked by the payment gateway.
+        reorg_depth: usize,
+    },
+    /// A billing period of a recurring invoice ended without being paid in
+    /// full. Sent after the final update to the invoice for that period, once
+    /// the invoice for the next period has been issued.
+    BillingPeriodUnpaid {
+        /// ID of the invoice for the unpaid billing period.
+        invoice_id: InvoiceId,
+        /// Index of the unpaid billing period, counting from 0.
+        period_index: u64,
+        /// Amount received during the billing period, in piconeros.
+        amount_paid: u64,
+        /// ID of the invoice for the next billing period.
+        successor: InvoiceId,
+    },
+}
+
 #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 struct SenderId(u128);
 
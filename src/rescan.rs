@@ -0,0 +1,306 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use log::debug;
+use monero::{
+    cryptonote::{onetime_key::SubKeyChecker, subaddress},
+    ViewPair,
+};
+use tokio::task;
+
+use crate::{
+    caching::CachedBlock,
+    invoice::Transfer,
+    rpc::RpcClient,
+    scanner::{add_transfers, block_transfers, OutputChecker},
+    AcceptXmrError, Invoice, InvoiceId, SubIndex,
+};
+
+/// Number of blocks downloaded and scanned at once during a rescan.
+const RESCAN_BATCH_SIZE: u64 = 100;
+
+/// What to look for when rescanning the blockchain with
+/// [`PaymentGateway::rescan`](crate::PaymentGateway::rescan).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RescanTarget {
+    /// Transfers to a single invoice.
+    Invoice(InvoiceId),
+    /// Transfers to every invoice using a subaddress, including each billing
+    /// period of a recurring invoice.
+    Subaddress(SubIndex),
+}
+
+impl RescanTarget {
+    /// Returns the subaddress index to scan for.
+    pub(crate) fn sub_index(self) -> SubIndex {
+        match self {
+            RescanTarget::Invoice(invoice_id) => invoice_id.sub_index,
+            RescanTarget::Subaddress(sub_index) => sub_index,
+        }
+    }
+
+    /// Returns `true` if the target covers the invoice with the given ID.
+    pub(crate) fn covers(self, invoice_id: InvoiceId) -> bool {
+        match self {
+            RescanTarget::Invoice(id) => id == invoice_id,
+            RescanTarget::Subaddress(sub_index) => sub_index == invoice_id.sub_index,
+        }
+    }
+}
+
+/// The outcome of a [`PaymentGateway::rescan`](crate::PaymentGateway::rescan).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RescanReport {
+    heights: Option<RangeInclusive<u64>>,
+    invoices: Vec<RescannedInvoice>,
+}
+
+impl RescanReport {
+    pub(crate) fn new(
+        heights: Option<RangeInclusive<u64>>,
+        invoices: Vec<RescannedInvoice>,
+    ) -> RescanReport {
+        RescanReport { heights, invoices }
+    }
+
+    /// Returns the heights of the blocks rescanned, or `None` if no blocks
+    /// needed rescanning.
+    #[must_use]
+    pub fn heights(&self) -> Option<&RangeInclusive<u64>> {
+        self.heights.as_ref()
+    }
+
+    /// Returns the invoices whose transfers were changed by the rescan.
+    #[must_use]
+    pub fn invoices(&self) -> &[RescannedInvoice] {
+        &self.invoices
+    }
+}
+
+/// An invoice whose transfers were changed by a rescan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RescannedInvoice {
+    invoice: Invoice,
+    added: Vec<Transfer>,
+    removed: Vec<Transfer>,
+}
+
+impl RescannedInvoice {
+    /// Returns the invoice, as updated by the rescan.
+    #[must_use]
+    pub fn invoice(&self) -> &Invoice {
+        &self.invoice
+    }
+
+    /// Returns the transfers found by the rescan which the invoice was
+    /// missing.
+    #[must_use]
+    pub fn added(&self) -> &[Transfer] {
+        &self.added
+    }
+
+    /// Returns the transfers the invoice had in the rescanned blocks, which
+    /// the rescan didn't find.
+    #[must_use]
+    pub fn removed(&self) -> &[Transfer] {
+        &self.removed
+    }
+}
+
+/// Download the blocks at `heights` and scan them for transfers to the
+/// subaddresses at `sub_indices`.
+///
+/// Blocks are downloaded and scanned in batches, and each batch is checked for
+/// owned outputs on a blocking thread, away from the async runtime.
+pub(crate) async fn scan<E: Send + 'static>(
+    rpc_client: &RpcClient,
+    viewpair: ViewPair,
+    sub_indices: &[SubIndex],
+    heights: RangeInclusive<u64>,
+) -> Result<Vec<(SubIndex, Transfer)>, AcceptXmrError<E>> {
+    let mut transfers = Vec::new();
+    let mut start = *heights.start();
+    while start <= *heights.end() {
+        let end = (start + RESCAN_BATCH_SIZE - 1).min(*heights.end());
+        debug!("Rescanning blocks {start} to {end}");
+        let blocks: Vec<CachedBlock> = rpc_client
+            .blocks_with_transactions(start..=end)
+            .await?
+            .into_iter()
+            .zip(start..=end)
+            .map(|((id, block, txs), height)| CachedBlock::new(id, height, block, txs))
+            .collect();
+
+        let sub_indices = sub_indices.to_vec();
+        let batch_transfers = task::spawn_blocking(move || {
+            let sub_key_checker = sub_key_checker_for_indices(&viewpair, &sub_indices);
+            let output_checker = OutputChecker::new(1)?;
+            let mut transfers = Vec::new();
+            for block in &blocks {
+                transfers.extend(block_transfers::<E>(
+                    block,
+                    &output_checker,
+                    &sub_key_checker,
+                )?);
+            }
+            Ok::<_, AcceptXmrError<E>>(transfers)
+        })
+        .await
+        .map_err(|_| AcceptXmrError::ScanningThreadPanic)??;
+        transfers.extend(batch_transfers);
+
+        start = end + 1;
+    }
+    Ok(transfers)
+}
+
+/// Reconcile an invoice with the `transfers` found by rescanning the blocks at
+/// `heights`, returning the updated invoice if its transfers or dust changed.
+///
+/// The invoice's transfers from the rescanned blocks are replaced by those
+/// found. Transfers from other blocks and from the txpool are kept as they are.
+pub(crate) fn reconciled_invoice(
+    old_invoice: &Invoice,
+    transfers: &[(SubIndex, Transfer)],
+    heights: &RangeInclusive<u64>,
+    dust_threshold: u64,
+) -> Option<RescannedInvoice> {
+    let rescanned = |transfer: &Transfer| {
+        transfer
+            .height
+            .map_or(false, |height| heights.contains(&height))
+    };
+    let mut invoice = old_invoice.clone();
+    invoice.transfers.retain(|transfer| !rescanned(transfer));
+    invoice.dust.retain(|transfer| !rescanned(transfer));
+    add_transfers(&mut invoice, transfers, dust_threshold);
+
+    let old_transfers: Vec<Transfer> = old_invoice
+        .transfers
+        .iter()
+        .copied()
+        .filter(rescanned)
+        .collect();
+    let new_transfers: Vec<Transfer> = invoice
+        .transfers
+        .iter()
+        .copied()
+        .filter(rescanned)
+        .collect();
+    let added = missing_from(&new_transfers, &old_transfers);
+    let removed = missing_from(&old_transfers, &new_transfers);
+    let dust_changed = {
+        let mut dust = invoice.dust.clone();
+        let mut old_dust = old_invoice.dust.clone();
+        dust.sort_unstable();
+        old_dust.sort_unstable();
+        dust != old_dust
+    };
+    if added.is_empty() && removed.is_empty() && !dust_changed {
+        return None;
+    }
+
+    invoice.recalculate_paid();
+    Some(RescannedInvoice {
+        invoice,
+        added,
+        removed,
+    })
+}
+
+/// Returns the transfers in `transfers` which aren't in `other`, counting
+/// repeated transfers separately.
+fn missing_from(transfers: &[Transfer], other: &[Transfer]) -> Vec<Transfer> {
+    let mut other = other.to_vec();
+    transfers
+        .iter()
+        .filter(|transfer| match other.iter().position(|t| t == *transfer) {
+            Some(i) => {
+                other.swap_remove(i);
+                false
+            }
+            None => true,
+        })
+        .copied()
+        .collect()
+}
+
+/// Creates a [`SubKeyChecker`] covering only the given subaddresses.
+fn sub_key_checker_for_indices<'a>(
+    viewpair: &'a ViewPair,
+    sub_indices: &[SubIndex],
+) -> SubKeyChecker<'a> {
+    let table: HashMap<_, _> = sub_indices
+        .iter()
+        .map(|&sub_index| {
+            let index = subaddress::Index::from(sub_index);
+            (subaddress::get_spend_public_key(viewpair, index), index)
+        })
+        .collect();
+    SubKeyChecker {
+        table,
+        keys: viewpair,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::reconciled_invoice;
+    use crate::{invoice::Transfer, Invoice, SubIndex};
+
+    fn invoice(transfers: Vec<Transfer>) -> Invoice {
+        let mut invoice = Invoice::new(
+            "testAddress".to_string(),
+            SubIndex::new(0, 1),
+            2477657,
+            10,
+            1,
+            10,
+            "test_description".to_string(),
+        );
+        invoice.transfers = transfers;
+        invoice.recalculate_paid();
+        invoice
+    }
+
+    #[test]
+    fn reconcile_rescanned_blocks() {
+        let kept = Transfer::new(1, Some(2477657));
+        let missed = Transfer::new(2, Some(2477660));
+        let false_transfer = Transfer::new(3, Some(2477661));
+        let txpool = Transfer::new(4, None);
+        let old_invoice = invoice(vec![kept, false_transfer, txpool]);
+
+        // The rescan of blocks 2477659 to 2477662 finds a missed transfer, but not
+        // the transfer the invoice already had from those blocks.
+        let transfers = [(SubIndex::new(0, 1), missed)];
+        let rescanned =
+            reconciled_invoice(&old_invoice, &transfers, &(2477659..=2477662), 0).unwrap();
+        assert_eq!(rescanned.added(), [missed]);
+        assert_eq!(rescanned.removed(), [false_transfer]);
+        assert_eq!(rescanned.invoice().transfers(), [kept, missed, txpool]);
+        assert_eq!(rescanned.invoice().amount_paid(), 7);
+
+        // Rescanning again changes nothing.
+        assert_eq!(
+            reconciled_invoice(rescanned.invoice(), &transfers, &(2477659..=2477662), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn ignore_other_subaddresses_and_blocks() {
+        let old_invoice = invoice(Vec::new());
+
+        let transfers = [
+            // Another subaddress.
+            (SubIndex::new(0, 2), Transfer::new(1, Some(2477660))),
+            // Before the invoice was created.
+            (SubIndex::new(0, 1), Transfer::new(1, Some(2477655))),
+        ];
+        assert_eq!(
+            reconciled_invoice(&old_invoice, &transfers, &(2477650..=2477662), 0),
+            None
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Encoding and decoding of monero's epee portable storage format, used by the
+//! daemon's binary (`.bin`) RPC endpoints.
+
+use indexmap::IndexMap;
+use thiserror::Error;
+
+/// Portable storage signature, followed by the format version.
+const HEADER: [u8; 9] = [0x01, 0x11, 0x01, 0x01, 0x01, 0x01, 0x02, 0x01, 0x01];
+/// Sections can't be nested deeper than this, to protect against malicious
+/// responses.
+const MAX_DEPTH: usize = 100;
+
+const TYPE_INT64: u8 = 1;
+const TYPE_INT32: u8 = 2;
+const TYPE_INT16: u8 = 3;
+const TYPE_INT8: u8 = 4;
+const TYPE_UINT64: u8 = 5;
+const TYPE_UINT32: u8 = 6;
+const TYPE_UINT16: u8 = 7;
+const TYPE_UINT8: u8 = 8;
+const TYPE_DOUBLE: u8 = 9;
+const TYPE_STRING: u8 = 10;
+const TYPE_BOOL: u8 = 11;
+const TYPE_OBJECT: u8 = 12;
+const TYPE_ARRAY: u8 = 13;
+const FLAG_ARRAY: u8 = 0x80;
+
+/// A named collection of values. Keys keep the order they were inserted in.
+pub(crate) type Section = IndexMap<String, Value>;
+
+/// A value in a portable storage [`Section`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Int64(i64),
+    Int32(i32),
+    Int16(i16),
+    Int8(i8),
+    Uint64(u64),
+    Uint32(u32),
+    Uint16(u16),
+    Uint8(u8),
+    Double(f64),
+    /// Strings are arbitrary bytes, and often hold binary blobs.
+    String(Vec<u8>),
+    Bool(bool),
+    Object(Section),
+    /// Every element of an array must be of the same type.
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Returns the value as a `u64` if it is an unsigned integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Uint64(v) => Some(*v),
+            Value::Uint32(v) => Some(u64::from(*v)),
+            Value::Uint16(v) => Some(u64::from(*v)),
+            Value::Uint8(v) => Some(u64::from(*v)),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&Section> {
+        match self {
+            Value::Object(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn type_code(&self) -> u8 {
+        match self {
+            Value::Int64(_) => TYPE_INT64,
+            Value::Int32(_) => TYPE_INT32,
+            Value::Int16(_) => TYPE_INT16,
+            Value::Int8(_) => TYPE_INT8,
+            Value::Uint64(_) => TYPE_UINT64,
+            Value::Uint32(_) => TYPE_UINT32,
+            Value::Uint16(_) => TYPE_UINT16,
+            Value::Uint8(_) => TYPE_UINT8,
+            Value::Double(_) => TYPE_DOUBLE,
+            Value::String(_) => TYPE_STRING,
+            Value::Bool(_) => TYPE_BOOL,
+            Value::Object(_) => TYPE_OBJECT,
+            Value::Array(_) => TYPE_ARRAY,
+        }
+    }
+}
+
+/// Serialize a section, including the portable storage header.
+pub(crate) fn to_bytes(section: &Section) -> Vec<u8> {
+    let mut bytes = HEADER.to_vec();
+    write_section(&mut bytes, section);
+    bytes
+}
+
+/// Deserialize a section, including the portable storage header.
+pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Section, EpeeError> {
+    let mut reader = Reader { bytes, depth: 0 };
+    if reader.take(HEADER.len())? != HEADER {
+        return Err(EpeeError::Header);
+    }
+    let section = reader.section()?;
+    if !reader.bytes.is_empty() {
+        return Err(EpeeError::TrailingBytes(reader.bytes.len()));
+    }
+    Ok(section)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_varint(bytes: &mut Vec<u8>, value: usize) {
+    // The two lowest bits hold the number of bytes used.
+    let value = value as u64;
+    if value < 1 << 6 {
+        bytes.push((value as u8) << 2);
+    } else if value < 1 << 14 {
+        bytes.extend_from_slice(&((value as u16) << 2 | 1).to_le_bytes());
+    } else if value < 1 << 30 {
+        bytes.extend_from_slice(&((value as u32) << 2 | 2).to_le_bytes());
+    } else {
+        bytes.extend_from_slice(&(value << 2 | 3).to_le_bytes());
+    }
+}
+
+fn write_section(bytes: &mut Vec<u8>, section: &Section) {
+    // Like the daemon, leave out empty arrays, since their type is unknown.
+    let entries: Vec<_> = section
+        .iter()
+        .filter(|(_, value)| !matches!(value, Value::Array(a) if a.is_empty()))
+        .collect();
+    write_varint(bytes, entries.len());
+    for (name, value) in entries {
+        // Names are limited to 255 bytes.
+        let name_len = u8::try_from(name.len()).unwrap_or(u8::MAX);
+        bytes.push(name_len);
+        bytes.extend_from_slice(&name.as_bytes()[..usize::from(name_len)]);
+        write_entry(bytes, value);
+    }
+}
+
+/// Write a value, preceded by its type.
+fn write_entry(bytes: &mut Vec<u8>, value: &Value) {
+    if let Value::Array(elements) = value {
+        let element_type = elements.first().map_or(TYPE_UINT8, Value::type_code);
+        bytes.push(element_type | FLAG_ARRAY);
+        write_varint(bytes, elements.len());
+        for element in elements {
+            write_value(bytes, element);
+        }
+    } else {
+        bytes.push(value.type_code());
+        write_value(bytes, value);
+    }
+}
+
+fn write_value(bytes: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int64(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Int32(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Int16(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Int8(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Uint64(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Uint32(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Uint16(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::Uint8(v) => bytes.push(*v),
+        Value::Double(v) => bytes.extend_from_slice(&v.to_le_bytes()),
+        Value::String(v) => {
+            write_varint(bytes, v.len());
+            bytes.extend_from_slice(v);
+        }
+        Value::Bool(v) => bytes.push(u8::from(*v)),
+        Value::Object(v) => write_section(bytes, v),
+        // Nested arrays carry their own type.
+        Value::Array(_) => write_entry(bytes, value),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EpeeError> {
+        if len > self.bytes.len() {
+            return Err(EpeeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], EpeeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn varint(&mut self) -> Result<usize, EpeeError> {
+        let first = *self.take(1)?.first().ok_or(EpeeError::UnexpectedEnd)?;
+        let mut raw = [0; 8];
+        let len = 1 << (first & 0b11);
+        raw[0] = first;
+        raw[1..len].copy_from_slice(self.take(len - 1)?);
+        usize::try_from(u64::from_le_bytes(raw) >> 2).map_err(|_| EpeeError::Overflow)
+    }
+
+    fn descend(&mut self) -> Result<(), EpeeError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(EpeeError::Depth);
+        }
+        Ok(())
+    }
+
+    fn section(&mut self) -> Result<Section, EpeeError> {
+        self.descend()?;
+        let count = self.varint()?;
+        let mut section = Section::new();
+        for _ in 0..count {
+            let name_len = self.array::<1>()?[0];
+            let name = String::from_utf8(self.take(usize::from(name_len))?.to_vec())
+                .map_err(|_| EpeeError::Name)?;
+            let value = self.entry()?;
+            section.insert(name, value);
+        }
+        self.depth -= 1;
+        Ok(section)
+    }
+
+    /// Read a value, preceded by its type.
+    fn entry(&mut self) -> Result<Value, EpeeError> {
+        let type_code = self.array::<1>()?[0];
+        if type_code & FLAG_ARRAY == 0 {
+            return self.value(type_code);
+        }
+        self.descend()?;
+        let element_type = type_code & !FLAG_ARRAY;
+        let count = self.varint()?;
+        // Don't trust the count for preallocation; every element takes at least one
+        // byte.
+        let mut elements = Vec::with_capacity(count.min(self.bytes.len()));
+        for _ in 0..count {
+            elements.push(self.value(element_type)?);
+        }
+        self.depth -= 1;
+        Ok(Value::Array(elements))
+    }
+
+    fn value(&mut self, type_code: u8) -> Result<Value, EpeeError> {
+        Ok(match type_code {
+            TYPE_INT64 => Value::Int64(i64::from_le_bytes(self.array()?)),
+            TYPE_INT32 => Value::Int32(i32::from_le_bytes(self.array()?)),
+            TYPE_INT16 => Value::Int16(i16::from_le_bytes(self.array()?)),
+            TYPE_INT8 => Value::Int8(i8::from_le_bytes(self.array()?)),
+            TYPE_UINT64 => Value::Uint64(u64::from_le_bytes(self.array()?)),
+            TYPE_UINT32 => Value::Uint32(u32::from_le_bytes(self.array()?)),
+            TYPE_UINT16 => Value::Uint16(u16::from_le_bytes(self.array()?)),
+            TYPE_UINT8 => Value::Uint8(self.array::<1>()?[0]),
+            TYPE_DOUBLE => Value::Double(f64::from_le_bytes(self.array()?)),
+            TYPE_STRING => {
+                let len = self.varint()?;
+                Value::String(self.take(len)?.to_vec())
+            }
+            TYPE_BOOL => Value::Bool(self.array::<1>()?[0] != 0),
+            TYPE_OBJECT => Value::Object(self.section()?),
+            // Nested arrays carry their own type.
+            TYPE_ARRAY => self.entry()?,
+            _ => return Err(EpeeError::Type(type_code)),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EpeeError {
+    #[error("portable storage header is missing or unsupported")]
+    Header,
+    #[error("unexpected end of data")]
+    UnexpectedEnd,
+    #[error("{0} unexpected bytes after the end of the data")]
+    TrailingBytes(usize),
+    #[error("unknown type {0}")]
+    Type(u8),
+    #[error("field name is not valid UTF-8")]
+    Name,
+    #[error("sections or arrays nested more than {MAX_DEPTH} deep")]
+    Depth,
+    #[error("length does not fit in memory")]
+    Overflow,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{from_bytes, to_bytes, EpeeError, Section, Value};
+
+    #[test]
+    fn round_trip() {
+        let mut inner = Section::new();
+        inner.insert("blob".to_string(), Value::String(vec![0xab; 300]));
+        inner.insert("flag".to_string(), Value::Bool(true));
+        let mut section = Section::new();
+        section.insert("int".to_string(), Value::Int32(-7));
+        section.insert("big".to_string(), Value::Uint64(u64::MAX));
+        section.insert("double".to_string(), Value::Double(0.5));
+        section.insert(
+            "heights".to_string(),
+            Value::Array((0..70_000).map(Value::Uint64).collect()),
+        );
+        section.insert("object".to_string(), Value::Object(inner.clone()));
+        section.insert(
+            "objects".to_string(),
+            Value::Array(vec![Value::Object(inner.clone()), Value::Object(inner)]),
+        );
+        section.insert(
+            "nested".to_string(),
+            Value::Array(vec![Value::Array(vec![Value::Uint8(1), Value::Uint8(2)])]),
+        );
+
+        assert_eq!(from_bytes(&to_bytes(&section)).unwrap(), section);
+    }
+
+    #[test]
+    fn encodes_like_the_daemon() {
+        let mut section = Section::new();
+        section.insert("heights".to_string(), Value::Array(vec![Value::Uint64(5)]));
+        section.insert("empty".to_string(), Value::Array(Vec::new()));
+
+        assert_eq!(
+            hex::encode(to_bytes(&section)),
+            "011101010101020101\
+             04\
+             0768656967687473\
+             8504\
+             0500000000000000"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let bytes = to_bytes(&Section::new());
+        assert!(matches!(from_bytes(&bytes[1..]), Err(EpeeError::Header)));
+        assert!(matches!(
+            from_bytes(&[&bytes[..], &[0]].concat()),
+            Err(EpeeError::TrailingBytes(1))
+        ));
+
+        let mut section = Section::new();
+        section.insert("blob".to_string(), Value::String(vec![0; 10]));
+        let bytes = to_bytes(&section);
+        assert!(matches!(
+            from_bytes(&bytes[..bytes.len() - 1]),
+            Err(EpeeError::UnexpectedEnd)
+        ));
+
+        // Sections nested inside each other without end.
+        let mut bytes = bytes[..9].to_vec();
+        for _ in 0..200 {
+            bytes.extend_from_slice(&[0x04, 0x01, b'a', 0x0c]);
+        }
+        assert!(matches!(from_bytes(&bytes), Err(EpeeError::Depth)));
+    }
+}
@@ -1,13 +1,21 @@
 mod authentication;
+mod epee;
 
 use std::{
     any,
     collections::HashSet,
-    sync::{Arc, Mutex, PoisonError},
+    future::{poll_fn, Future},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    task::Poll,
     time::Duration,
 };
 
 use authentication::{AuthError, AuthInfo};
+use epee::{EpeeError, Section, Value};
 use http::StatusCode;
 use hyper::{
     body,
@@ -31,6 +39,11 @@ pub(crate) struct RpcClient {
     url: Uri,
     timeout: Duration,
     auth_info: Arc<Mutex<Option<AuthInfo>>>,
+    /// Whether the daemon's binary endpoints are usable. Cleared when the
+    /// daemon rejects them, after which only JSON endpoints are used.
+    binary: Arc<AtomicBool>,
+    /// Maximum number of blocks or batches of transactions requested at once.
+    concurrency: usize,
 }
 
 impl RpcClient {
@@ -42,6 +55,7 @@ impl RpcClient {
         username: Option<String>,
         password: Option<String>,
         seed: Option<u64>,
+        concurrency: usize,
     ) -> RpcClient {
         let mut hyper_connector = HttpConnector::new();
         hyper_connector.set_connect_timeout(Some(connection_timeout));
@@ -68,6 +82,8 @@ impl RpcClient {
             url,
             timeout: total_timeout,
             auth_info,
+            binary: Arc::new(AtomicBool::new(true)),
+            concurrency,
         }
     }
 
@@ -100,6 +116,44 @@ impl RpcClient {
         Ok((block_hash, block))
     }
 
+    /// Get the IDs of the blocks at the given heights, in order of height,
+    /// without fetching the blocks themselves.
+    pub async fn block_ids(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> Result<Vec<monero::Hash>, RpcError> {
+        trace!(
+            "Requesting block IDs {} to {}",
+            heights.start(),
+            heights.end()
+        );
+        let request_body = json!({
+            "jsonrpc": "2.0",
+            "id": "0",
+            "method": "get_block_headers_range",
+            "params": {"start_height": heights.start(), "end_height": heights.end()}
+        })
+        .to_string();
+        let request_endpoint = "json_rpc";
+
+        let res = self.request(&request_body, request_endpoint).await?;
+
+        let headers = res["result"]["headers"].as_array().ok_or_else(|| {
+            RpcError::MissingData("{{ result: {{ headers: [...] }} }}".to_string())
+        })?;
+        headers
+            .iter()
+            .map(|header| {
+                let block_id_str = header["hash"].as_str().ok_or_else(|| {
+                    RpcError::MissingData(
+                        "{{ result: {{ headers: [ {{ hash: \"...\" }} ] }} }}".to_string(),
+                    )
+                })?;
+                Ok(monero::Hash::from_slice(&hex::decode(block_id_str)?))
+            })
+            .collect()
+    }
+
     pub async fn block_transactions(
         &self,
         block: &monero::Block,
@@ -109,6 +163,146 @@ impl RpcClient {
         self.transactions_by_hashes(transaction_hashes).await
     }
 
+    /// Get the block at the given height along with its transactions.
+    pub async fn block_with_transactions(
+        &self,
+        height: u64,
+    ) -> Result<(monero::Hash, monero::Block, Vec<monero::Transaction>), RpcError> {
+        if self.binary.load(Ordering::Relaxed) {
+            match self.blocks_by_height_bin(&[height]).await {
+                Ok(mut blocks) => {
+                    return blocks
+                        .pop()
+                        .ok_or_else(|| RpcError::MissingData("{{ blocks: [...] }}".to_string()))
+                }
+                Err(e) => self.disable_binary_or(e)?,
+            }
+        }
+        let (block_id, block) = self.block(height).await?;
+        let transactions = self.block_transactions(&block).await?;
+        Ok((block_id, block, transactions))
+    }
+
+    /// Get the blocks at the given heights along with their transactions. Blocks
+    /// are returned in order of height.
+    ///
+    /// Uses the daemon's binary `get_blocks.bin` endpoint where possible, which
+    /// returns all blocks and transactions in one response. Otherwise, blocks
+    /// are requested through JSON endpoints, up to `concurrency` at once.
+    pub async fn blocks_with_transactions(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> Result<Vec<(monero::Hash, monero::Block, Vec<monero::Transaction>)>, RpcError> {
+        trace!("Requesting blocks {} to {}", heights.start(), heights.end());
+        if self.binary.load(Ordering::Relaxed) {
+            match self.blocks_bin(heights.clone()).await {
+                Ok(blocks) => return Ok(blocks),
+                Err(e) => self.disable_binary_or(e)?,
+            }
+        }
+        let requests = heights
+            .map(|height| async move {
+                let (block_id, block) = self.block(height).await?;
+                let transactions = self.block_transactions(&block).await?;
+                Ok((block_id, block, transactions))
+            })
+            .collect();
+        join_bounded(requests, self.concurrency).await
+    }
+
+    /// Get a range of blocks and their transactions from `get_blocks.bin`. The
+    /// daemon may return fewer blocks than requested, so this requests the
+    /// remainder until the whole range has been received.
+    async fn blocks_bin(
+        &self,
+        heights: RangeInclusive<u64>,
+    ) -> Result<Vec<(monero::Hash, monero::Block, Vec<monero::Transaction>)>, RpcError> {
+        // The daemon needs a list of known block IDs ending in the genesis block
+        // when starting from height 0, so request that one by height instead.
+        if *heights.start() == 0 {
+            return self
+                .blocks_by_height_bin(&heights.collect::<Vec<u64>>())
+                .await;
+        }
+
+        let mut blocks = Vec::new();
+        let mut start_height = *heights.start();
+        while start_height <= *heights.end() {
+            let mut request = Section::new();
+            request.insert("start_height".to_string(), Value::Uint64(start_height));
+            request.insert("prune".to_string(), Value::Bool(false));
+            request.insert("no_miner_tx".to_string(), Value::Bool(true));
+            // Daemons older than v0.18.3 ignore this, and may return more blocks.
+            request.insert(
+                "max_block_count".to_string(),
+                Value::Uint64(heights.end() - start_height + 1),
+            );
+            let response = self.request_bin(&request, "get_blocks.bin").await?;
+
+            let response_start = response.get("start_height").and_then(Value::as_u64);
+            if response_start != Some(start_height) {
+                return Err(RpcError::MissingData(format!(
+                    "{{ start_height: {start_height} }}"
+                )));
+            }
+            let mut new_blocks = block_entries(&response)?;
+            if new_blocks.is_empty() {
+                return Err(RpcError::MissingData("{{ blocks: [...] }}".to_string()));
+            }
+            new_blocks
+                .truncate(usize::try_from(heights.end() - start_height + 1).unwrap_or(usize::MAX));
+            start_height += new_blocks.len() as u64;
+            blocks.append(&mut new_blocks);
+        }
+        trace!("Received {} blocks", blocks.len());
+        Ok(blocks)
+    }
+
+    /// Get the blocks at the given heights and their transactions from
+    /// `get_blocks_by_height.bin`.
+    async fn blocks_by_height_bin(
+        &self,
+        heights: &[u64],
+    ) -> Result<Vec<(monero::Hash, monero::Block, Vec<monero::Transaction>)>, RpcError> {
+        let mut request = Section::new();
+        request.insert(
+            "heights".to_string(),
+            Value::Array(heights.iter().copied().map(Value::Uint64).collect()),
+        );
+        let response = self
+            .request_bin(&request, "get_blocks_by_height.bin")
+            .await?;
+
+        let blocks = block_entries(&response)?;
+        if blocks.len() != heights.len() {
+            return Err(RpcError::MissingData(format!(
+                "{{ blocks: [...] }} with {} blocks",
+                heights.len()
+            )));
+        }
+        Ok(blocks)
+    }
+
+    /// Stop using binary endpoints if the error shows that the daemon refuses
+    /// them, i.e. they are missing or forbidden, or the daemon's RPC is
+    /// restricted. Otherwise (e.g. if the daemon is busy), return the error and
+    /// keep using binary endpoints.
+    fn disable_binary_or(&self, error: RpcError) -> Result<(), RpcError> {
+        let refused = match &error {
+            RpcError::HttpStatus(status) => {
+                *status == StatusCode::FORBIDDEN || *status == StatusCode::NOT_FOUND
+            }
+            RpcError::DaemonStatus(status) => status.to_lowercase().contains("restricted"),
+            _ => false,
+        };
+        if !refused {
+            return Err(error);
+        }
+        warn!("Daemon refused binary RPC request, falling back to JSON RPC: {error}");
+        self.binary.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub async fn txpool(&self) -> Result<Vec<monero::Transaction>, RpcError> {
         trace!("Requesting txpool");
         let mut transactions = Vec::new();
@@ -156,62 +350,61 @@ impl RpcClient {
         Ok(transactions)
     }
 
+    /// Get the transactions with the given hashes, in the same order. The
+    /// transactions are requested in batches of up to 100 (the restricted RPC
+    /// maximum), with up to `concurrency` batches requested at once.
     pub async fn transactions_by_hashes(
         &self,
         hashes: &[monero::Hash],
     ) -> Result<Vec<monero::Transaction>, RpcError> {
-        let mut transactions = Vec::new();
-        for i in 0..=hashes.len() / MAX_REQUESTED_TRANSACTIONS {
-            // We've gotta grab these in parts to avoid putting too much load on the RPC
-            // server, so these are the start and end indexes of the hashes
-            // we're grabbing for now.
-            //
-            // TODO: Get them concurrently.
-            let starting_index: usize = i * MAX_REQUESTED_TRANSACTIONS;
-            let ending_index: usize =
-                std::cmp::min(MAX_REQUESTED_TRANSACTIONS * (i + 1), hashes.len());
-
-            // If requesting an empty list, return what we have now.
-            if ending_index == starting_index {
-                return Ok(transactions);
-            }
+        let requests = hashes
+            .chunks(MAX_REQUESTED_TRANSACTIONS)
+            .map(|chunk| self.transactions_batch(chunk))
+            .collect();
+        let batches = join_bounded(requests, self.concurrency).await?;
+        Ok(batches.into_iter().flatten().collect())
+    }
 
-            // Build a json containing the hashes of the transactions we want.
-            trace!("Requesting {} transactions", ending_index - starting_index);
-            let request_body = r#"{"txs_hashes":"#.to_owned()
-                + &json!(hashes[starting_index..ending_index]
-                    .iter()
-                    .map(|x| hex::encode(x.as_bytes())) // Convert from monero::Hash to hex.
-                    .collect::<Vec<String>>())
-                .to_string()
-                + "}";
-            let request_endpoint = "get_transactions";
-
-            let res = self.request(&request_body, request_endpoint).await?;
-
-            let hexes = res["txs_as_hex"]
-                .as_array()
-                .ok_or_else(|| RpcError::MissingData("{{ txs_as_hex: [...] }}".to_string()))?;
-            if ending_index - starting_index == hexes.len() {
-                trace!("Received {} transactions", hexes.len());
-            } else {
-                warn!(
-                    "Received {} transactions, requested {}",
-                    hexes.len(),
-                    ending_index - starting_index
-                );
-            }
+    /// Get a batch of transactions from the daemon.
+    async fn transactions_batch(
+        &self,
+        hashes: &[monero::Hash],
+    ) -> Result<Vec<monero::Transaction>, RpcError> {
+        // Build a json containing the hashes of the transactions we want.
+        trace!("Requesting {} transactions", hashes.len());
+        let request_body = r#"{"txs_hashes":"#.to_owned()
+            + &json!(hashes
+                .iter()
+                .map(|x| hex::encode(x.as_bytes())) // Convert from monero::Hash to hex.
+                .collect::<Vec<String>>())
+            .to_string()
+            + "}";
+        let request_endpoint = "get_transactions";
+
+        let res = self.request(&request_body, request_endpoint).await?;
+
+        let hexes = res["txs_as_hex"]
+            .as_array()
+            .ok_or_else(|| RpcError::MissingData("{{ txs_as_hex: [...] }}".to_string()))?;
+        if hashes.len() == hexes.len() {
+            trace!("Received {} transactions", hexes.len());
+        } else {
+            warn!(
+                "Received {} transactions, requested {}",
+                hexes.len(),
+                hashes.len()
+            );
+        }
 
-            // Add these transactions to the total list.
-            for tx_json in hexes {
-                let tx_str = tx_json.as_str().ok_or(RpcError::DataType {
-                    found: tx_json.clone(),
-                    expected: "&str",
-                })?;
-                let tx_hex = hex::decode(tx_str)?;
-                let tx: monero::Transaction = deserialize(&tx_hex)?;
-                transactions.push(tx);
-            }
+        let mut transactions = Vec::with_capacity(hexes.len());
+        for tx_json in hexes {
+            let tx_str = tx_json.as_str().ok_or(RpcError::DataType {
+                found: tx_json.clone(),
+                expected: "&str",
+            })?;
+            let tx_hex = hex::decode(tx_str)?;
+            let tx: monero::Transaction = deserialize(&tx_hex)?;
+            transactions.push(tx);
         }
         Ok(transactions)
     }
@@ -230,10 +423,38 @@ impl RpcClient {
     }
 
     async fn request(&self, body: &str, endpoint: &str) -> Result<serde_json::Value, RpcError> {
+        let (_status, full_body) = self.send(body.as_bytes(), endpoint).await?;
+
+        Ok(serde_json::from_slice(&full_body)?)
+    }
+
+    /// Send a request to one of the daemon's binary endpoints.
+    async fn request_bin(&self, request: &Section, endpoint: &str) -> Result<Section, RpcError> {
+        let (status, full_body) = self.send(&epee::to_bytes(request), endpoint).await?;
+        // Restricted daemons may refuse binary requests.
+        if !status.is_success() {
+            return Err(RpcError::HttpStatus(status));
+        }
+
+        let response = epee::from_bytes(&full_body)?;
+        match response.get("status").and_then(Value::as_bytes) {
+            Some(b"OK") => Ok(response),
+            Some(status) => Err(RpcError::DaemonStatus(
+                String::from_utf8_lossy(status).into_owned(),
+            )),
+            None => Err(RpcError::MissingData("{{ status: \"...\" }}".to_string())),
+        }
+    }
+
+    async fn send(
+        &self,
+        body: &[u8],
+        endpoint: &str,
+    ) -> Result<(StatusCode, body::Bytes), RpcError> {
         let mut req = Request::builder()
             .method(Method::POST)
             .uri(self.url.clone().to_string() + endpoint)
-            .body(Body::from(body.to_owned()))?;
+            .body(Body::from(body.to_vec()))?;
         let (method, uri) = (req.method().clone(), req.uri().clone());
 
         // If configured with a username and password, try to authenticate with most
@@ -268,15 +489,15 @@ impl RpcClient {
                 .method(Method::POST)
                 .uri(self.url.clone().to_string() + endpoint)
                 .header(AUTHORIZATION, auth_header)
-                .body(Body::from(body.to_owned()))?;
+                .body(Body::from(body.to_vec()))?;
             // Await full response.
             response = timeout(self.timeout, self.client.request(req)).await??;
         }
 
-        let (_parts, body) = response.into_parts();
+        let (parts, body) = response.into_parts();
         let full_body = body::to_bytes(body).await?;
 
-        Ok(serde_json::from_slice(&full_body)?)
+        Ok((parts.status, full_body))
     }
 
     pub fn url(&self) -> String {
@@ -284,6 +505,94 @@ impl RpcClient {
     }
 }
 
+/// Parse the blocks and transactions in a binary blocks response.
+fn block_entries(
+    response: &Section,
+) -> Result<Vec<(monero::Hash, monero::Block, Vec<monero::Transaction>)>, RpcError> {
+    // Empty arrays are left out of binary responses entirely.
+    let Some(entries) = response.get("blocks") else {
+        return Ok(Vec::new());
+    };
+    let entries = entries
+        .as_array()
+        .ok_or_else(|| RpcError::MissingData("{{ blocks: [...] }}".to_string()))?;
+
+    let mut blocks = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let entry = entry.as_object().ok_or_else(|| {
+            RpcError::MissingData("{{ blocks: [ {{ block: \"...\" }} ] }}".to_string())
+        })?;
+        let block_blob = entry
+            .get("block")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| {
+                RpcError::MissingData("{{ blocks: [ {{ block: \"...\" }} ] }}".to_string())
+            })?;
+        let block: monero::Block = deserialize(block_blob)?;
+
+        let mut transactions = Vec::with_capacity(block.tx_hashes.len());
+        let tx_entries = entry.get("txs").and_then(Value::as_array).unwrap_or(&[]);
+        for tx_entry in tx_entries {
+            // Transactions are plain blobs, unless pruned.
+            let tx_blob = tx_entry
+                .as_bytes()
+                .or_else(|| tx_entry.as_object()?.get("blob")?.as_bytes())
+                .ok_or_else(|| {
+                    RpcError::MissingData("{{ blocks: [ {{ txs: [...] }} ] }}".to_string())
+                })?;
+            transactions.push(deserialize(tx_blob)?);
+        }
+        if transactions.len() != block.tx_hashes.len() {
+            return Err(RpcError::MissingData(format!(
+                "{{ blocks: [ {{ txs: [...] }} ] }} with {} transactions",
+                block.tx_hashes.len()
+            )));
+        }
+
+        blocks.push((block.id(), block, transactions));
+    }
+    Ok(blocks)
+}
+
+/// Run the `futures` concurrently, with at most `limit` of them in progress at
+/// once. Outputs are returned in the order of the futures. If any future fails,
+/// the remaining futures are dropped and the error is returned.
+async fn join_bounded<T, E, F>(futures: Vec<F>, limit: usize) -> Result<Vec<T>, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let mut futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<T>> = futures.iter().map(|_| None).collect();
+    let mut started = 0;
+    let mut in_progress = 0;
+    poll_fn(|cx| loop {
+        while started < futures.len() && in_progress < limit.max(1) {
+            started += 1;
+            in_progress += 1;
+        }
+        let mut finished_any = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()).take(started) {
+            if output.is_some() {
+                continue;
+            }
+            if let Poll::Ready(result) = future.as_mut().poll(cx) {
+                *output = Some(result?);
+                in_progress -= 1;
+                finished_any = true;
+            }
+        }
+        if in_progress == 0 && started == futures.len() {
+            return Poll::Ready(Ok(()));
+        }
+        // Newly started futures need to be polled before waiting.
+        if !finished_any {
+            return Poll::Pending;
+        }
+    })
+    .await?;
+    Ok(outputs.into_iter().flatten().collect())
+}
+
 #[derive(Error, Debug)]
 pub enum RpcError {
     #[error("HTTP request failed: {0}")]
@@ -307,4 +616,366 @@ pub enum RpcError {
     InvalidJson(#[from] serde_json::Error),
     #[error("authentication error: {0}")]
     Auth(#[from] AuthError),
+    #[error("daemon responded with HTTP status {0}")]
+    HttpStatus(StatusCode),
+    #[error("daemon responded with status \"{0}\"")]
+    DaemonStatus(String),
+    #[error("failed to interpret binary response: {0}")]
+    Epee(#[from] EpeeError),
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{cell::Cell, fs, sync::atomic::Ordering, time::Duration};
+
+    use httpmock::MockServer;
+    use monero::cryptonote::hash::Hashable;
+
+    use super::{
+        epee::{self, Section, Value},
+        join_bounded, RpcClient, RpcError,
+    };
+
+    fn rpc_client(server: &MockServer) -> RpcClient {
+        RpcClient::new(
+            server.url("").parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            None,
+            None,
+            None,
+            16,
+        )
+    }
+
+    fn read_json(path: &str) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// Binary blocks response holding the test blocks at the given heights.
+    fn blocks_response(heights: &[u64]) -> Vec<u8> {
+        let blocks = heights
+            .iter()
+            .map(|height| {
+                let block = read_json(&format!("tests/rpc_resources/blocks/{height}/block.json"));
+                let blob = hex::decode(block["result"]["blob"].as_str().unwrap()).unwrap();
+                // Blocks without transactions have no "txs" field.
+                let txs = read_json(&format!(
+                    "tests/rpc_resources/blocks/{height}/transactions_0.json"
+                ))["txs"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|tx| Value::String(hex::decode(tx["as_hex"].as_str().unwrap()).unwrap()))
+                    .collect();
+                let mut entry = Section::new();
+                entry.insert("block".to_string(), Value::String(blob));
+                entry.insert("txs".to_string(), Value::Array(txs));
+                Value::Object(entry)
+            })
+            .collect();
+        let mut response = Section::new();
+        response.insert("blocks".to_string(), Value::Array(blocks));
+        response.insert("start_height".to_string(), Value::Uint64(heights[0]));
+        response.insert("status".to_string(), Value::String(b"OK".to_vec()));
+        epee::to_bytes(&response)
+    }
+
+    fn expected_block_id(height: u64) -> monero::Hash {
+        let block = read_json(&format!("tests/rpc_resources/blocks/{height}/block.json"));
+        monero::Hash::from_slice(
+            &hex::decode(block["result"]["block_header"]["hash"].as_str().unwrap()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn binary_block() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/get_blocks_by_height.bin");
+            then.status(200).body(blocks_response(&[2477657]));
+        });
+        let rpc_client = rpc_client(&server);
+
+        let (block_id, block, transactions) =
+            rpc_client.block_with_transactions(2477657).await.unwrap();
+
+        mock.assert();
+        assert_eq!(block_id, expected_block_id(2477657));
+        assert_eq!(
+            transactions.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+            block.tx_hashes
+        );
+        assert!(!transactions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn binary_blocks() {
+        let server = MockServer::start();
+        // Older daemons may return more blocks than requested.
+        let mock = server.mock(|when, then| {
+            when.path("/get_blocks.bin");
+            then.status(200)
+                .body(blocks_response(&[2477660, 2477661, 2477662, 2477663]));
+        });
+        let rpc_client = rpc_client(&server);
+
+        let blocks = rpc_client
+            .blocks_with_transactions(2477660..=2477662)
+            .await
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(
+            blocks.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(),
+            (2477660..=2477662)
+                .map(expected_block_id)
+                .collect::<Vec<_>>()
+        );
+        assert!(blocks[1].2.is_empty());
+        for (_, block, transactions) in blocks {
+            assert_eq!(transactions.len(), block.tx_hashes.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_json() {
+        let server = MockServer::start();
+        let binary_mock = server.mock(|when, then| {
+            when.path("/get_blocks_by_height.bin");
+            then.status(403);
+        });
+        server.mock(|when, then| {
+            when.path("/json_rpc");
+            then.status(200)
+                .body_from_file("tests/rpc_resources/blocks/2477661/block.json");
+        });
+        let rpc_client = rpc_client(&server);
+
+        for _ in 0..2 {
+            let (block_id, block, transactions) =
+                rpc_client.block_with_transactions(2477661).await.unwrap();
+            assert_eq!(block_id, expected_block_id(2477661));
+            assert_eq!(block.tx_hashes.len(), 0);
+            assert!(transactions.is_empty());
+        }
+
+        // Binary endpoints aren't tried again.
+        binary_mock.assert_hits(1);
+        assert!(!rpc_client.binary.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn binary_busy_daemon() {
+        let server = MockServer::start();
+        let mut response = Section::new();
+        response.insert("status".to_string(), Value::String(b"BUSY".to_vec()));
+        let mut busy_mock = server.mock(|when, then| {
+            when.path("/get_blocks_by_height.bin");
+            then.status(200).body(epee::to_bytes(&response));
+        });
+        let json_mock = server.mock(|when, then| {
+            when.path("/json_rpc");
+            then.status(200)
+                .body_from_file("tests/rpc_resources/blocks/2477661/block.json");
+        });
+        let rpc_client = rpc_client(&server);
+
+        // The error is returned, without falling back to JSON RPC for good.
+        let error = rpc_client
+            .block_with_transactions(2477661)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "daemon responded with status \"BUSY\"");
+        assert!(rpc_client.binary.load(Ordering::Relaxed));
+        json_mock.assert_hits(0);
+
+        // Binary endpoints are tried again once the daemon is no longer busy.
+        busy_mock.delete();
+        let binary_mock = server.mock(|when, then| {
+            when.path("/get_blocks_by_height.bin");
+            then.status(200).body(blocks_response(&[2477661]));
+        });
+        let (block_id, _, _) = rpc_client.block_with_transactions(2477661).await.unwrap();
+        assert_eq!(block_id, expected_block_id(2477661));
+        binary_mock.assert_hits(1);
+        json_mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn binary_daemon_error() {
+        let server = MockServer::start();
+        let mut response = Section::new();
+        response.insert("status".to_string(), Value::String(b"Failed".to_vec()));
+        server.mock(|when, then| {
+            when.path("/get_blocks_by_height.bin");
+            then.status(200).body(epee::to_bytes(&response));
+        });
+        let rpc_client = rpc_client(&server);
+
+        let error = rpc_client
+            .blocks_by_height_bin(&[2477661])
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "daemon responded with status \"Failed\"");
+    }
+
+    #[tokio::test]
+    async fn block_ids() {
+        let server = MockServer::start();
+        let heights = 2477655..=2477658;
+        let mock = server.mock(|when, then| {
+            when.path("/json_rpc").json_body(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": "0",
+                "method": "get_block_headers_range",
+                "params": {"start_height": 2477655, "end_height": 2477658}
+            }));
+            then.status(200).json_body(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": "0",
+                "result": {
+                    "headers": heights.clone().map(|height| {
+                        read_json(&format!("tests/rpc_resources/blocks/{height}/block.json"))
+                            ["result"]["block_header"]
+                            .clone()
+                    }).collect::<Vec<_>>(),
+                    "status": "OK"
+                }
+            }));
+        });
+        let rpc_client = rpc_client(&server);
+
+        let block_ids = rpc_client.block_ids(heights.clone()).await.unwrap();
+
+        mock.assert();
+        assert_eq!(
+            block_ids,
+            heights.map(expected_block_id).collect::<Vec<_>>()
+        );
+    }
+
+    /// Test transactions from several blocks, with their hashes.
+    fn transactions(heights: &[u64]) -> Vec<(monero::Hash, String)> {
+        heights
+            .iter()
+            .flat_map(|height| {
+                read_json(&format!(
+                    "tests/rpc_resources/blocks/{height}/transactions_0.json"
+                ))["txs"]
+                    .as_array()
+                    .unwrap()
+                    .clone()
+            })
+            .map(|tx| {
+                let tx_hex = tx["as_hex"].as_str().unwrap().to_string();
+                let tx: monero::Transaction =
+                    monero::consensus::deserialize(&hex::decode(&tx_hex).unwrap()).unwrap();
+                (tx.hash(), tx_hex)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn transactions_in_order() {
+        let transactions = transactions(&[2477647, 2477648, 2477649]);
+        assert!(transactions.len() > 100);
+        let server = MockServer::start();
+        let mocks: Vec<_> = transactions
+            .chunks(100)
+            .enumerate()
+            .map(|(i, chunk)| {
+                server.mock(|when, then| {
+                    when.path("/get_transactions")
+                        .body_contains(hex::encode(chunk[0].0.as_bytes()));
+                    // The first batch arrives last.
+                    then.status(200)
+                        .delay(Duration::from_millis(if i == 0 { 300 } else { 0 }))
+                        .json_body(serde_json::json!({
+                            "txs_as_hex": chunk.iter().map(|(_, tx)| tx).collect::<Vec<_>>()
+                        }));
+                })
+            })
+            .collect();
+        let rpc_client = rpc_client(&server);
+
+        let hashes: Vec<_> = transactions.iter().map(|(hash, _)| *hash).collect();
+        let received = rpc_client.transactions_by_hashes(&hashes).await.unwrap();
+
+        for mock in mocks {
+            mock.assert();
+        }
+        assert_eq!(
+            received.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+            hashes
+        );
+    }
+
+    #[tokio::test]
+    async fn transactions_batch_error() {
+        let transactions = transactions(&[2477647, 2477648, 2477649]);
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/get_transactions")
+                .body_contains(hex::encode(transactions[0].0.as_bytes()));
+            then.status(200)
+                .delay(Duration::from_millis(300))
+                .json_body(serde_json::json!({
+                    "txs_as_hex": transactions[..100].iter().map(|(_, tx)| tx).collect::<Vec<_>>()
+                }));
+        });
+        server.mock(|when, then| {
+            when.path("/get_transactions")
+                .body_contains(hex::encode(transactions[100].0.as_bytes()));
+            then.status(200)
+                .json_body(serde_json::json!({ "txs_as_hex": ["not hex"] }));
+        });
+        let rpc_client = rpc_client(&server);
+
+        let hashes: Vec<_> = transactions.iter().map(|(hash, _)| *hash).collect();
+        assert!(matches!(
+            rpc_client.transactions_by_hashes(&hashes).await,
+            Err(RpcError::HexDecode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_bounded_limits_concurrency() {
+        let in_progress = Cell::new(0);
+        let max_in_progress = Cell::new(0);
+        let futures = (0..10u64)
+            .map(|i| {
+                let (in_progress, max_in_progress) = (&in_progress, &max_in_progress);
+                async move {
+                    in_progress.set(in_progress.get() + 1);
+                    max_in_progress.set(max_in_progress.get().max(in_progress.get()));
+                    // Finish out of order.
+                    tokio::time::sleep(Duration::from_millis(10 * (10 - i))).await;
+                    in_progress.set(in_progress.get() - 1);
+                    Ok::<_, ()>(i)
+                }
+            })
+            .collect();
+
+        let outputs = join_bounded(futures, 3).await.unwrap();
+        assert_eq!(outputs, (0..10).collect::<Vec<_>>());
+        assert_eq!(max_in_progress.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn join_bounded_returns_first_error() {
+        let futures = (0..10u64)
+            .map(|i| async move {
+                if i == 4 {
+                    Err(i)
+                } else {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Ok(i)
+                }
+            })
+            .collect();
+
+        assert_eq!(join_bounded(futures, 3).await, Err(4));
+    }
 }
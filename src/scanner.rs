@@ -1,63 +1,108 @@
 use std::{
     collections::HashMap,
+    io,
+    path::PathBuf,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use log::{debug, error, info, trace};
-use monero::{
-    cryptonote::{hash::Hashable, onetime_key::SubKeyChecker},
-    Amount, Transaction, VarInt,
+use log::{debug, error, info, trace, warn};
+use monero::{blockdata::transaction, cryptonote::onetime_key::SubKeyChecker, Amount};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use tokio::{
+    join,
+    sync::Mutex,
+    time::{self, Interval},
 };
-use tokio::{join, sync::Mutex};
 
+#[cfg(feature = "zmq")]
+use crate::zmq_notifier::ZmqNotifier;
 use crate::{
-    caching::{BlockCache, TxpoolCache},
+    caching::{
+        BlockCache, CacheSnapshot, CachedBlock, CompactTransaction, OutputStats, OwnedOutput,
+        Reorg, TxpoolCache,
+    },
     invoice::Transfer,
-    pubsub::Publisher,
-    rpc::RpcClient,
-    storage::{InvoiceStorage, Store},
-    AcceptXmrError, SubIndex,
+    pubsub::{GatewayEvent, Publisher},
+    rpc::{RpcClient, RpcError},
+    storage::{Checkpoint, InvoiceStorage, Store},
+    AcceptXmrError, Invoice, InvoiceId, SubIndex,
 };
 
+/// How often to poll the daemon while receiving its ZMQ notifications.
+#[cfg(feature = "zmq")]
+const ZMQ_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An updated invoice, along with whether the update should be published to
+/// subscribers, and whether the invoice just became spendable.
+type InvoiceUpdate = (Invoice, bool, bool);
+
+/// Amounts received by each transaction, along with the transaction hash.
+type AmountsReceived = Vec<(monero::Hash, Vec<OwnedAmount>)>;
+
+/// Outputs of a transaction owned by a sub key checker.
+type OwnedOutputs = Result<Vec<OwnedOutput>, transaction::Error>;
+
 pub(crate) struct Scanner<S: InvoiceStorage> {
     invoice_store: Store<S>,
     // Block cache and txpool cache are mutexed to allow concurrent block &
-    // txpool scanning. This is necessary even though txpool scanning doesn't
-    // use the block cache, and vice versa, because rust doesn't allow mutably
-    // borrowing only part of "self".
-    block_cache: Mutex<BlockCache>,
-    txpool_cache: Mutex<TxpoolCache>,
+    // txpool scanning, and so that they can be shared between the scanners of
+    // several payment gateways.
+    block_cache: Arc<Mutex<BlockCache>>,
+    txpool_cache: Arc<Mutex<TxpoolCache>>,
+    /// Transfers previously discovered in the txpool (no reason to scan the
+    /// same transactions twice).
+    discovered_transfers: Mutex<HashMap<monero::Hash, Vec<(SubIndex, Transfer)>>>,
     publisher: Arc<Publisher>,
+    /// Outputs smaller than this are ignored as dust, unless the invoice sets its
+    /// own threshold.
+    dust_threshold: u64,
+    output_checker: OutputChecker,
+    /// The checkpoint last saved to the invoice store.
+    checkpoint: Option<Checkpoint>,
+    /// Where to save the caches, if they are saved to disk.
+    cache_path: Option<PathBuf>,
     first_scan: bool,
 }
 
 impl<S: InvoiceStorage> Scanner<S> {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         rpc_client: RpcClient,
         invoice_store: Store<S>,
         block_cache_size: usize,
+        reorg_depth: usize,
         atomic_cache_height: Arc<AtomicU64>,
         atomic_daemon_height: Arc<AtomicU64>,
         publisher: Arc<Publisher>,
+        dust_threshold: u64,
+        output_checker: OutputChecker,
+        cache_path: Option<PathBuf>,
     ) -> Result<Scanner<S>, AcceptXmrError<S::Error>> {
         // Determine sensible initial height for block cache.
         let daemon_height = rpc_client.daemon_height().await?;
-        let cache_height = match invoice_store.lowest_height() {
-            Ok(Some(h)) => {
+        let checkpoint = invoice_store
+            .checkpoint()
+            .map_err(AcceptXmrError::InvoiceStorage)?;
+        let cache_height = match (checkpoint, invoice_store.lowest_height()) {
+            (Some(checkpoint), _) => {
+                resume_height(&rpc_client, checkpoint, daemon_height, reorg_depth).await?
+            }
+            (None, Ok(Some(h))) => {
                 info!("Pending invoices found in AcceptXMR database. Resuming from last block scanned: {}", h);
                 h - 1
             }
-            Ok(None) => {
+            (None, Ok(None)) => {
                 trace!("Retrieving daemon hight for scanner setup.");
                 let h = daemon_height;
                 info!("No pending invoices found in AcceptXMR database. Skipping to blockchain tip: {}", h);
                 h - 1
             }
-            Err(e) => return Err(AcceptXmrError::InvoiceStorage(e)),
+            (None, Err(e)) => return Err(AcceptXmrError::InvoiceStorage(e)),
         };
 
         // Set atomic height to the above determined initial height. This sets the
@@ -65,40 +110,113 @@ impl<S: InvoiceStorage> Scanner<S> {
         atomic_cache_height.store(cache_height, Ordering::Relaxed);
         atomic_daemon_height.store(daemon_height, Ordering::Relaxed);
 
-        // Initialize block cache and txpool cache.
+        // Initialize block cache and txpool cache, from the snapshot saved when
+        // the scanner last stopped if there is one.
+        let snapshot = cache_path.as_deref().and_then(CacheSnapshot::load);
         let (block_cache, txpool_cache) = join!(
-            BlockCache::init::<S>(
+            BlockCache::init::<S::Error>(
                 rpc_client.clone(),
                 block_cache_size,
+                reorg_depth,
                 atomic_cache_height,
-                atomic_daemon_height
+                atomic_daemon_height,
+                snapshot.as_ref(),
             ),
-            TxpoolCache::init::<S>(rpc_client.clone())
+            TxpoolCache::init::<S::Error>(rpc_client.clone(), snapshot.as_ref())
         );
 
-        Ok(Scanner {
+        let mut scanner = Scanner::with_caches(
             invoice_store,
-            block_cache: Mutex::new(block_cache?),
-            txpool_cache: Mutex::new(txpool_cache?),
+            Arc::new(Mutex::new(block_cache?)),
+            Arc::new(Mutex::new(txpool_cache?)),
             publisher,
+            dust_threshold,
+            output_checker,
+        );
+        scanner.cache_path = cache_path;
+        Ok(scanner)
+    }
+
+    /// Create a scanner using existing block and txpool caches. The caches are
+    /// expected to be kept up to date by the caller.
+    pub fn with_caches(
+        invoice_store: Store<S>,
+        block_cache: Arc<Mutex<BlockCache>>,
+        txpool_cache: Arc<Mutex<TxpoolCache>>,
+        publisher: Arc<Publisher>,
+        dust_threshold: u64,
+        output_checker: OutputChecker,
+    ) -> Scanner<S> {
+        Scanner {
+            invoice_store,
+            block_cache,
+            txpool_cache,
+            discovered_transfers: Mutex::new(HashMap::new()),
+            publisher,
+            dust_threshold,
+            output_checker,
+            checkpoint: None,
+            cache_path: None,
             first_scan: true,
-        })
+        }
     }
 
-    /// Scan for invoice updates.
+    /// Update caches if `fetch` is `true`, and scan for invoice updates.
     pub async fn scan(
         &mut self,
         sub_key_checker: &SubKeyChecker<'_>,
+        fetch: bool,
     ) -> Result<(), AcceptXmrError<S::Error>> {
         // Update block and txpool caches.
-        let (blocks_updated, new_transactions) = self.update_caches().await?;
+        let (blocks_updated, new_transactions) = if fetch {
+            let (blocks_updated, new_transactions) = self.update_caches().await?;
+            if blocks_updated > 0 {
+                self.save_caches().await;
+            }
+            (blocks_updated, new_transactions)
+        } else {
+            // Any reorg found by the last update has been dealt with already.
+            self.block_cache.lock().await.reorg = None;
+            (0, Vec::new())
+        };
 
+        self.scan_caches(sub_key_checker, blocks_updated, &new_transactions)
+            .await
+    }
+
+    /// Save the caches to disk, if configured to.
+    pub async fn save_caches(&self) {
+        if let Some(path) = &self.cache_path {
+            CacheSnapshot::save(path, &self.block_cache, &self.txpool_cache).await;
+        }
+    }
+
+    /// Scan for invoice updates in the `blocks_updated` newest blocks of the
+    /// block cache, and in the `new_transactions` added to the txpool, without
+    /// updating the caches first.
+    pub async fn scan_caches(
+        &mut self,
+        sub_key_checker: &SubKeyChecker<'_>,
+        blocks_updated: usize,
+        new_transactions: &[CompactTransaction],
+    ) -> Result<(), AcceptXmrError<S::Error>> {
         // Scan block cache and new transactions in the txpool.
         let (blocks_amounts_or_err, txpool_amounts_or_err) = join!(
             self.scan_blocks(sub_key_checker, blocks_updated),
-            self.scan_txpool(sub_key_checker, &new_transactions)
+            self.scan_txpool(sub_key_checker, new_transactions)
         );
-        let block_cache_height = self.block_cache.lock().await.height.load(Ordering::Relaxed);
+        let (block_cache_height, reorg, reorg_depth, checkpoint) = {
+            let block_cache = self.block_cache.lock().await;
+            (
+                block_cache.height.load(Ordering::Relaxed),
+                block_cache.reorg.clone(),
+                block_cache.reorg_depth(),
+                block_cache.blocks.first().map(|block| Checkpoint {
+                    height: block.height,
+                    block_id: block.id.to_bytes(),
+                }),
+            )
+        };
 
         let blocks_amounts = match blocks_amounts_or_err {
             Ok(amts) => amts,
@@ -126,75 +244,22 @@ impl<S: InvoiceStorage> Scanner<S> {
         }
 
         // Prepare updated invoices.
-        // TODO: Break this out into its own function.
+        let _updates = self.invoice_store.lock_updates().await;
         let deepest_update = block_cache_height - blocks_updated as u64 + 1;
-        let mut updated_invoices = Vec::new();
-        for invoice_or_err in self
-            .invoice_store
-            .lock()
-            .try_iter()
-            .map_err(AcceptXmrError::InvoiceStorage)?
-        {
-            // Retrieve old invoice object.
-            let old_invoice = match invoice_or_err {
-                Ok(p) => p,
-                Err(e) => {
-                    error!(
-                        "Failed to retrieve old invoice object from database while iterating through database: {}", e
-                    );
-                    continue;
-                }
-            };
-            let mut invoice = old_invoice.clone();
-
-            // Remove transfers occurring in or after the deepest block update.
-            invoice.transfers.retain(|transfer| {
-                transfer
-                    .cmp_by_height(&Transfer::new(0, Some(deepest_update)))
-                    .is_lt()
-            });
-
-            // Add transfers from blocks and txpool.
-            for (sub_index, owned_transfer) in &transfers {
-                if sub_index == &invoice.index()
-                    && owned_transfer
-                        // Creation height - 1 because creation height is one greater than top block
-                        // height.
-                        .cmp_by_height(&Transfer::new(0, Some(invoice.creation_height() - 1)))
-                        .is_gt()
-                {
-                    invoice.transfers.push(*owned_transfer);
-                }
-            }
-
-            // Update invoice's current_block.
-            if invoice.current_height != block_cache_height + 1 {
-                invoice.current_height = block_cache_height + 1;
-            }
-
-            // No need to recalculate total paid_amount or paid_at unless something changed.
-            if invoice != old_invoice {
-                // Zero it out first.
-                invoice.paid_height = None;
-                invoice.amount_paid = 0;
-                // Now add up the transfers.
-                for transfer in &invoice.transfers {
-                    invoice.amount_paid += transfer.amount;
-                    if invoice.amount_paid >= invoice.amount_requested()
-                        && invoice.paid_height.is_none()
-                    {
-                        invoice.paid_height = transfer.height;
-                    }
-                }
-
-                // This invoice has been updated. We can now add it in with the other
-                // updated_invoices.
-                updated_invoices.push(invoice);
-            }
-        }
+        let updated_invoices = self.updated_invoices(
+            &transfers,
+            deepest_update,
+            block_cache_height,
+            reorg.as_ref().map(|reorg| reorg.fork_height),
+        )?;
 
         // Save and log updates.
-        for invoice in updated_invoices {
+        let reorged_invoices = updated_invoices
+            .iter()
+            .filter(|(_, reorged)| *reorged)
+            .map(|((invoice, _, _), _)| invoice.id())
+            .collect();
+        for ((invoice, publish, became_spendable), _) in updated_invoices {
             debug!(
                 "Invoice update for subaddress index {}: \
                     \n{}",
@@ -210,36 +275,122 @@ impl<S: InvoiceStorage> Scanner<S> {
             } else {
                 // If the update was successful, send an update that down the subscriber
                 // channel.
-                self.publisher.send_updates(&invoice).await;
+                if publish {
+                    self.publisher.send_updates(&invoice).await;
+                }
+                if became_spendable {
+                    debug!("Invoice {} is now spendable", invoice.id());
+                    self.publisher.send_spendable(&invoice).await;
+                }
             }
         }
 
+        // Record the last block scanned, to resume from it after a restart.
+        if let Some(checkpoint) = checkpoint.filter(|c| Some(*c) != self.checkpoint) {
+            self.invoice_store
+                .set_checkpoint(checkpoint)
+                .map_err(AcceptXmrError::InvoiceStorage)?;
+            self.checkpoint = Some(checkpoint);
+        }
+
         // Flush changes to the database.
         self.invoice_store
             .flush()
             .map_err(AcceptXmrError::InvoiceStorage)?;
+
+        if let Some(reorg) = reorg {
+            self.send_reorg_events(reorg, reorg_depth, reorged_invoices)
+                .await;
+        }
         Ok(())
     }
 
-    async fn update_caches(&self) -> Result<(usize, Vec<Transaction>), AcceptXmrError<S::Error>> {
-        // Update block cache.
-        let mut block_cache = self.block_cache.lock().await;
-        let blocks_updated = if self
+    /// Apply the newly scanned `transfers` to every stored invoice.
+    ///
+    /// Returns the updated invoices, each along with whether it had transfers
+    /// removed or moved by a reorg down to `fork_height`.
+    fn updated_invoices(
+        &self,
+        transfers: &[(SubIndex, Transfer)],
+        deepest_update: u64,
+        block_cache_height: u64,
+        fork_height: Option<u64>,
+    ) -> Result<Vec<(InvoiceUpdate, bool)>, AcceptXmrError<S::Error>> {
+        let mut updated_invoices = Vec::new();
+        for invoice_or_err in self
             .invoice_store
-            .is_empty()
+            .lock()
+            .try_iter()
             .map_err(AcceptXmrError::InvoiceStorage)?
         {
-            // Skip ahead to blockchain tip if there are no pending invoices.
-            block_cache.skip_ahead::<S>().await?
-        } else {
-            block_cache.update::<S>().await?
-        };
+            // Retrieve old invoice object.
+            let old_invoice = match invoice_or_err {
+                Ok(p) => p,
+                Err(e) => {
+                    error!(
+                        "Failed to retrieve old invoice object from database while iterating through database: {}", e
+                    );
+                    continue;
+                }
+            };
+            if let Some(update) = updated_invoice(
+                &old_invoice,
+                transfers,
+                deepest_update,
+                block_cache_height,
+                self.dust_threshold,
+            ) {
+                let reorged = fork_height.map_or(false, |height| {
+                    transfers_reorged(&old_invoice, &update.0, height)
+                });
+                // This invoice has been updated. We can now add it in with the other
+                // updated_invoices.
+                updated_invoices.push((update, reorged));
+            }
+        }
+        Ok(updated_invoices)
+    }
 
-        // Update txpool.
-        let mut txpool_cache = self.txpool_cache.lock().await;
-        let new_transactions = txpool_cache.update::<S>().await?;
+    /// Notify event subscribers of a reorg found during the last block cache
+    /// update, which affected the `reorged_invoices`.
+    async fn send_reorg_events(
+        &self,
+        reorg: Reorg,
+        reorg_depth: usize,
+        reorged_invoices: Vec<InvoiceId>,
+    ) {
+        let block_ids = |ids: Vec<monero::Hash>| {
+            ids.into_iter()
+                .map(|id| hex::encode(id.as_bytes()))
+                .collect()
+        };
+        self.publisher
+            .send_event(&GatewayEvent::Reorg {
+                fork_height: reorg.fork_height,
+                old_blocks: block_ids(reorg.old_blocks),
+                new_blocks: block_ids(reorg.new_blocks),
+                invoices: reorged_invoices,
+            })
+            .await;
+        if reorg.beyond_window {
+            self.publisher
+                .send_event(&GatewayEvent::ReorgBeyondWindow {
+                    rescanned_from: reorg.fork_height,
+                    reorg_depth,
+                })
+                .await;
+        }
+    }
 
-        Ok((blocks_updated, new_transactions))
+    async fn update_caches(
+        &self,
+    ) -> Result<(usize, Vec<CompactTransaction>), AcceptXmrError<S::Error>> {
+        // Skip ahead to blockchain tip if there are no pending invoices.
+        let skip_ahead = self
+            .invoice_store
+            .is_empty()
+            .map_err(AcceptXmrError::InvoiceStorage)?;
+        update_caches(&self.block_cache, &self.txpool_cache, skip_ahead).await
     }
 
     /// Scan the block cache up to `updated_blocks` deep.
@@ -262,13 +413,21 @@ impl<S: InvoiceStorage> Scanner<S> {
 
         // Scan updated blocks.
         for i in (0..blocks_updated).rev() {
-            let transactions = &block_cache.blocks[i].3;
-            let amounts_received = self.scan_transactions(transactions, sub_key_checker)?;
+            let block = &block_cache.blocks[i];
+            let (mut amounts_received, mut stats) =
+                self.scan_transactions(&block.transactions, sub_key_checker)?;
+            // Scan the miner transaction too, for payouts from pools or solo mining.
+            let (miner_amounts_received, miner_stats) =
+                self.scan_transactions(std::slice::from_ref(&block.miner_tx), sub_key_checker)?;
+            amounts_received.extend(miner_amounts_received);
+            stats += miner_stats;
             trace!(
-                "Scanned {} transactions from block {}, and found {} transactions to tracked invoices",
-                transactions.len(),
-                block_cache.blocks[i].1,
-                amounts_received.len()
+                "Scanned {} transactions from block {}, and found {} transactions to tracked invoices. View tags ruled out {} of {} outputs",
+                block.transactions.len(),
+                block.height,
+                amounts_received.len(),
+                stats.view_tag_rejections,
+                stats.outputs
             );
 
             let block_cache_height: u64 = block_cache.height.load(Ordering::Relaxed) - i as u64;
@@ -278,10 +437,10 @@ impl<S: InvoiceStorage> Scanner<S> {
                 amounts_received
                     .into_iter()
                     .flat_map(|(_, amounts)| amounts)
-                    .map(|OwnedAmount { sub_index, amount }| {
+                    .map(|owned_amount| {
                         (
-                            sub_index,
-                            Transfer::new(amount.as_pico(), Some(block_cache_height)),
+                            owned_amount.sub_index,
+                            owned_amount.to_transfer(Some(block_cache_height)),
                         )
                     })
                     .collect(),
@@ -297,20 +456,25 @@ impl<S: InvoiceStorage> Scanner<S> {
     async fn scan_txpool(
         &self,
         sub_key_checker: &SubKeyChecker<'_>,
-        new_transactions: &[Transaction],
+        new_transactions: &[CompactTransaction],
     ) -> Result<Vec<(SubIndex, Transfer)>, AcceptXmrError<S::Error>> {
-        let mut txpool_cache = self.txpool_cache.lock().await;
+        let mut discovered_transfers = self.discovered_transfers.lock().await;
 
-        // Transfers previously discovered in the txpool (no reason to scan the same
-        // transactions twice).
-        let discovered_transfers = txpool_cache.discovered_transfers();
+        // Forget transfers which are no longer in the txpool.
+        {
+            let txpool_cache = self.txpool_cache.lock().await;
+            discovered_transfers.retain(|hash, _| txpool_cache.contains(hash));
+        }
 
         // Scan txpool.
-        let amounts_received = self.scan_transactions(new_transactions, sub_key_checker)?;
+        let (amounts_received, stats) =
+            self.scan_transactions(new_transactions, sub_key_checker)?;
         trace!(
-            "Scanned {} transactions from txpool, and found {} transfers for tracked invoices",
+            "Scanned {} transactions from txpool, and found {} transfers for tracked invoices. View tags ruled out {} of {} outputs",
             new_transactions.len(),
-            amounts_received.len()
+            amounts_received.len(),
+            stats.view_tag_rejections,
+            stats.outputs
         );
 
         let new_transfers: HashMap<monero::Hash, Vec<(SubIndex, Transfer)>> = amounts_received
@@ -320,8 +484,8 @@ impl<S: InvoiceStorage> Scanner<S> {
                     *hash,
                     amounts
                         .iter()
-                        .map(|OwnedAmount { sub_index, amount }| {
-                            (*sub_index, Transfer::new(amount.as_pico(), None))
+                        .map(|owned_amount| {
+                            (owned_amount.sub_index, owned_amount.to_transfer(None))
                         })
                         .collect(),
                 )
@@ -329,11 +493,14 @@ impl<S: InvoiceStorage> Scanner<S> {
             .collect();
 
         let mut transfers: HashMap<monero::Hash, Vec<(SubIndex, Transfer)>> = new_transfers.clone();
-        // CLoning here because discovered_transactions is owned by the txpool cache.
         transfers.extend(discovered_transfers.clone());
 
         // Add the new transfers to the cache for next scan.
-        txpool_cache.insert_transfers(&new_transfers);
+        discovered_transfers.extend(new_transfers);
+        trace!(
+            "Txpool contains {} transfers for tracked invoices",
+            discovered_transfers.len(),
+        );
 
         Ok(transfers
             .into_iter()
@@ -341,50 +508,313 @@ impl<S: InvoiceStorage> Scanner<S> {
             .collect())
     }
 
+    /// Scan `transactions` for outputs to tracked invoices.
+    ///
+    /// Returns the amounts received by each transaction with any, in the order
+    /// of `transactions`, along with statistics on the outputs checked.
     fn scan_transactions(
         &self,
-        transactions: &[monero::Transaction],
+        transactions: &[CompactTransaction],
         sub_key_checker: &SubKeyChecker<'_>,
-    ) -> Result<HashMap<monero::Hash, Vec<OwnedAmount>>, AcceptXmrError<S::Error>> {
-        let mut amounts_received = HashMap::new();
-        for tx in transactions {
-            // Ensure the time lock is zero.
-            if tx.prefix().unlock_time != VarInt(0) {
-                debug!("Saw time locked transaction with hash {}", tx.hash());
-                continue;
-            }
-
-            // Scan transaction for owned outputs.
-            let transfers = tx.check_outputs_with(sub_key_checker)?;
-
-            for transfer in &transfers {
-                let sub_index = SubIndex::from(transfer.sub_index());
+    ) -> Result<(AmountsReceived, OutputStats), AcceptXmrError<S::Error>> {
+        let (owned_outputs, stats) = self.output_checker.check(transactions, sub_key_checker);
 
+        let mut amounts_received = Vec::new();
+        for (tx, outputs) in transactions.iter().zip(owned_outputs) {
+            let mut amounts = Vec::new();
+            for owned_amount in owned_amounts(tx, &outputs?)? {
                 // If this invoice is being tracked, add the amount and subindex to the result
                 // set.
                 if self
                     .invoice_store
-                    .contains_sub_index(sub_index)
+                    .contains_sub_index(owned_amount.sub_index)
                     .map_err(AcceptXmrError::InvoiceStorage)?
                 {
-                    let amount = OwnedAmount {
-                        sub_index,
-                        amount: transfers[0]
-                            .amount()
-                            .ok_or(AcceptXmrError::<S::Error>::Unblind(sub_index))?,
-                    };
-                    amounts_received
-                        .entry(tx.hash())
-                        .or_insert_with(Vec::new)
-                        .push(amount);
+                    amounts.push(owned_amount);
                 }
             }
+            if !amounts.is_empty() {
+                amounts_received.push((tx.hash, amounts));
+            }
         }
 
-        Ok(amounts_received.into_iter().collect())
+        Ok((amounts_received, stats))
     }
 }
 
+/// Checks transactions for owned outputs, splitting the work by transaction
+/// across a thread pool. Without a thread pool, transactions are checked on the
+/// calling thread.
+#[derive(Clone)]
+pub(crate) struct OutputChecker {
+    thread_pool: Option<Arc<ThreadPool>>,
+}
+
+impl OutputChecker {
+    /// Create an output checker using up to `threads` threads. With fewer than
+    /// two threads, no thread pool is created.
+    pub fn new(threads: usize) -> io::Result<OutputChecker> {
+        if threads < 2 {
+            return Ok(OutputChecker { thread_pool: None });
+        }
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("Output Scanning Thread {i}"))
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(OutputChecker {
+            thread_pool: Some(Arc::new(thread_pool)),
+        })
+    }
+
+    /// Check each of `transactions` for outputs owned by `sub_key_checker`.
+    ///
+    /// Returns the result for each transaction, in the order of
+    /// `transactions`, along with statistics on the outputs checked.
+    fn check(
+        &self,
+        transactions: &[CompactTransaction],
+        sub_key_checker: &SubKeyChecker<'_>,
+    ) -> (Vec<OwnedOutputs>, OutputStats) {
+        let check_transaction = |tx: &CompactTransaction| {
+            let mut stats = OutputStats::default();
+            (tx.check_outputs_with(sub_key_checker, &mut stats), stats)
+        };
+        let checked: Vec<_> = match &self.thread_pool {
+            Some(thread_pool) => {
+                thread_pool.install(|| transactions.par_iter().map(check_transaction).collect())
+            }
+            None => transactions.iter().map(check_transaction).collect(),
+        };
+
+        let mut total_stats = OutputStats::default();
+        let owned_outputs = checked
+            .into_iter()
+            .map(|(owned_outputs, stats)| {
+                total_stats += stats;
+                owned_outputs
+            })
+            .collect();
+        (owned_outputs, total_stats)
+    }
+}
+
+/// Apply the newly scanned `transfers` to an invoice, returning the updated
+/// invoice if anything changed, along with whether the update should be
+/// published to subscribers, and whether the invoice just became spendable.
+fn updated_invoice(
+    old_invoice: &Invoice,
+    transfers: &[(SubIndex, Transfer)],
+    deepest_update: u64,
+    block_cache_height: u64,
+    dust_threshold: u64,
+) -> Option<InvoiceUpdate> {
+    let mut invoice = old_invoice.clone();
+
+    // Remove transfers (and dust) occurring in or after the deepest block update.
+    let deepest_transfer = Transfer::new(0, Some(deepest_update));
+    invoice
+        .transfers
+        .retain(|transfer| transfer.cmp_by_height(&deepest_transfer).is_lt());
+    invoice
+        .dust
+        .retain(|transfer| transfer.cmp_by_height(&deepest_transfer).is_lt());
+
+    // Add transfers from blocks and txpool.
+    add_transfers(&mut invoice, transfers, dust_threshold);
+
+    // Update invoice's current_block.
+    if invoice.current_height != block_cache_height + 1 {
+        invoice.current_height = block_cache_height + 1;
+    }
+
+    // No need to recalculate total paid_amount or paid_at unless something changed.
+    if invoice == *old_invoice {
+        return None;
+    }
+
+    // Dust is recorded, but never published on its own.
+    let dust_only = {
+        let mut without_new_dust = invoice.clone();
+        without_new_dust.dust.clone_from(&old_invoice.dust);
+        without_new_dust == *old_invoice
+    };
+
+    invoice.recalculate_paid();
+
+    // Watched addresses are only published when their transfers change, rather
+    // than on every new block.
+    let publish = !dust_only
+        && (!invoice.is_watched() || {
+            let mut transfers = invoice.transfers.clone();
+            let mut old_transfers = old_invoice.transfers.clone();
+            transfers.sort_unstable();
+            old_transfers.sort_unstable();
+            transfers != old_transfers
+        });
+
+    let became_spendable = invoice.is_spendable() && !old_invoice.is_spendable();
+
+    Some((invoice, publish || became_spendable, became_spendable))
+}
+
+/// Add the `transfers` to the invoice, where they belong to it. Transfers
+/// smaller than the dust threshold are added as dust.
+pub(crate) fn add_transfers(
+    invoice: &mut Invoice,
+    transfers: &[(SubIndex, Transfer)],
+    dust_threshold: u64,
+) {
+    let dust_threshold = invoice.dust_threshold().unwrap_or(dust_threshold);
+
+    // Transfers made once a successor on the same subaddress exists (i.e. the next
+    // billing period of a recurring invoice) belong to the successor.
+    let successor_height = invoice
+        .successor()
+        .filter(|successor| successor.sub_index == invoice.index())
+        .map(|successor| successor.creation_height);
+
+    for (sub_index, owned_transfer) in transfers {
+        if sub_index == &invoice.index()
+            && owned_transfer
+                // Creation height - 1 because creation height is one greater than top block
+                // height.
+                .cmp_by_height(&Transfer::new(0, Some(invoice.creation_height() - 1)))
+                .is_gt()
+            && successor_height.map_or(true, |height| {
+                owned_transfer
+                    .cmp_by_height(&Transfer::new(0, Some(height - 1)))
+                    .is_le()
+            })
+        {
+            if owned_transfer.amount < dust_threshold {
+                invoice.dust.push(*owned_transfer);
+            } else {
+                invoice.transfers.push(*owned_transfer);
+            }
+        }
+    }
+}
+
+/// Scan a block's transactions, including its miner transaction, for outputs
+/// owned by `sub_key_checker`, whether or not an invoice tracks them.
+pub(crate) fn block_transfers<E>(
+    block: &CachedBlock,
+    output_checker: &OutputChecker,
+    sub_key_checker: &SubKeyChecker<'_>,
+) -> Result<Vec<(SubIndex, Transfer)>, AcceptXmrError<E>> {
+    let mut transfers = Vec::new();
+    for transactions in [
+        &block.transactions[..],
+        std::slice::from_ref(&block.miner_tx),
+    ] {
+        let (owned_outputs, _) = output_checker.check(transactions, sub_key_checker);
+        for (tx, outputs) in transactions.iter().zip(owned_outputs) {
+            for owned_amount in owned_amounts(tx, &outputs?)? {
+                transfers.push((
+                    owned_amount.sub_index,
+                    owned_amount.to_transfer(Some(block.height)),
+                ));
+            }
+        }
+    }
+    Ok(transfers)
+}
+
+/// Unblind the amount of each of the `outputs` owned in `tx`.
+fn owned_amounts<E>(
+    tx: &CompactTransaction,
+    outputs: &[OwnedOutput],
+) -> Result<Vec<OwnedAmount>, AcceptXmrError<E>> {
+    outputs
+        .iter()
+        .map(|output| {
+            let sub_index = SubIndex::from(output.sub_index);
+            Ok(OwnedAmount {
+                sub_index,
+                amount: output.amount.ok_or(AcceptXmrError::Unblind(sub_index))?,
+                coinbase: tx.coinbase,
+                unlock_time: tx.prefix.unlock_time.0,
+            })
+        })
+        .collect()
+}
+
+/// Returns `true` if any of the old invoice's transfers from blocks at or above
+/// `fork_height` is missing from the new invoice, meaning that a reorg removed
+/// it or moved it to a different block.
+fn transfers_reorged(old_invoice: &Invoice, new_invoice: &Invoice, fork_height: u64) -> bool {
+    old_invoice
+        .transfers
+        .iter()
+        .filter(|transfer| {
+            transfer
+                .height
+                .map_or(false, |height| height >= fork_height)
+        })
+        .any(|transfer| !new_invoice.transfers.contains(transfer))
+}
+
+/// Determine the block cache height to resume scanning from, given the
+/// `checkpoint` saved by the last scan before the scanner stopped.
+///
+/// Scanning resumes from the checkpoint if its block is still part of the
+/// blockchain. Otherwise, the blockchain was reorganized while the scanner was
+/// stopped, and scanning resumes `reorg_depth` blocks below the checkpoint so
+/// that the reorganized blocks are scanned again.
+pub(crate) async fn resume_height(
+    rpc_client: &RpcClient,
+    checkpoint: Checkpoint,
+    daemon_height: u64,
+    reorg_depth: usize,
+) -> Result<u64, RpcError> {
+    if checkpoint.height < daemon_height {
+        let (block_id, _) = rpc_client.block(checkpoint.height).await?;
+        if block_id.to_bytes() == checkpoint.block_id {
+            info!(
+                "Resuming from last block scanned: {} ({})",
+                checkpoint.height,
+                hex::encode(checkpoint.block_id)
+            );
+            return Ok(checkpoint.height);
+        }
+    }
+
+    let height = checkpoint.height.saturating_sub(reorg_depth as u64);
+    warn!(
+        "Last block scanned ({}, at height {}) is no longer part of the blockchain. A reorg likely occurred while the scanner was stopped. Resuming from height {}",
+        hex::encode(checkpoint.block_id),
+        checkpoint.height,
+        height
+    );
+    Ok(height)
+}
+
+/// Update the block and txpool caches. If `skip_ahead` is `true`, the block
+/// cache skips ahead to the blockchain tip rather than advancing one block.
+///
+/// Returns the number of blocks updated, and the transactions newly added to
+/// the txpool.
+pub(crate) async fn update_caches<E>(
+    block_cache: &Mutex<BlockCache>,
+    txpool_cache: &Mutex<TxpoolCache>,
+    skip_ahead: bool,
+) -> Result<(usize, Vec<CompactTransaction>), AcceptXmrError<E>> {
+    // Update block cache.
+    let mut block_cache = block_cache.lock().await;
+    let blocks_updated = if skip_ahead {
+        block_cache.skip_ahead::<E>().await?
+    } else {
+        block_cache.update::<E>().await?
+    };
+
+    // Update txpool.
+    let mut txpool_cache = txpool_cache.lock().await;
+    let new_transactions = txpool_cache.update::<E>().await?;
+
+    Ok((blocks_updated, new_transactions))
+}
+
 pub(crate) struct ScannerHandle<S: InvoiceStorage>(
     JoinHandle<Result<(), AcceptXmrError<S::Error>>>,
 );
@@ -407,7 +837,453 @@ impl<S: InvoiceStorage> From<JoinHandle<Result<(), AcceptXmrError<S::Error>>>>
     }
 }
 
+/// Decides when the scanning thread scans next, and whether to fetch new
+/// blocks and transactions from the daemon first.
+///
+/// Scans happen once every scan interval, fetching from the daemon each time.
+/// If subscribed to the daemon's ZMQ notifications, the daemon is instead
+/// only queried when it announces a new block or txpool transaction (which
+/// triggers a scan right away), or every [`ZMQ_POLL_INTERVAL`] in case
+/// notifications are missed. Scans in between only check the cached blocks
+/// and transactions, for new invoices. Whenever the ZMQ connection is down,
+/// the daemon is polled every scan interval as usual.
+pub(crate) struct ScanTimer {
+    interval: Interval,
+    #[cfg(feature = "zmq")]
+    zmq_notifier: Option<ZmqNotifier>,
+    /// When the daemon was last queried.
+    #[cfg(feature = "zmq")]
+    last_fetch: time::Instant,
+}
+
+impl ScanTimer {
+    /// Create a scan timer, subscribing to ZMQ notifications at `zmq_url` if
+    /// given. Must be called from within a tokio runtime.
+    #[cfg_attr(not(feature = "zmq"), allow(unused_variables))]
+    pub fn new(scan_interval: Duration, zmq_url: Option<&str>) -> ScanTimer {
+        #[cfg(feature = "zmq")]
+        let zmq_notifier = zmq_url.and_then(|url| {
+            ZmqNotifier::start(url)
+                .map_err(|e| {
+                    error!(
+                        "Failed to subscribe to ZMQ notifications from {url}, polling instead: {e}"
+                    );
+                })
+                .ok()
+        });
+        #[cfg_attr(not(feature = "zmq"), allow(unused_mut))]
+        let mut interval = time::interval(scan_interval);
+        // Notifications interrupt the interval, so don't try to make up for
+        // ticks missed meanwhile.
+        #[cfg(feature = "zmq")]
+        if zmq_notifier.is_some() {
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        }
+        ScanTimer {
+            interval,
+            #[cfg(feature = "zmq")]
+            zmq_notifier,
+            #[cfg(feature = "zmq")]
+            last_fetch: time::Instant::now(),
+        }
+    }
+
+    /// Wait until the next scan is due. Returns `true` if new blocks and
+    /// transactions should be fetched from the daemon before scanning.
+    pub async fn tick(&mut self) -> bool {
+        #[cfg(feature = "zmq")]
+        if let Some(zmq_notifier) = self.zmq_notifier.as_ref().filter(|n| n.connected()) {
+            tokio::select! {
+                () = zmq_notifier.notified() => {}
+                _ = self.interval.tick() => {}
+            }
+            if zmq_notifier.take_notification() || self.last_fetch.elapsed() >= ZMQ_POLL_INTERVAL {
+                self.last_fetch = time::Instant::now();
+                return true;
+            }
+            return false;
+        }
+        self.interval.tick().await;
+        #[cfg(feature = "zmq")]
+        {
+            self.last_fetch = time::Instant::now();
+        }
+        true
+    }
+}
+
 struct OwnedAmount {
     sub_index: SubIndex,
     amount: Amount,
+    /// Whether the amount was received in a miner transaction.
+    coinbase: bool,
+    unlock_time: u64,
+}
+
+impl OwnedAmount {
+    fn to_transfer(&self, height: Option<u64>) -> Transfer {
+        let mut transfer = Transfer::new(self.amount.as_pico(), height);
+        transfer.coinbase = self.coinbase;
+        transfer.unlock_time = self.unlock_time;
+        transfer
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use httpmock::MockServer;
+    use monero::{
+        blockdata::transaction::{OwnedTxOut, TxOutTarget},
+        cryptonote::{
+            hash::Hashable,
+            onetime_key::{KeyGenerator, SubKeyChecker},
+        },
+        Transaction, VarInt,
+    };
+
+    use super::{block_transfers, resume_height, updated_invoice, OutputChecker};
+    use crate::{
+        caching::{CachedBlock, CompactTransaction, OutputStats},
+        invoice::Transfer,
+        rpc::RpcClient,
+        storage::Checkpoint,
+        Invoice, SubIndex,
+    };
+
+    const PRIVATE_VIEW_KEY: &str =
+        "ad2093a5705b9f33e6f0f0c1bc1f5f639c756cdfc168c8f2ac6127ccbdab3a03";
+    const PRIMARY_ADDRESS: &str =
+        "4613YiHLM6JMH4zejMB2zJY5TwQCxL8p65ufw8kBP5yxX9itmuGLqp1dS4tkVoTxjyH3aYhYNrtGHbQzJQP5bFus3KHVdmf";
+
+    /// Transactions of a block, followed by txpool transactions including a
+    /// payment to subaddress (1, 97).
+    fn transactions() -> Vec<monero::Transaction> {
+        [
+            "tests/rpc_resources/blocks/2477647/transactions_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment.json",
+        ]
+        .iter()
+        .flat_map(|path| {
+            let json: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            json["txs_as_hex"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|tx| {
+                    monero::consensus::deserialize(&hex::decode(tx.as_str().unwrap()).unwrap())
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn parallel_output_check_matches_single_threaded() {
+        let viewpair = monero::ViewPair {
+            view: monero::PrivateKey::from_str(PRIVATE_VIEW_KEY).unwrap(),
+            spend: monero::Address::from_str(PRIMARY_ADDRESS)
+                .unwrap()
+                .public_spend,
+        };
+        let sub_key_checker = SubKeyChecker::new(&viewpair, 1..2, 0..200);
+        let transactions: Vec<CompactTransaction> =
+            transactions().into_iter().map(Into::into).collect();
+
+        // Index of each transaction and output found, along with its subaddress.
+        let owned = |threads| {
+            OutputChecker::new(threads)
+                .unwrap()
+                .check(&transactions, &sub_key_checker)
+                .0
+                .into_iter()
+                .enumerate()
+                .flat_map(|(i, outputs)| {
+                    outputs
+                        .unwrap()
+                        .into_iter()
+                        .map(move |output| (i, output.index, SubIndex::from(output.sub_index)))
+                })
+                .collect::<Vec<_>>()
+        };
+        let single_threaded = owned(1);
+        assert!(single_threaded
+            .iter()
+            .any(|(_, _, sub_index)| *sub_index == SubIndex::new(1, 97)));
+        assert_eq!(owned(4), single_threaded);
+    }
+
+    /// Give the outputs of `tx` view tags, as in transactions created since
+    /// view tags were introduced. Only outputs owned by `sub_key_checker` get
+    /// matching view tags.
+    fn with_view_tags(tx: &Transaction, sub_key_checker: &SubKeyChecker<'_>) -> Transaction {
+        let owned: Vec<usize> = tx
+            .check_outputs_with(sub_key_checker)
+            .unwrap()
+            .iter()
+            .map(OwnedTxOut::index)
+            .collect();
+        let extra = tx.prefix().extra.try_parse();
+        let tx_pubkeys = extra
+            .tx_additional_pubkeys()
+            .unwrap_or_else(|| vec![extra.tx_pubkey().unwrap(); tx.prefix().outputs.len()]);
+
+        let mut tx = tx.clone();
+        for (i, output) in tx.prefix.outputs.iter_mut().enumerate() {
+            let TxOutTarget::ToKey { key } = output.target else {
+                continue;
+            };
+            let rv = KeyGenerator::from_key(sub_key_checker.keys, tx_pubkeys[i]).rv;
+            output.target = (0..=u8::MAX)
+                .map(|view_tag| TxOutTarget::ToTaggedKey { key, view_tag })
+                .find(|target| {
+                    target.check_view_tag(rv, u8::try_from(i).unwrap()) == owned.contains(&i)
+                })
+                .unwrap();
+        }
+        tx
+    }
+
+    /// Check compacted `transactions` using the view tag fast path, and make
+    /// sure the same outputs and amounts are found as with the full check of
+    /// the full transactions.
+    fn check_against_full_check(
+        transactions: &[Transaction],
+        sub_key_checker: &SubKeyChecker<'_>,
+    ) -> OutputStats {
+        let compact_transactions: Vec<CompactTransaction> =
+            transactions.iter().cloned().map(Into::into).collect();
+        let (owned_outputs, stats) = OutputChecker::new(1)
+            .unwrap()
+            .check(&compact_transactions, sub_key_checker);
+        for (tx, owned_outputs) in transactions.iter().zip(owned_outputs) {
+            let owned: Vec<_> = owned_outputs
+                .unwrap()
+                .iter()
+                .map(|output| (output.index, output.sub_index, output.amount))
+                .collect();
+            let expected: Vec<_> = tx
+                .check_outputs_with(sub_key_checker)
+                .unwrap()
+                .iter()
+                .map(|output| (output.index(), output.sub_index(), output.amount()))
+                .collect();
+            assert_eq!(owned, expected);
+        }
+        stats
+    }
+
+    #[test]
+    fn view_tags_rule_out_outputs() {
+        let viewpair = monero::ViewPair {
+            view: monero::PrivateKey::from_str(PRIVATE_VIEW_KEY).unwrap(),
+            spend: monero::Address::from_str(PRIMARY_ADDRESS)
+                .unwrap()
+                .public_spend,
+        };
+        let sub_key_checker = SubKeyChecker::new(&viewpair, 1..2, 0..200);
+        let untagged = transactions();
+        let outputs = untagged.iter().map(|tx| tx.prefix().outputs.len()).sum();
+
+        // Outputs without view tags can't be ruled out.
+        assert_eq!(
+            check_against_full_check(&untagged, &sub_key_checker),
+            OutputStats {
+                outputs,
+                view_tag_rejections: 0,
+            }
+        );
+
+        // Every output but those owned is ruled out by its view tag.
+        let owned: usize = untagged
+            .iter()
+            .map(|tx| tx.check_outputs_with(&sub_key_checker).unwrap().len())
+            .sum();
+        assert!(owned > 0);
+        let tagged: Vec<_> = untagged
+            .iter()
+            .map(|tx| with_view_tags(tx, &sub_key_checker))
+            .collect();
+        assert_eq!(
+            check_against_full_check(&tagged, &sub_key_checker),
+            OutputStats {
+                outputs,
+                view_tag_rejections: outputs - owned,
+            }
+        );
+    }
+
+    /// Hash of the transaction in block 2477657 paying subaddresses (1, 97) and
+    /// (1, 138).
+    const PAYMENT_TX: &str = "1c1fca679c78420ac28d3c3041144eef5ed9edd9f37d082ef4950f76f95d2b98";
+
+    /// Block 2477657, with its payment to subaddresses (1, 97) and (1, 138)
+    /// time locked until `unlock_time`.
+    fn block_with_time_locked_payment(unlock_time: u64) -> CachedBlock {
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("tests/rpc_resources/blocks/2477657/block.json").unwrap(),
+        )
+        .unwrap();
+        let block: monero::Block = monero::consensus::deserialize(
+            &hex::decode(json["result"]["blob"].as_str().unwrap()).unwrap(),
+        )
+        .unwrap();
+        let id = monero::Hash::from_str(json["result"]["block_header"]["hash"].as_str().unwrap())
+            .unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("tests/rpc_resources/blocks/2477657/transactions_0.json")
+                .unwrap(),
+        )
+        .unwrap();
+        let transactions = json["txs_as_hex"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tx| {
+                let mut tx: monero::Transaction =
+                    monero::consensus::deserialize(&hex::decode(tx.as_str().unwrap()).unwrap())
+                        .unwrap();
+                if tx.hash().as_bytes() == hex::decode(PAYMENT_TX).unwrap() {
+                    tx.prefix.unlock_time = VarInt(unlock_time);
+                }
+                tx
+            })
+            .collect();
+        CachedBlock::new(id, 2477657, block, transactions)
+    }
+
+    #[test]
+    fn time_locked_payment() {
+        let viewpair = monero::ViewPair {
+            view: monero::PrivateKey::from_str(PRIVATE_VIEW_KEY).unwrap(),
+            spend: monero::Address::from_str(PRIMARY_ADDRESS)
+                .unwrap()
+                .public_spend,
+        };
+        let sub_key_checker = SubKeyChecker::new(&viewpair, 1..2, 0..200);
+        let output_checker = OutputChecker::new(1).unwrap();
+
+        // Locked until a block height, or until a unix timestamp.
+        for (unlock_time, unlock_height) in [(2477700, 2477700), (1_900_000_000, 2477667)] {
+            let block = block_with_time_locked_payment(unlock_time);
+            let transfers =
+                block_transfers::<()>(&block, &output_checker, &sub_key_checker).unwrap();
+            let sub_indices: Vec<SubIndex> =
+                transfers.iter().map(|(sub_index, _)| *sub_index).collect();
+            assert_eq!(sub_indices, [SubIndex::new(1, 138), SubIndex::new(1, 97)]);
+            for (_, transfer) in transfers {
+                assert_eq!(transfer.amount(), 37419570);
+                assert_eq!(transfer.unlock_time(), unlock_time);
+                assert_eq!(transfer.unlock_height(), Some(unlock_height));
+            }
+        }
+    }
+
+    #[test]
+    fn becomes_spendable_once() {
+        let sub_index = SubIndex::new(0, 1);
+        let mut invoice = Invoice::new(
+            "testAddress".to_string(),
+            sub_index,
+            100,
+            10,
+            0,
+            100,
+            "test_description".to_string(),
+        );
+        invoice.current_height = 100;
+
+        // Paid at height 100, so spendable from height 110.
+        let transfers = [(sub_index, Transfer::new(10, Some(100)))];
+        let (mut invoice, publish, became_spendable) =
+            updated_invoice(&invoice, &transfers, 100, 100, 0).unwrap();
+        assert!(publish);
+        assert!(!became_spendable);
+
+        for height in 101..115 {
+            let (updated, _, became_spendable) =
+                updated_invoice(&invoice, &[], height, height, 0).unwrap();
+            assert_eq!(became_spendable, height == 109);
+            invoice = updated;
+        }
+        assert!(invoice.is_spendable());
+    }
+
+    /// Mock daemon serving block 2477656.
+    fn mock_daemon() -> (MockServer, RpcClient) {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/json_rpc").body(
+                r#"{"jsonrpc":"2.0","id":"0","method":"get_block","params":{"height":2477656}}"#,
+            );
+            then.status(200)
+                .header("content-type", "application/json")
+                .body_from_file("tests/rpc_resources/blocks/2477656/block.json");
+        });
+        let rpc_client = RpcClient::new(
+            server.url("").parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            None,
+            None,
+            None,
+            16,
+        );
+        (server, rpc_client)
+    }
+
+    fn block_id_2477656() -> [u8; 32] {
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("tests/rpc_resources/blocks/2477656/block.json").unwrap(),
+        )
+        .unwrap();
+        hex::decode(json["result"]["block_header"]["hash"].as_str().unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resume_from_checkpoint() {
+        let (_server, rpc_client) = mock_daemon();
+        let checkpoint = Checkpoint {
+            height: 2477656,
+            block_id: block_id_2477656(),
+        };
+
+        let height = resume_height(&rpc_client, checkpoint, 2477666, 5)
+            .await
+            .unwrap();
+        assert_eq!(height, 2477656);
+    }
+
+    #[tokio::test]
+    async fn resume_below_reorged_checkpoint() {
+        let (_server, rpc_client) = mock_daemon();
+
+        // The checkpoint's block was replaced while the scanner was stopped.
+        let checkpoint = Checkpoint {
+            height: 2477656,
+            block_id: [0; 32],
+        };
+        let height = resume_height(&rpc_client, checkpoint, 2477666, 5)
+            .await
+            .unwrap();
+        assert_eq!(height, 2477651);
+
+        // The blockchain no longer reaches the checkpoint's height.
+        let checkpoint = Checkpoint {
+            height: 2477670,
+            block_id: block_id_2477656(),
+        };
+        let height = resume_height(&rpc_client, checkpoint, 2477666, 5)
+            .await
+            .unwrap();
+        assert_eq!(height, 2477665);
+    }
 }
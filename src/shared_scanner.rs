@@ -0,0 +1,944 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    future::Future,
+    mem,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, Mutex as StdMutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use hyper::Uri;
+use log::{debug, error, info, warn};
+use monero::{
+    cryptonote::{onetime_key::SubKeyChecker, subaddress::Index},
+    PublicKey,
+};
+use tokio::{
+    join,
+    runtime::Runtime,
+    sync::{oneshot, Mutex},
+    time,
+};
+
+use crate::{
+    caching::{
+        sub_key_checker_for, subaddress_count, BlockCache, CacheSnapshot, CompactTransaction,
+        TxpoolCache,
+    },
+    payment_gateway::{
+        default_scan_threads, MessageToScanner, DEFAULT_BLOCK_CACHE_SIZE, DEFAULT_DAEMON,
+        DEFAULT_REORG_DEPTH, DEFAULT_RPC_CONCURRENCY, DEFAULT_RPC_CONNECTION_TIMEOUT,
+        DEFAULT_RPC_TOTAL_TIMEOUT, DEFAULT_SCAN_INTERVAL,
+    },
+    rpc::{RpcClient, RpcError},
+    scanner::{resume_height, update_caches, OutputChecker, ScanTimer, Scanner},
+    storage::{Checkpoint, InvoiceStorage},
+    AcceptXmrError, PaymentGateway, RescanTarget,
+};
+
+/// A blockchain scanner which can be shared by many
+/// [`PaymentGateway`](PaymentGateway)s, each with its own view key, invoice
+/// storage and subscribers.
+///
+/// Every block and txpool transaction is fetched from the daemon once, and then
+/// checked for outputs owned by each attached payment gateway. Payment gateways
+/// can be attached and detached while the scanner is running.
+///
+/// Attached payment gateways must not be [run](PaymentGateway::run)
+/// themselves.
+///
+/// # Examples
+///
+/// ```no_run
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// #
+/// use acceptxmr::{storage::stores::InMemory, PaymentGateway, SharedScanner};
+///
+/// let scanner = SharedScanner::builder()
+///     .daemon_url("http://example.com:18081".to_string())
+///     .build()?;
+///
+/// let payment_gateway = PaymentGateway::builder(
+///     "ad2093a5705b9f33e6f0f0c1bc1f5f639c756cdfc168c8f2ac6127ccbdab3a03".to_string(),
+///     "4613YiHLM6JMH4zejMB2zJY5TwQCxL8p65ufw8kBP5yxX9itmuGLqp1dS4tkVoTxjyH3aYhYNrtGHbQzJQP5bFus3KHVdmf".to_string(),
+///     InMemory::new(),
+/// )
+/// .build()?;
+///
+/// // Scan for payments to the payment gateway using the shared scanner.
+/// scanner.attach(&payment_gateway).await?;
+/// scanner.run().await?;
+/// #   Ok(())
+/// # }
+/// ```
+pub struct SharedScanner(Arc<SharedScannerInner>);
+
+type SharedScannerHandle = JoinHandle<Result<(), AcceptXmrError<Infallible>>>;
+
+struct SharedScannerInner {
+    rpc_client: RpcClient,
+    scan_interval: Duration,
+    /// URL of the daemon's ZMQ publisher, if subscribing to its notifications.
+    zmq_url: Option<String>,
+    /// Number of threads checking transactions for owned outputs.
+    scan_threads: usize,
+    /// Where to save the block and txpool caches, if they are saved to disk.
+    cache_path: Option<PathBuf>,
+    /// Number of recent block hashes tracked to find the fork point of reorgs.
+    reorg_depth: usize,
+    block_cache_height: Arc<AtomicU64>,
+    cached_daemon_height: Arc<AtomicU64>,
+    /// Payment gateways to scan for.
+    gateways: Arc<Mutex<Vec<Attachment>>>,
+    scanner_handle: StdMutex<Option<SharedScannerHandle>>,
+    /// Send commands to the scanning thread.
+    scanner_command_sender: (
+        StdMutex<Sender<MessageToScanner>>,
+        Arc<StdMutex<Receiver<MessageToScanner>>>,
+    ),
+}
+
+impl Clone for SharedScanner {
+    fn clone(&self) -> Self {
+        SharedScanner(self.0.clone())
+    }
+}
+
+impl SharedScanner {
+    /// Returns a builder used to create a new shared scanner.
+    #[must_use]
+    pub fn builder() -> SharedScannerBuilder {
+        SharedScannerBuilder::new()
+    }
+
+    /// Runs the shared scanner. This function spawns a new thread, which
+    /// periodically scans new blocks and transactions from the configured
+    /// daemon and updates pending [`Invoice`](crate::Invoice)s of all attached
+    /// payment gateways.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::Rpc`] error if there was an issue getting
+    ///   necessary data from the monero daemon while starting.
+    ///
+    /// * Returns an [`AcceptXmrError::AlreadyRunning`] error if the shared
+    ///   scanner is already running.
+    ///
+    /// * Returns an [`AcceptXmrError::Threading`] error if there was an error
+    ///   creating the scanning thread.
+    pub async fn run(&self) -> Result<(), AcceptXmrError<Infallible>> {
+        // Determine if the scanning thread is already running.
+        if self.is_running() {
+            return Err(AcceptXmrError::AlreadyRunning);
+        }
+
+        // Determine sensible initial height for block cache.
+        let daemon_height = self.0.rpc_client.daemon_height().await?;
+        // Attached payment gateways will need to use the new caches.
+        for attachment in self.attachments().await {
+            if let Some(gateway) = attachment.target.lock().await.as_mut() {
+                gateway.reset();
+            }
+        }
+        let cache_height = if let Some(h) = self.lowest_resume_height(daemon_height).await? {
+            info!("Checkpoints or pending invoices found in attached payment gateways. Resuming from last block scanned: {h}");
+            h
+        } else {
+            info!("No checkpoints or pending invoices found in attached payment gateways. Skipping to blockchain tip: {daemon_height}");
+            daemon_height.saturating_sub(1)
+        };
+        self.0
+            .block_cache_height
+            .store(cache_height, Ordering::Relaxed);
+        self.0
+            .cached_daemon_height
+            .store(daemon_height, Ordering::Relaxed);
+
+        // Initialize block cache and txpool cache, from the snapshot saved when
+        // the shared scanner last stopped if there is one.
+        debug!("Creating shared blockchain scanner");
+        let snapshot = self.0.cache_path.as_deref().and_then(CacheSnapshot::load);
+        let (block_cache, txpool_cache) = join!(
+            BlockCache::init::<Infallible>(
+                self.0.rpc_client.clone(),
+                DEFAULT_BLOCK_CACHE_SIZE,
+                self.0.reorg_depth,
+                self.0.block_cache_height.clone(),
+                self.0.cached_daemon_height.clone(),
+                snapshot.as_ref(),
+            ),
+            TxpoolCache::init::<Infallible>(self.0.rpc_client.clone(), snapshot.as_ref())
+        );
+        let caches = SharedCaches {
+            block_cache: Arc::new(Mutex::new(block_cache?)),
+            txpool_cache: Arc::new(Mutex::new(txpool_cache?)),
+            height: self.0.block_cache_height.clone(),
+            daemon_height: self.0.cached_daemon_height.clone(),
+            output_checker: OutputChecker::new(self.0.scan_threads)?,
+            cache_path: self.0.cache_path.clone(),
+        };
+
+        let scan_interval = self.0.scan_interval;
+        let zmq_url = self.0.zmq_url.clone();
+        let gateways = self.0.gateways.clone();
+        let command_receiver = self.0.scanner_command_sender.1.clone();
+
+        // Spawn the scanning thread.
+        info!("Starting shared blockchain scanner");
+        *self.0.scanner_handle.lock().unwrap_or_else(PoisonError::into_inner) = Some(thread::Builder::new()
+            .name("Shared Scanning Thread".to_string())
+            .spawn(move || -> Result<(), AcceptXmrError<Infallible>> {
+                // The thread needs a tokio runtime to process async functions.
+                let tokio_runtime = Runtime::new()?;
+                tokio_runtime.block_on(async move {
+                    // Scan for transactions once every scan_interval, or when the daemon
+                    // announces them.
+                    let mut scan_timer = ScanTimer::new(scan_interval, zmq_url.as_deref());
+                    let mut fetch = true;
+                    loop {
+                        // If we're received the stop signal, stop.
+                        match command_receiver.lock().unwrap_or_else(PoisonError::into_inner).try_recv() {
+                            Ok(MessageToScanner::Stop) => {
+                                info!("Shared scanner received stop signal. Stopping gracefully");
+                                break;
+                            }
+                            Err(TryRecvError::Empty) => {
+                            }
+                            Err(TryRecvError::Disconnected) => {
+                                error!("Shared scanner lost connection to its handle. Stopping gracefully.");
+                                break;
+                            }
+                        }
+                        // Scan!
+                        let (fetch_next, result) = join!(scan_timer.tick(), caches.scan(&gateways, fetch));
+                        fetch = fetch_next;
+                        if let Err(e) = result {
+                            error!("Shared scanner encountered an error while scanning for payments: {e}");
+                        }
+                    }
+                    caches.save().await;
+                });
+                Ok(())
+            })?);
+        debug!("Shared scanner started successfully");
+        Ok(())
+    }
+
+    /// Returns the earliest block that any attached payment gateway still needs
+    /// scanned, or `None` if none of them has a checkpoint or pending invoices.
+    async fn lowest_resume_height(
+        &self,
+        daemon_height: u64,
+    ) -> Result<Option<u64>, AcceptXmrError<Infallible>> {
+        let mut lowest_height = None;
+        for attachment in self.attachments().await {
+            let (invoice_height, checkpoint) = match attachment.target.lock().await.as_ref() {
+                Some(gateway) => (gateway.lowest_height(), gateway.checkpoint()),
+                None => continue,
+            };
+            let Some(height) = self
+                .gateway_resume_height(invoice_height, checkpoint, daemon_height)
+                .await?
+            else {
+                continue;
+            };
+            lowest_height = Some(lowest_height.map_or(height, |lowest: u64| lowest.min(height)));
+        }
+        Ok(lowest_height)
+    }
+
+    /// Returns the earliest block needed by a payment gateway, resuming from its
+    /// `checkpoint` if it has one, or from `invoice_height` where its pending
+    /// invoices start otherwise. Returns `None` if it has neither.
+    async fn gateway_resume_height(
+        &self,
+        invoice_height: Option<u64>,
+        checkpoint: Option<Checkpoint>,
+        daemon_height: u64,
+    ) -> Result<Option<u64>, RpcError> {
+        match (checkpoint, invoice_height) {
+            (Some(checkpoint), _) => Ok(Some(
+                resume_height(
+                    &self.0.rpc_client,
+                    checkpoint,
+                    daemon_height,
+                    self.0.reorg_depth,
+                )
+                .await?,
+            )),
+            (None, Some(h)) => Ok(Some(h.saturating_sub(1))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Returns the payment gateways currently attached.
+    async fn attachments(&self) -> Vec<Attachment> {
+        self.0.gateways.lock().await.clone()
+    }
+
+    /// Returns `true` if the shared scanner's scanning thread is running.
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        self.0
+            .scanner_handle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map_or(false, |handle| !handle.is_finished())
+    }
+
+    /// Stops the shared scanner, blocking until complete. If the shared scanner
+    /// is not running, this method does nothing. Attached payment gateways
+    /// remain attached, and will be scanned for again if the shared scanner is
+    /// restarted.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::StopSignal`] error if the shared scanner
+    ///   could not be stopped.
+    ///
+    /// * Returns an [`AcceptXmrError::ScanningThreadPanic`] error if the
+    ///   scanning thread exited with a panic.
+    ///
+    /// * If the scanning thread exited with an error, returns the error
+    ///   encountered.
+    pub fn stop(&self) -> Result<(), AcceptXmrError<Infallible>> {
+        let Some(thread) = self
+            .0
+            .scanner_handle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        else {
+            return Ok(());
+        };
+        if !thread.is_finished() {
+            self.0
+                .scanner_command_sender
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .send(MessageToScanner::Stop)
+                .map_err(|e| AcceptXmrError::StopSignal(e.to_string()))?;
+        }
+        match thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(AcceptXmrError::ScanningThreadPanic),
+        }
+    }
+
+    /// Attach a payment gateway to the shared scanner. Incoming payments to the
+    /// payment gateway's invoices will be detected by the shared scanner from
+    /// the next scan onwards, starting with the blocks already in the block
+    /// cache and the transactions already in the txpool cache.
+    ///
+    /// If the shared scanner is running and the payment gateway's pending
+    /// invoices need blocks older than those in the block cache, this method
+    /// waits for the payment gateway's first scan, then
+    /// [rescans](PaymentGateway::rescan) the older blocks for payments to
+    /// them.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [`AcceptXmrError::AlreadyRunning`] error if the payment
+    ///   gateway is already running, or is already attached to a shared
+    ///   scanner.
+    ///
+    /// * Returns an [`AcceptXmrError::Rpc`] error if the payment gateway's
+    ///   checkpoint could not be checked against the daemon. The payment
+    ///   gateway is not attached in this case.
+    ///
+    /// * Returns an error if the older blocks could not be rescanned. The
+    ///   payment gateway remains attached, and the blocks can be rescanned
+    ///   with [`PaymentGateway::rescan`].
+    pub async fn attach<S: InvoiceStorage + 'static>(
+        &self,
+        payment_gateway: &PaymentGateway<S>,
+    ) -> Result<(), AcceptXmrError<S::Error>> {
+        if payment_gateway.scanner_running()
+            || payment_gateway.attached.swap(true, Ordering::Relaxed)
+        {
+            return Err(AcceptXmrError::AlreadyRunning);
+        }
+        let mut gateway = AttachedGateway::new(payment_gateway.clone());
+        let mut backfill = None;
+        if self.is_running() {
+            let daemon_height = self.0.cached_daemon_height.load(Ordering::Relaxed);
+            // Blocks below the block cache won't be scanned for the payment
+            // gateway, so find where its pending invoices need scanning from.
+            // Without pending invoices, there is nothing to backfill.
+            let (invoice_height, checkpoint) = (gateway.lowest_height(), gateway.checkpoint());
+            let backfill_height = if invoice_height.is_some() {
+                match self
+                    .gateway_resume_height(invoice_height, checkpoint, daemon_height)
+                    .await
+                {
+                    Ok(height) => height,
+                    Err(e) => {
+                        // Leave the payment gateway free to be attached or run again.
+                        payment_gateway.attached.store(false, Ordering::Relaxed);
+                        return Err(e.into());
+                    }
+                }
+            } else {
+                None
+            };
+            // Make the shared scanner's heights available to the payment gateway
+            // right away, so that new invoices are created at the correct height.
+            payment_gateway.block_cache_height.store(
+                self.0.block_cache_height.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            payment_gateway
+                .cached_daemon_height
+                .store(daemon_height, Ordering::Relaxed);
+            // Transactions already in the txpool cache won't be returned by later
+            // updates, so they are scanned for the payment gateway separately.
+            gateway.scan_cached_txpool = true;
+            if let Some(height) = backfill_height {
+                let (sender, receiver) = oneshot::channel();
+                gateway.first_scan = Some(sender);
+                backfill = Some((height, receiver));
+            }
+        }
+        self.0.gateways.lock().await.push(Attachment {
+            id: gateway_id(payment_gateway),
+            target: Arc::new(Mutex::new(Some(Box::new(gateway)))),
+        });
+        debug!("Payment gateway attached to shared scanner");
+
+        if let Some((height, first_scan)) = backfill {
+            self.backfill(payment_gateway, height, first_scan).await?;
+        }
+        Ok(())
+    }
+
+    /// Rescan the blocks from `height` up to the lowest block in the block
+    /// cache at the payment gateway's first scan, for payments to its pending
+    /// invoices.
+    async fn backfill<S: InvoiceStorage + 'static>(
+        &self,
+        payment_gateway: &PaymentGateway<S>,
+        height: u64,
+        mut first_scan: oneshot::Receiver<Option<u64>>,
+    ) -> Result<(), AcceptXmrError<S::Error>> {
+        let lowest_cached = loop {
+            match time::timeout(self.0.scan_interval, &mut first_scan).await {
+                Ok(lowest_cached) => break lowest_cached.ok().flatten(),
+                // If the shared scanner stopped before the first scan, it will resume
+                // from the payment gateway's pending invoices when run again.
+                Err(_) if !self.is_running() => break None,
+                Err(_) => {}
+            }
+        };
+        let Some(lowest_cached) = lowest_cached.filter(|&lowest_cached| lowest_cached > height)
+        else {
+            return Ok(());
+        };
+
+        let targets: Vec<RescanTarget> = payment_gateway
+            .invoice_store
+            .lock()
+            .try_iter()
+            .map_err(AcceptXmrError::InvoiceStorage)?
+            .map(|invoice_or_err| {
+                invoice_or_err
+                    .map(|invoice| invoice.index())
+                    .map_err(AcceptXmrError::InvoiceStorage)
+            })
+            .collect::<Result<HashSet<_>, _>>()?
+            .into_iter()
+            .map(RescanTarget::Subaddress)
+            .collect();
+        info!(
+            "Attached payment gateway has pending invoices older than the block cache. Rescanning blocks {} to {}",
+            height,
+            lowest_cached - 1
+        );
+        payment_gateway
+            .rescan(height..=lowest_cached - 1, &targets)
+            .await?;
+        Ok(())
+    }
+
+    /// Detach a payment gateway from the shared scanner. The payment gateway
+    /// will no longer be scanned for, and may be run on its own or attached to
+    /// another shared scanner.
+    ///
+    /// Returns `false` if the payment gateway was not attached to this shared
+    /// scanner.
+    pub async fn detach<S: InvoiceStorage + 'static>(
+        &self,
+        payment_gateway: &PaymentGateway<S>,
+    ) -> bool {
+        let id = gateway_id(payment_gateway);
+        let attachment = {
+            let mut gateways = self.0.gateways.lock().await;
+            let Some(position) = gateways.iter().position(|attachment| attachment.id == id) else {
+                return false;
+            };
+            gateways.remove(position)
+        };
+        // Wait for any scan of the payment gateway in progress to finish.
+        attachment.target.lock().await.take();
+        payment_gateway.attached.store(false, Ordering::Relaxed);
+        debug!("Payment gateway detached from shared scanner");
+        true
+    }
+
+    /// Returns the number of payment gateways attached to the shared scanner.
+    pub async fn attached_count(&self) -> usize {
+        self.0.gateways.lock().await.len()
+    }
+
+    /// Returns the height of the shared scanner's block cache.
+    #[must_use]
+    pub fn cache_height(&self) -> u64 {
+        self.0.block_cache_height.load(Ordering::Relaxed)
+    }
+
+    /// Returns URL of configured daemon.
+    #[must_use]
+    pub fn daemon_url(&self) -> String {
+        self.0.rpc_client.url()
+    }
+}
+
+/// A builder for the shared scanner. Used to configure your desired monero
+/// daemon, scan interval, etc.
+pub struct SharedScannerBuilder {
+    daemon_url: String,
+    daemon_username: Option<String>,
+    daemon_password: Option<String>,
+    rpc_timeout: Duration,
+    rpc_connection_timeout: Duration,
+    rpc_concurrency: usize,
+    scan_interval: Duration,
+    zmq_url: Option<String>,
+    scan_threads: usize,
+    cache_path: Option<PathBuf>,
+    reorg_depth: usize,
+    seed: Option<u64>,
+}
+
+impl Default for SharedScannerBuilder {
+    fn default() -> Self {
+        SharedScannerBuilder::new()
+    }
+}
+
+impl SharedScannerBuilder {
+    /// Create a new shared scanner builder.
+    #[must_use]
+    pub fn new() -> SharedScannerBuilder {
+        SharedScannerBuilder {
+            daemon_url: DEFAULT_DAEMON.to_string(),
+            daemon_username: None,
+            daemon_password: None,
+            rpc_timeout: DEFAULT_RPC_TOTAL_TIMEOUT,
+            rpc_connection_timeout: DEFAULT_RPC_CONNECTION_TIMEOUT,
+            rpc_concurrency: DEFAULT_RPC_CONCURRENCY,
+            scan_interval: DEFAULT_SCAN_INTERVAL,
+            zmq_url: None,
+            scan_threads: default_scan_threads(),
+            cache_path: None,
+            reorg_depth: DEFAULT_REORG_DEPTH,
+            seed: None,
+        }
+    }
+
+    /// Set the url and port of your preferred monero daemon. Defaults to
+    /// [http://node.moneroworld.com:18089](http://node.moneroworld.com:18089).
+    #[must_use]
+    pub fn daemon_url(mut self, url: String) -> SharedScannerBuilder {
+        self.daemon_url = url;
+        self
+    }
+
+    /// If your preferred daemon requires a password, configure it here.
+    #[must_use]
+    pub fn daemon_login(mut self, username: String, password: String) -> SharedScannerBuilder {
+        self.daemon_username = Some(username);
+        self.daemon_password = Some(password);
+        self
+    }
+
+    /// Time before an remote procedure call times out. Defaults to 10 seconds.
+    #[must_use]
+    pub fn rpc_timeout(mut self, timeout: Duration) -> SharedScannerBuilder {
+        self.rpc_timeout = timeout;
+        self
+    }
+
+    /// Time before a remote procedure call times out while failing to connect.
+    /// Defaults to 5 seconds.
+    #[must_use]
+    pub fn rpc_connection_timeout(mut self, timeout: Duration) -> SharedScannerBuilder {
+        self.rpc_connection_timeout = timeout;
+        self
+    }
+
+    /// Maximum number of blocks, or batches of up to 100 transactions,
+    /// requested from the daemon at once. Defaults to 16.
+    #[must_use]
+    pub fn rpc_concurrency(mut self, requests: usize) -> SharedScannerBuilder {
+        self.rpc_concurrency = requests;
+        self
+    }
+
+    /// Set the minimum scan interval. New blocks and transactions will be
+    /// scanned for relevant outputs at most every `interval`. Defaults to 1
+    /// second.
+    #[must_use]
+    pub fn scan_interval(mut self, interval: Duration) -> SharedScannerBuilder {
+        self.scan_interval = interval;
+        self
+    }
+
+    /// Subscribe to the daemon's ZMQ notifications, published at `url`. See
+    /// [`PaymentGatewayBuilder::zmq_url`](crate::PaymentGatewayBuilder::zmq_url).
+    #[cfg(feature = "zmq")]
+    #[must_use]
+    pub fn zmq_url(mut self, url: String) -> SharedScannerBuilder {
+        self.zmq_url = Some(url);
+        self
+    }
+
+    /// Number of threads checking new blocks and txpool transactions for
+    /// payments to attached payment gateways. See
+    /// [`PaymentGatewayBuilder::scan_threads`](crate::PaymentGatewayBuilder::scan_threads).
+    /// Defaults to the number of CPU cores.
+    #[must_use]
+    pub fn scan_threads(mut self, threads: usize) -> SharedScannerBuilder {
+        self.scan_threads = threads;
+        self
+    }
+
+    /// File to save the shared block cache and txpool cache to, so that they
+    /// needn't all be fetched from the daemon again on
+    /// [`run`](SharedScanner::run). See
+    /// [`PaymentGatewayBuilder::cache_path`](crate::PaymentGatewayBuilder::cache_path).
+    /// By default, the caches are not saved.
+    #[must_use]
+    pub fn cache_path(mut self, path: String) -> SharedScannerBuilder {
+        self.cache_path = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Number of recent blocks whose hashes are tracked to detect blockchain
+    /// reorgs. See [`PaymentGatewayBuilder::reorg_depth`](crate::PaymentGatewayBuilder::reorg_depth).
+    /// Defaults to 60 blocks.
+    #[must_use]
+    pub fn reorg_depth(mut self, blocks: usize) -> SharedScannerBuilder {
+        self.reorg_depth = blocks;
+        self
+    }
+
+    /// Seed for random number generator. Use only for reproducible testing. Do
+    /// not set in a production environment.
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> SharedScannerBuilder {
+        warn!("Seed set to {seed}. Some operations intended to be random will be predictable.");
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Build the shared scanner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the internal RPC client cannot parse the provided
+    /// URL.
+    pub fn build(self) -> Result<SharedScanner, AcceptXmrError<Infallible>> {
+        let rpc_client = RpcClient::new(
+            self.daemon_url
+                .parse::<Uri>()
+                .map_err(|e| AcceptXmrError::Parse {
+                    datatype: "Uri",
+                    input: self.daemon_url,
+                    error: e.to_string(),
+                })?,
+            self.rpc_timeout,
+            self.rpc_connection_timeout,
+            self.daemon_username,
+            self.daemon_password,
+            self.seed,
+            self.rpc_concurrency,
+        );
+
+        let (scanner_cmd_tx, scanner_cmd_rx) = channel();
+        let scanner_command_sender = (
+            StdMutex::new(scanner_cmd_tx),
+            Arc::new(StdMutex::new(scanner_cmd_rx)),
+        );
+
+        Ok(SharedScanner(Arc::new(SharedScannerInner {
+            rpc_client,
+            scan_interval: self.scan_interval,
+            zmq_url: self.zmq_url,
+            scan_threads: self.scan_threads,
+            cache_path: self.cache_path,
+            reorg_depth: self.reorg_depth,
+            block_cache_height: Arc::new(AtomicU64::new(0)),
+            cached_daemon_height: Arc::new(AtomicU64::new(0)),
+            gateways: Arc::new(Mutex::new(Vec::new())),
+            scanner_handle: StdMutex::new(None),
+            scanner_command_sender,
+        })))
+    }
+}
+
+/// Block and txpool caches shared by all attached payment gateways.
+struct SharedCaches {
+    block_cache: Arc<Mutex<BlockCache>>,
+    txpool_cache: Arc<Mutex<TxpoolCache>>,
+    height: Arc<AtomicU64>,
+    daemon_height: Arc<AtomicU64>,
+    output_checker: OutputChecker,
+    /// Where to save the caches, if they are saved to disk.
+    cache_path: Option<PathBuf>,
+}
+
+impl SharedCaches {
+    /// Update the caches once if `fetch` is `true`, then scan them for each
+    /// attached payment gateway.
+    async fn scan(
+        &self,
+        gateways: &Mutex<Vec<Attachment>>,
+        fetch: bool,
+    ) -> Result<(), AcceptXmrError<Infallible>> {
+        // Payment gateways can be attached and detached while others are scanned.
+        let gateways = gateways.lock().await.clone();
+
+        let (blocks_updated, new_transactions) = if fetch {
+            // Skip ahead to blockchain tip if there are no pending invoices.
+            let mut skip_ahead = true;
+            for attachment in &gateways {
+                if attachment
+                    .target
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map_or(false, |gateway| !gateway.is_empty())
+                {
+                    skip_ahead = false;
+                    break;
+                }
+            }
+            let (blocks_updated, new_transactions) =
+                update_caches(&self.block_cache, &self.txpool_cache, skip_ahead).await?;
+            if blocks_updated > 0 {
+                self.save().await;
+            }
+            (blocks_updated, new_transactions)
+        } else {
+            // Any reorg found by the last update has been dealt with already.
+            self.block_cache.lock().await.reorg = None;
+            (0, Vec::new())
+        };
+
+        for attachment in &gateways {
+            if let Some(gateway) = attachment.target.lock().await.as_mut() {
+                gateway.scan(self, blocks_updated, &new_transactions).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the caches to disk, if configured to.
+    async fn save(&self) {
+        if let Some(path) = &self.cache_path {
+            CacheSnapshot::save(path, &self.block_cache, &self.txpool_cache).await;
+        }
+    }
+}
+
+/// A payment gateway attached to a [`SharedScanner`].
+#[derive(Clone)]
+struct Attachment {
+    /// Identifies the attached payment gateway.
+    id: usize,
+    /// The attached payment gateway, taken when it is detached.
+    target: Arc<Mutex<Option<Box<dyn ScanTarget>>>>,
+}
+
+/// A payment gateway attached to a [`SharedScanner`], with its storage type
+/// erased.
+trait ScanTarget: Send {
+    /// Returns `true` if the payment gateway has no pending invoices.
+    fn is_empty(&self) -> bool;
+    /// Returns the lowest height of the payment gateway's pending invoices.
+    fn lowest_height(&self) -> Option<u64>;
+    /// Returns the checkpoint saved by the payment gateway's last scan.
+    fn checkpoint(&self) -> Option<Checkpoint>;
+    /// Forget the caches used so far.
+    fn reset(&mut self);
+    /// Scan the shared caches for updates to the payment gateway's invoices.
+    fn scan<'a>(
+        &'a mut self,
+        caches: &'a SharedCaches,
+        blocks_updated: usize,
+        new_transactions: &'a [CompactTransaction],
+    ) -> Pin<Box<dyn Future<Output = ()> + 'a>>;
+}
+
+struct AttachedGateway<S: InvoiceStorage> {
+    payment_gateway: PaymentGateway<S>,
+    scanner: Option<Scanner<S>>,
+    /// Table of the sub key checker, kept between scans for efficient tx output
+    /// checking.
+    sub_key_table: HashMap<PublicKey, Index>,
+    /// Receives the height of the lowest block in the block cache once the
+    /// payment gateway has first been scanned for.
+    first_scan: Option<oneshot::Sender<Option<u64>>>,
+    /// Whether the transactions already in the txpool cache when the payment
+    /// gateway was attached still need to be scanned for it.
+    scan_cached_txpool: bool,
+}
+
+impl<S: InvoiceStorage> AttachedGateway<S> {
+    fn new(payment_gateway: PaymentGateway<S>) -> AttachedGateway<S> {
+        AttachedGateway {
+            payment_gateway,
+            scanner: None,
+            sub_key_table: HashMap::new(),
+            first_scan: None,
+            scan_cached_txpool: false,
+        }
+    }
+}
+
+impl<S: InvoiceStorage + 'static> ScanTarget for AttachedGateway<S> {
+    fn is_empty(&self) -> bool {
+        match self.payment_gateway.invoice_store.is_empty() {
+            Ok(empty) => empty,
+            Err(e) => {
+                error!(
+                    "Failed to check whether attached payment gateway has pending invoices: {e}"
+                );
+                false
+            }
+        }
+    }
+
+    fn lowest_height(&self) -> Option<u64> {
+        match self.payment_gateway.invoice_store.lowest_height() {
+            Ok(height) => height,
+            Err(e) => {
+                error!("Failed to retrieve lowest invoice height of attached payment gateway: {e}");
+                None
+            }
+        }
+    }
+
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        match self.payment_gateway.invoice_store.checkpoint() {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                error!("Failed to retrieve scanner checkpoint of attached payment gateway: {e}");
+                None
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.scanner = None;
+    }
+
+    fn scan<'a>(
+        &'a mut self,
+        caches: &'a SharedCaches,
+        blocks_updated: usize,
+        new_transactions: &'a [CompactTransaction],
+    ) -> Pin<Box<dyn Future<Output = ()> + 'a>> {
+        Box::pin(async move {
+            let payment_gateway = &self.payment_gateway;
+            payment_gateway
+                .block_cache_height
+                .store(caches.height.load(Ordering::Relaxed), Ordering::Relaxed);
+            payment_gateway.cached_daemon_height.store(
+                caches.daemon_height.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+
+            let scanner = self.scanner.get_or_insert_with(|| {
+                Scanner::with_caches(
+                    payment_gateway.invoice_store.clone(),
+                    caches.block_cache.clone(),
+                    caches.txpool_cache.clone(),
+                    payment_gateway.publisher.clone(),
+                    payment_gateway.dust_threshold,
+                    caches.output_checker.clone(),
+                )
+            });
+
+            // Update sub key checker if necessary.
+            let viewpair = &payment_gateway.viewpair;
+            let subaddress_ranges = &payment_gateway.subaddress_ranges;
+            let mut sub_key_checker = SubKeyChecker {
+                table: mem::take(&mut self.sub_key_table),
+                keys: viewpair,
+            };
+            if sub_key_checker.table.len() < subaddress_count(subaddress_ranges) {
+                sub_key_checker = sub_key_checker_for(viewpair, subaddress_ranges);
+            }
+
+            // The txpool cache includes the new transactions.
+            let mut cached_txpool = None;
+            if self.scan_cached_txpool {
+                match caches
+                    .txpool_cache
+                    .lock()
+                    .await
+                    .transactions::<Infallible>()
+                    .await
+                {
+                    Ok(transactions) => {
+                        cached_txpool = Some(transactions);
+                        self.scan_cached_txpool = false;
+                    }
+                    Err(e) => error!("Shared scanner failed to retrieve the txpool for an attached payment gateway: {e}"),
+                }
+            }
+            let new_transactions = cached_txpool.as_deref().unwrap_or(new_transactions);
+
+            if let Err(e) = scanner
+                .scan_caches(&sub_key_checker, blocks_updated, new_transactions)
+                .await
+            {
+                error!("Shared scanner encountered an error while scanning for payments to an attached payment gateway: {e}");
+            }
+            self.sub_key_table = sub_key_checker.table;
+
+            if let Some(first_scan) = self.first_scan.take() {
+                let lowest_cached = caches
+                    .block_cache
+                    .lock()
+                    .await
+                    .blocks
+                    .last()
+                    .map(|block| block.height);
+                // Attaching may have been cancelled since.
+                let _ = first_scan.send(lowest_cached);
+            }
+
+            // Issue the next billing period of recurring invoices.
+            if let Err(e) = payment_gateway.renew_recurring_invoices().await {
+                error!("Shared scanner encountered an error while renewing recurring invoices of an attached payment gateway: {e}");
+            }
+        })
+    }
+}
+
+/// Identifies a payment gateway by the address of its shared inner state.
+fn gateway_id<S: InvoiceStorage>(payment_gateway: &PaymentGateway<S>) -> usize {
+    Arc::as_ptr(&payment_gateway.0) as usize
+}
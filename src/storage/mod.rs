@@ -11,7 +11,9 @@ use std::{
     sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
 };
 
-use crate::{Invoice, InvoiceId, SubIndex};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{AcceptXmrError, Invoice, InvoiceId, SubIndex};
 
 /// The [`InvoiceStorage`] trait describes the storage layer for pending
 /// invoices. Consumers of this library can use one of the existing storage
@@ -103,6 +105,31 @@ pub trait InvoiceStorage: Send + Sync {
         Ok(self.try_iter()?.next().is_none())
     }
 
+    /// Retrieve the scanner's [`Checkpoint`], if one is stored. Scanning
+    /// resumes from the checkpoint, after making sure its block is still part
+    /// of the blockchain.
+    ///
+    /// The default implementation stores no checkpoint, in which case scanning
+    /// resumes from the [lowest height](InvoiceStorage::lowest_height) of the
+    /// invoices in storage instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the checkpoint could not be read.
+    fn checkpoint(&self) -> Result<Option<Checkpoint>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Store the scanner's [`Checkpoint`], replacing the previous one. The
+    /// default implementation does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the checkpoint could not be stored.
+    fn set_checkpoint(&mut self, _checkpoint: Checkpoint) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Flush all changes to disk. This method should be manually implemented
     /// for any storage layer that does not automatically flush on write. The
     /// default implementation does nothing.
@@ -115,11 +142,52 @@ pub trait InvoiceStorage: Send + Sync {
     }
 }
 
-pub(crate) struct Store<S: InvoiceStorage>(Arc<RwLock<S>>);
+/// The last block scanned for payments, stored so that scanning can resume from
+/// it after a restart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Height of the block.
+    pub height: u64,
+    /// ID (hash) of the block.
+    pub block_id: [u8; 32],
+}
+
+impl Checkpoint {
+    /// Encode the checkpoint as its big endian height followed by its block ID.
+    #[cfg(any(feature = "sled", feature = "sqlite"))]
+    pub(crate) fn to_bytes(self) -> [u8; 40] {
+        let mut bytes = [0; 40];
+        bytes[..8].copy_from_slice(&self.height.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.block_id);
+        bytes
+    }
+
+    /// Decode a checkpoint encoded by [`to_bytes`](Checkpoint::to_bytes).
+    #[cfg(any(feature = "sled", feature = "sqlite"))]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Checkpoint> {
+        let (height, block_id) = (bytes.get(..8)?, bytes.get(8..)?);
+        Some(Checkpoint {
+            height: u64::from_be_bytes(height.try_into().ok()?),
+            block_id: block_id.try_into().ok()?,
+        })
+    }
+}
+
+/// Shared handle to the invoice store. Also holds the lock taken while
+/// applying scan results to stored invoices.
+pub(crate) struct Store<S: InvoiceStorage>(Arc<RwLock<S>>, Arc<Mutex<()>>);
 
 impl<S: InvoiceStorage> Store<S> {
     pub fn new(store: S) -> Store<S> {
-        Store(Arc::new(RwLock::new(store)))
+        Store(Arc::new(RwLock::new(store)), Arc::new(Mutex::new(())))
+    }
+
+    /// Lock out other scan results until the guard is dropped. Scan results
+    /// are applied by reading the stored invoices, then writing the updated
+    /// invoices back, so results applied concurrently (e.g. by the scanner
+    /// and a rescan) could otherwise overwrite each other.
+    pub async fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.1.lock().await
     }
 
     pub fn insert(&self, invoice: Invoice) -> Result<(), S::Error> {
@@ -132,11 +200,41 @@ impl<S: InvoiceStorage> Store<S> {
         store.remove(invoice_id)
     }
 
-    pub fn update(&self, invoice: Invoice) -> Result<Option<Invoice>, S::Error> {
+    /// Update existing invoice in storage. Links to a successor are never
+    /// removed, so that an update prepared from an older copy of the invoice
+    /// (e.g. by the scanner) doesn't undo a concurrent reissue.
+    pub fn update(&self, mut invoice: Invoice) -> Result<Option<Invoice>, S::Error> {
         let mut store = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if invoice.successor.is_none() {
+            invoice.successor = store
+                .get(invoice.id())?
+                .and_then(|current| current.successor);
+        }
         store.update(invoice)
     }
 
+    /// Link the invoice with ID `invoice_id` to its `successor`, if `check`
+    /// accepts the stored invoice. The invoice is checked and updated under one
+    /// write lock, so that nothing can change it in between.
+    pub fn link_successor(
+        &self,
+        invoice_id: InvoiceId,
+        successor: InvoiceId,
+        check: impl FnOnce(&Invoice) -> Result<(), AcceptXmrError<S::Error>>,
+    ) -> Result<(), AcceptXmrError<S::Error>> {
+        let mut store = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        let mut invoice = store
+            .get(invoice_id)
+            .map_err(AcceptXmrError::InvoiceStorage)?
+            .ok_or(AcceptXmrError::InvoiceNotFound(invoice_id))?;
+        check(&invoice)?;
+        invoice.successor = Some(successor);
+        store
+            .update(invoice)
+            .map_err(AcceptXmrError::InvoiceStorage)?;
+        Ok(())
+    }
+
     pub fn get(&self, invoice_id: InvoiceId) -> Result<Option<Invoice>, S::Error> {
         let store = self.0.read().unwrap_or_else(PoisonError::into_inner);
         store.get(invoice_id)
@@ -164,6 +262,16 @@ impl<S: InvoiceStorage> Store<S> {
         store.is_empty()
     }
 
+    pub fn checkpoint(&self) -> Result<Option<Checkpoint>, S::Error> {
+        let store = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        store.checkpoint()
+    }
+
+    pub fn set_checkpoint(&self, checkpoint: Checkpoint) -> Result<(), S::Error> {
+        let mut store = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        store.set_checkpoint(checkpoint)
+    }
+
     pub fn flush(&self) -> Result<(), S::Error> {
         let store = self.0.read().unwrap_or_else(PoisonError::into_inner);
         store.flush()
@@ -172,7 +280,7 @@ impl<S: InvoiceStorage> Store<S> {
 
 impl<S: InvoiceStorage> Clone for Store<S> {
     fn clone(&self) -> Self {
-        Store(self.0.clone())
+        Store(self.0.clone(), self.1.clone())
     }
 }
 
@@ -187,7 +295,7 @@ mod test {
     use crate::{
         storage::{
             stores::{InMemory, Sled, Sqlite},
-            InvoiceStorage,
+            Checkpoint, InvoiceStorage,
         },
         Invoice, SubIndex,
     };
@@ -391,4 +499,33 @@ mod test {
         let mut iter = store.try_iter().unwrap();
         assert_eq!(iter.next().transpose().unwrap(), None);
     }
+
+    #[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+    #[test_case(InMemory::new())]
+    #[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+    fn checkpoint<'a, S, E, I>(mut store: S)
+    where
+        S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+        E: Debug + Display + Send,
+        I: Iterator,
+    {
+        assert_eq!(store.checkpoint().unwrap(), None);
+
+        let checkpoint = Checkpoint {
+            height: 123,
+            block_id: [1; 32],
+        };
+        store.set_checkpoint(checkpoint).unwrap();
+        assert_eq!(store.checkpoint().unwrap(), Some(checkpoint));
+
+        let checkpoint = Checkpoint {
+            height: 124,
+            block_id: [2; 32],
+        };
+        store.set_checkpoint(checkpoint).unwrap();
+        assert_eq!(store.checkpoint().unwrap(), Some(checkpoint));
+
+        // The checkpoint is not an invoice.
+        assert!(store.is_empty().unwrap());
+    }
 }
@@ -5,17 +5,26 @@ use std::collections::{
 
 use thiserror::Error;
 
-use crate::{storage::InvoiceStorage, Invoice, InvoiceId, SubIndex};
+use crate::{
+    storage::{Checkpoint, InvoiceStorage},
+    Invoice, InvoiceId, SubIndex,
+};
 
 /// In-memory store of pending invoices. Note that invoices stored in memory
 /// will not be recoverable on power loss.
-pub struct InMemory(BTreeMap<InvoiceId, Invoice>);
+pub struct InMemory {
+    invoices: BTreeMap<InvoiceId, Invoice>,
+    checkpoint: Option<Checkpoint>,
+}
 
 impl InMemory {
     /// Create a new in-memory invoice store.
     #[must_use]
     pub fn new() -> InMemory {
-        InMemory(BTreeMap::new())
+        InMemory {
+            invoices: BTreeMap::new(),
+            checkpoint: None,
+        }
     }
 }
 
@@ -30,40 +39,49 @@ impl InvoiceStorage for InMemory {
     type Iter<'a> = InMemoryIter<'a>;
 
     fn insert(&mut self, invoice: Invoice) -> Result<(), Self::Error> {
-        if self.0.contains_key(&invoice.id()) {
+        if self.invoices.contains_key(&invoice.id()) {
             return Err(InMemoryStorageError::DuplicateEntry);
         }
-        self.0.insert(invoice.id(), invoice);
+        self.invoices.insert(invoice.id(), invoice);
         Ok(())
     }
 
     fn remove(&mut self, invoice_id: InvoiceId) -> Result<Option<Invoice>, Self::Error> {
-        Ok(self.0.remove(&invoice_id))
+        Ok(self.invoices.remove(&invoice_id))
     }
 
     fn update(&mut self, invoice: Invoice) -> Result<Option<Invoice>, Self::Error> {
-        if let Entry::Occupied(mut entry) = self.0.entry(invoice.id()) {
+        if let Entry::Occupied(mut entry) = self.invoices.entry(invoice.id()) {
             return Ok(Some(entry.insert(invoice)));
         }
         Ok(None)
     }
 
     fn get(&self, invoice_id: InvoiceId) -> Result<Option<Invoice>, Self::Error> {
-        Ok(self.0.get(&invoice_id).cloned())
+        Ok(self.invoices.get(&invoice_id).cloned())
     }
 
     fn contains_sub_index(&self, sub_index: SubIndex) -> Result<bool, Self::Error> {
         Ok(self
-            .0
+            .invoices
             .range(InvoiceId::new(sub_index, 0)..)
             .next()
             .is_some())
     }
 
     fn try_iter(&self) -> Result<Self::Iter<'_>, InMemoryStorageError> {
-        let iter = self.0.values();
+        let iter = self.invoices.values();
         Ok(InMemoryIter(iter))
     }
+
+    fn checkpoint(&self) -> Result<Option<Checkpoint>, InMemoryStorageError> {
+        Ok(self.checkpoint)
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<(), InMemoryStorageError> {
+        self.checkpoint = Some(checkpoint);
+        Ok(())
+    }
 }
 
 pub struct InMemoryIter<'a>(btree_map::Values<'a, InvoiceId, Invoice>);
@@ -1,15 +1,25 @@
 use sled::IVec;
 use thiserror::Error;
 
-use crate::{storage::InvoiceStorage, Invoice, InvoiceId, SubIndex};
+use crate::{
+    storage::{Checkpoint, InvoiceStorage},
+    Invoice, InvoiceId, SubIndex,
+};
+
+/// Key of the scanner's checkpoint in the checkpoint tree.
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
 
 /// Sled database containing pending invoices. Note that [sled](sled) is still
 /// in beta.
-pub struct Sled(sled::Tree);
+pub struct Sled {
+    invoices: sled::Tree,
+    checkpoint: sled::Tree,
+}
 
 impl Sled {
     /// Open a [Sled](sled) database at the specified location, and use the
-    /// specified tree. Creates a new database if one does not exist.
+    /// specified tree. Creates a new database if one does not exist. The
+    /// scanner's checkpoint is kept in a second tree, named `{tree}_checkpoint`.
     ///
     /// # Errors
     ///
@@ -20,12 +30,16 @@ impl Sled {
             .path(path)
             .flush_every_ms(None)
             .open()?;
-        let tree = db.open_tree(tree)?;
+        let invoices = db.open_tree(tree)?;
+        let checkpoint = db.open_tree(format!("{tree}_checkpoint"))?;
 
         // Set merge operator to act as an update().
-        tree.set_merge_operator(Sled::update_merge);
+        invoices.set_merge_operator(Sled::update_merge);
 
-        Ok(Sled(tree))
+        Ok(Sled {
+            invoices,
+            checkpoint,
+        })
     }
 
     fn update_merge(_key: &[u8], old_value: Option<&[u8]>, new_value: &[u8]) -> Option<Vec<u8>> {
@@ -50,7 +64,10 @@ impl InvoiceStorage for Sled {
         let value = bincode::encode_to_vec(invoice, bincode::config::standard())?;
 
         // Insert the invoice into the database.
-        match self.0.compare_and_swap(key, None::<IVec>, Some(value))? {
+        match self
+            .invoices
+            .compare_and_swap(key, None::<IVec>, Some(value))?
+        {
             Ok(_) => Ok(()),
             Err(_) => Err(SledStorageError::DuplicateInvoiceId),
         }
@@ -60,7 +77,7 @@ impl InvoiceStorage for Sled {
         // Prepare key (invoice id).
         let key = bincode::encode_to_vec(invoice_id, bincode::config::standard())?;
 
-        let old = self.0.remove(key).transpose();
+        let old = self.invoices.remove(key).transpose();
         old.map(|ivec_or_err| {
             Ok(bincode::decode_from_slice(&ivec_or_err?, bincode::config::standard())?.0)
         })
@@ -75,7 +92,7 @@ impl InvoiceStorage for Sled {
         let new_ivec = bincode::encode_to_vec(invoice, bincode::config::standard())?;
 
         // Do the update using the merge operator configured.
-        let maybe_old = self.0.fetch_and_update(key, move |old| {
+        let maybe_old = self.invoices.fetch_and_update(key, move |old| {
             if old.is_some() {
                 // Clone is necessary because the closure may be called multiple times.
                 Some(new_ivec.clone())
@@ -96,7 +113,7 @@ impl InvoiceStorage for Sled {
         // Prepare key (invoice id).
         let key = bincode::encode_to_vec(invoice_id, bincode::config::standard())?;
 
-        let current = self.0.get(key).transpose();
+        let current = self.invoices.get(key).transpose();
         current
             .map(|ivec_or_err| {
                 Ok(bincode::decode_from_slice(&ivec_or_err?, bincode::config::standard())?.0)
@@ -108,11 +125,24 @@ impl InvoiceStorage for Sled {
         // Prepare key (invoice id).
         let key = bincode::encode_to_vec(sub_index, bincode::config::standard())?;
 
-        Ok(self.0.scan_prefix(key).next().is_some())
+        Ok(self.invoices.scan_prefix(key).next().is_some())
     }
 
     fn try_iter(&self) -> Result<Self::Iter<'_>, SledStorageError> {
-        Ok(SledIter(self.0.iter()))
+        Ok(SledIter(self.invoices.iter()))
+    }
+
+    fn checkpoint(&self) -> Result<Option<Checkpoint>, SledStorageError> {
+        self.checkpoint
+            .get(CHECKPOINT_KEY)?
+            .map(|value| Checkpoint::from_bytes(&value).ok_or(SledStorageError::InvalidCheckpoint))
+            .transpose()
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<(), SledStorageError> {
+        self.checkpoint
+            .insert(CHECKPOINT_KEY, &checkpoint.to_bytes()[..])?;
+        Ok(())
     }
 
     /// Flush all changes to disk.
@@ -121,12 +151,12 @@ impl InvoiceStorage for Sled {
     ///
     /// Returns an error if flush does not succeed.
     fn flush(&self) -> Result<(), SledStorageError> {
-        self.0.flush()?;
+        self.invoices.flush()?;
         Ok(())
     }
 
     fn is_empty(&self) -> Result<bool, SledStorageError> {
-        Ok(self.0.is_empty())
+        Ok(self.invoices.is_empty())
     }
 }
 
@@ -166,4 +196,7 @@ pub enum SledStorageError {
     /// Failed to deserialize an [`Invoice`].
     #[error("deserialization error: {0}")]
     Deserialize(#[from] bincode::error::DecodeError),
+    /// The stored scanner [`Checkpoint`] is malformed.
+    #[error("invalid scanner checkpoint")]
+    InvalidCheckpoint,
 }
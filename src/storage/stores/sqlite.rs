@@ -4,17 +4,23 @@ use log::{debug, trace, warn};
 use sqlite::{version, Connection, ConnectionWithFullMutex, CursorWithOwnership, State, Value};
 use thiserror::Error;
 
-use crate::{storage::InvoiceStorage, Invoice, InvoiceId, SubIndex};
+use crate::{
+    storage::{Checkpoint, InvoiceStorage},
+    Invoice, InvoiceId, SubIndex,
+};
 
 /// `SQLite` database containing pending invoices.
 pub struct Sqlite {
     db: ConnectionWithFullMutex,
     table: TableName,
+    checkpoint_table: TableName,
 }
 
 impl Sqlite {
     /// Open a [`SQLite`](sqlite) database at the specified location, and use
-    /// the specified table. Creates a new database if one does not exist.
+    /// the specified table. Creates a new database if one does not exist. The
+    /// scanner's checkpoint is kept in a second table, named
+    /// `{table}_checkpoint`.
     ///
     /// # Errors
     ///
@@ -36,9 +42,20 @@ impl Sqlite {
             );"
         ))?;
 
+        let checkpoint_table = TableName::new(&format!("{table}_checkpoint"));
+
+        db.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {checkpoint_table} (
+                id  INTEGER PRIMARY KEY CHECK (id = 0),
+                height  BLOB NOT NULL,
+                block_id  BLOB NOT NULL
+            );"
+        ))?;
+
         Ok(Sqlite {
             db,
             table: escaped_table,
+            checkpoint_table,
         })
     }
 }
@@ -242,6 +259,45 @@ impl InvoiceStorage for Sqlite {
             .prepare(format!("SELECT invoice FROM {}", self.table))?;
         Ok(SqliteIter(statement.into_iter()))
     }
+
+    fn checkpoint(&self) -> Result<Option<Checkpoint>, SqliteStorageError> {
+        let mut select_stmt = self.db.prepare(format!(
+            "SELECT height, block_id FROM {} WHERE id = 0",
+            self.checkpoint_table
+        ))?;
+
+        if select_stmt.next()? == State::Done {
+            return Ok(None);
+        }
+        let mut bytes = select_stmt.read::<Vec<u8>, _>("height")?;
+        bytes.extend(select_stmt.read::<Vec<u8>, _>("block_id")?);
+
+        Checkpoint::from_bytes(&bytes)
+            .map(Some)
+            .ok_or(SqliteStorageError::InvalidCheckpoint)
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: Checkpoint) -> Result<(), SqliteStorageError> {
+        let mut statement = self.db.prepare(format!(
+            "INSERT OR REPLACE INTO {} (id, height, block_id) VALUES (0, :height, :block_id);",
+            self.checkpoint_table
+        ))?;
+        statement.bind::<&[(_, Value)]>(
+            &[
+                // Cast to byte array is needed because `Value` doesn't support u64.
+                (":height", checkpoint.height.to_be_bytes()[..].into()),
+                (":block_id", checkpoint.block_id[..].into()),
+            ][..],
+        )?;
+
+        while let Ok(State::Row) = statement.next() {
+            warn!(
+                "Checkpoint update returned an unexpected row: {:?}",
+                statement.read::<Value, _>(0)?
+            );
+        }
+        Ok(())
+    }
 }
 
 pub struct SqliteIter<'a>(CursorWithOwnership<'a>);
@@ -296,13 +352,23 @@ pub enum SqliteStorageError {
     /// Failed to deserialize an [`Invoice`].
     #[error("Deserialization error: {0}")]
     Deserialize(#[from] bincode::error::DecodeError),
+    /// The stored scanner [`Checkpoint`] is malformed.
+    #[error("invalid scanner checkpoint")]
+    InvalidCheckpoint,
 }
 
 #[cfg(test)]
+#[allow(clippy::expect_used)]
 mod test {
+    use sqlite::Value;
     use test_case::test_case;
 
-    use super::TableName;
+    use super::{Sqlite, TableName};
+    use crate::{
+        invoice::{LegacyInvoice, LegacyTransfer},
+        storage::InvoiceStorage,
+        InvoiceId, SubIndex,
+    };
 
     #[test_case("" => "\"\"")]
     #[test_case("invoices" => "\"invoices\"")]
@@ -312,4 +378,68 @@ mod test {
     fn escape_table_name(table: &str) -> String {
         TableName::new(table).0
     }
+
+    #[test]
+    fn load_legacy_invoice() {
+        let mut store =
+            Sqlite::new(":memory:", "invoices").expect("failed to create sqlite storage layer");
+
+        // Store an invoice encoded before its encoding was versioned.
+        let legacy = LegacyInvoice {
+            address: "testAddress".to_string(),
+            index: SubIndex::new(0, 1),
+            creation_height: 100,
+            amount_requested: 10,
+            amount_paid: 5,
+            paid_height: None,
+            confirmations_required: 5,
+            current_height: 110,
+            expiration_height: 120,
+            transfers: vec![LegacyTransfer {
+                amount: 5,
+                height: Some(105),
+            }],
+            description: "test_description".to_string(),
+        };
+        let value = bincode::encode_to_vec(legacy, bincode::config::standard())
+            .expect("failed to encode legacy invoice");
+        let mut statement = store
+            .db
+            .prepare(format!(
+                "INSERT INTO {} (major_subindex, minor_subindex, creation_height, invoice)
+                VALUES (0, 1, :height, :invoice);",
+                store.table
+            ))
+            .expect("failed to prepare statement");
+        statement
+            .bind::<&[(_, Value)]>(
+                &[
+                    (":height", 100u64.to_be_bytes()[..].into()),
+                    (":invoice", value.into()),
+                ][..],
+            )
+            .expect("failed to bind values");
+        statement.next().expect("failed to insert legacy invoice");
+        drop(statement);
+
+        let invoice_id = InvoiceId::new(SubIndex::new(0, 1), 100);
+        let invoice = store
+            .get(invoice_id)
+            .expect("failed to get invoice")
+            .expect("invoice does not exist");
+        assert_eq!(invoice.amount_paid(), 5);
+        assert_eq!(invoice.expiration_height(), 120);
+        assert_eq!(invoice.transfers().len(), 1);
+
+        // Updating it stores it in the current format.
+        store
+            .update(invoice.clone())
+            .expect("failed to update invoice");
+        let invoices: Vec<_> = store
+            .try_iter()
+            .expect("failed to iterate invoices")
+            .collect::<Result<_, _>>()
+            .expect("failed to load invoices");
+        assert_eq!(invoices, vec![invoice]);
+    }
 }
@@ -0,0 +1,231 @@
+//! Notifications of new blocks and txpool transactions, published by the
+//! monero daemon over ZMQ.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use log::{debug, info, warn};
+use tokio::sync::Notify;
+use zmq::SocketEvent;
+
+/// Topics announcing new blocks on the main chain and new txpool transactions.
+const TOPICS: [&str; 2] = ["json-minimal-chain_main", "json-full-txpool_add"];
+/// The notification thread checks for the stop signal this often.
+const POLL_TIMEOUT_MS: i64 = 200;
+const MONITOR_ENDPOINT: &str = "inproc://zmq-notifier-monitor";
+
+/// Subscribes to the daemon's ZMQ notifications from a separate thread,
+/// waking the scanner whenever the daemon announces a new block or txpool
+/// transaction.
+pub(crate) struct ZmqNotifier {
+    notify: Arc<Notify>,
+    /// Whether a notification arrived since it was last taken.
+    pending: Arc<AtomicBool>,
+    connected: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl ZmqNotifier {
+    /// Subscribe to the ZMQ publisher at `url`, e.g. `tcp://127.0.0.1:18083`.
+    /// The connection is established in the background, and re-established if
+    /// lost.
+    pub fn start(url: &str) -> io::Result<ZmqNotifier> {
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::SUB)?;
+        for topic in TOPICS {
+            socket.set_subscribe(topic.as_bytes())?;
+        }
+        // Monitor the connection, so that the scanner knows when to fall back to
+        // polling.
+        socket.monitor(
+            MONITOR_ENDPOINT,
+            i32::from(SocketEvent::CONNECTED.to_raw() | SocketEvent::DISCONNECTED.to_raw()),
+        )?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(MONITOR_ENDPOINT)?;
+        socket.connect(url)?;
+
+        let notify = Arc::new(Notify::new());
+        let pending = Arc::new(AtomicBool::new(false));
+        let connected = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let (notify, pending, connected, stop) = (
+                notify.clone(),
+                pending.clone(),
+                connected.clone(),
+                stop.clone(),
+            );
+            let url = url.to_string();
+            thread::Builder::new()
+                .name("ZMQ Thread".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let (message_ready, event_ready) = {
+                            let mut items = [
+                                socket.as_poll_item(zmq::POLLIN),
+                                monitor.as_poll_item(zmq::POLLIN),
+                            ];
+                            if let Err(e) = zmq::poll(&mut items, POLL_TIMEOUT_MS) {
+                                warn!("Stopped receiving ZMQ notifications: {e}");
+                                break;
+                            }
+                            (items[0].is_readable(), items[1].is_readable())
+                        };
+                        if event_ready {
+                            handle_event(&monitor, &url, &connected);
+                        }
+                        if message_ready {
+                            if let Ok(message) = socket.recv_bytes(0) {
+                                let topic = message.split(|b| *b == b':').next().unwrap_or(&[]);
+                                debug!(
+                                    "Received ZMQ notification {}",
+                                    String::from_utf8_lossy(topic)
+                                );
+                                pending.store(true, Ordering::Relaxed);
+                                notify.notify_one();
+                            }
+                        }
+                    }
+                    connected.store(false, Ordering::Relaxed);
+                })?
+        };
+
+        Ok(ZmqNotifier {
+            notify,
+            pending,
+            connected,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Returns `true` if currently connected to the ZMQ publisher.
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the next notification. If a notification arrived while
+    /// nothing was waiting, returns immediately.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+
+    /// Returns `true` if a notification arrived since this was last called.
+    pub fn take_notification(&self) -> bool {
+        self.pending.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl Drop for ZmqNotifier {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Record connection changes reported by the socket monitor.
+fn handle_event(monitor: &zmq::Socket, url: &str, connected: &AtomicBool) {
+    let Ok(frames) = monitor.recv_multipart(0) else {
+        return;
+    };
+    // The first frame holds the event, followed by a 32 bit value.
+    let Some(event) = frames.first().and_then(|frame| frame.get(..2)) else {
+        return;
+    };
+    match SocketEvent::from_raw(u16::from_le_bytes([event[0], event[1]])) {
+        SocketEvent::CONNECTED => {
+            info!("Subscribed to ZMQ notifications from {url}");
+            connected.store(true, Ordering::Relaxed);
+        }
+        SocketEvent::DISCONNECTED => {
+            warn!("Lost connection to ZMQ publisher at {url}. Falling back to polling");
+            connected.store(false, Ordering::Relaxed);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::ZmqNotifier;
+
+    /// Wait up to `duration` for a notification.
+    async fn notification_within(notifier: &ZmqNotifier, duration: Duration) -> bool {
+        timeout(duration, async {
+            while !notifier.take_notification() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn notifies_on_subscribed_topics() {
+        let context = zmq::Context::new();
+        let publisher = context.socket(zmq::PUB).unwrap();
+        publisher.bind("tcp://127.0.0.1:*").unwrap();
+        let url = publisher.get_last_endpoint().unwrap().unwrap();
+
+        let notifier = ZmqNotifier::start(&url).unwrap();
+        timeout(Duration::from_secs(5), async {
+            while !notifier.connected() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Subscriptions take a moment to reach the publisher, so keep publishing
+        // until one arrives.
+        timeout(Duration::from_secs(5), async {
+            loop {
+                publisher
+                    .send(r#"json-minimal-chain_main:{"first_height":1}"#, 0)
+                    .unwrap();
+                if notification_within(&notifier, Duration::from_millis(50)).await {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        timeout(Duration::from_secs(1), notifier.notified())
+            .await
+            .unwrap();
+        // Take notifications of any extra messages published meanwhile.
+        sleep(Duration::from_millis(200)).await;
+        notifier.take_notification();
+
+        // Other topics are ignored.
+        publisher.send("json-minimal-txpool_add:[]", 0).unwrap();
+        assert!(!notification_within(&notifier, Duration::from_millis(500)).await);
+
+        publisher.send("json-full-txpool_add:[]", 0).unwrap();
+        assert!(notification_within(&notifier, Duration::from_secs(5)).await);
+
+        drop(publisher);
+        timeout(Duration::from_secs(5), async {
+            while notifier.connected() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
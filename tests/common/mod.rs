@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     fmt::{Debug, Display},
     fs,
-    ops::Deref,
+    ops::{Deref, RangeInclusive},
     sync::Mutex,
     time::Duration,
 };
@@ -53,7 +53,6 @@ pub struct MockInvoice {
     pub creation_height: u64,
     pub amount_requested: u64,
     pub amount_paid: u64,
-    pub paid_height: Option<u64>,
     pub confirmations_required: u64,
     pub current_height: u64,
     pub expiration_height: u64,
@@ -82,7 +81,6 @@ impl MockInvoice {
             creation_height,
             amount_requested,
             amount_paid: 0,
-            paid_height: None,
             confirmations_required,
             current_height: creation_height,
             expiration_height: creation_height + expires_in,
@@ -175,7 +173,7 @@ impl MockDaemon {
         mock_daemon
     }
 
-    pub fn mock_daemon_height(&self, height: u64) -> Mock {
+    pub fn mock_daemon_height(&self, height: u64) -> Mock<'_> {
         // Use mock ID to delete old daemon height mock.
         if let Some(id) = *self
             .daemon_height_id
@@ -229,7 +227,20 @@ impl MockDaemon {
         self.mock_transactions(request_path, response_path);
     }
 
-    pub fn mock_txpool(&self, path: &str) -> Mock {
+    /// Mock block 2477657 without the payment it contains, as a daemon serving
+    /// an incomplete block would.
+    pub fn mock_incomplete_2477657(&self) {
+        // Mock block requests.
+        let response_path = "tests/rpc_resources/blocks/2477657_incomplete/block.json";
+        self.mock_block(2477657, response_path);
+
+        // Mock block transaction requests.
+        let request_path = "tests/rpc_resources/blocks/2477657_incomplete/txs_hashes_0.json";
+        let response_path = "tests/rpc_resources/blocks/2477657_incomplete/transactions_0.json";
+        self.mock_transactions(request_path, response_path);
+    }
+
+    pub fn mock_txpool(&self, path: &str) -> Mock<'_> {
         // Use ID to delete old mock.
         if let Some(id) = *self
             .txpool_id
@@ -253,7 +264,7 @@ impl MockDaemon {
         mock
     }
 
-    pub fn mock_transactions(&self, request_path: &str, response_path: &str) -> Mock {
+    pub fn mock_transactions(&self, request_path: &str, response_path: &str) -> Mock<'_> {
         let when_body: Value = serde_json::from_str(
             &fs::read_to_string(request_path)
                 .expect("failed to read transaction request from file when preparing mock"),
@@ -303,7 +314,139 @@ impl MockDaemon {
             .insert(height, mock.id);
     }
 
-    pub fn mock_txpool_hashes(&self, response_path: &str) -> Mock {
+    /// Number of times the block at `height` has been requested.
+    pub fn block_requests(&self, height: u64) -> usize {
+        let id = *self
+            .block_ids
+            .lock()
+            .expect("PoisonError when reading block mock ID")
+            .get(&height)
+            .expect("block is not mocked");
+        Mock::new(id, self).hits()
+    }
+
+    /// Mock a request for the headers of the blocks at `heights`.
+    pub fn mock_block_headers_range(&self, heights: RangeInclusive<u64>) -> Mock<'_> {
+        self.mock_headers_range(heights, |_| false)
+    }
+
+    /// Mock a request for the headers of the blocks at `heights`, where the
+    /// blocks at `alt_heights` are replaced by the alternative chain mocked by
+    /// `mock_alt_chain`.
+    pub fn mock_alt_block_headers_range(
+        &self,
+        heights: RangeInclusive<u64>,
+        alt_heights: RangeInclusive<u64>,
+    ) -> Mock<'_> {
+        self.mock_headers_range(heights, |height| alt_heights.contains(&height))
+    }
+
+    /// Mock a request for the headers of the blocks at `heights`, replacing the
+    /// IDs of those which are `alt` by their IDs in the alternative chain.
+    fn mock_headers_range(
+        &self,
+        heights: RangeInclusive<u64>,
+        alt: impl Fn(u64) -> bool,
+    ) -> Mock<'_> {
+        let headers: Vec<Value> = heights
+            .clone()
+            .map(|height| {
+                let mut header = read_block(height)["result"]["block_header"].clone();
+                if alt(height) {
+                    header["hash"] = json!(alt_block_id(&header));
+                }
+                header
+            })
+            .collect();
+        self.mock(|when, then| {
+            when.path("/json_rpc").json_body(json!({
+                "jsonrpc": "2.0",
+                "id": "0",
+                "method": "get_block_headers_range",
+                "params": {
+                    "start_height": heights.start(),
+                    "end_height": heights.end()
+                }
+            }));
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(json!({
+                    "id": "0",
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "headers": headers,
+                        "status": "OK"
+                    }
+                }));
+        })
+    }
+
+    /// Mock an alternative chain replacing the blocks at `heights`. Each new
+    /// block holds the transactions of the block below the one it replaces, as
+    /// if they had all been mined one block later.
+    pub fn mock_alt_chain(&self, heights: RangeInclusive<u64>) {
+        let mut prev_id: Option<String> = None;
+        for height in heights {
+            let replaced = read_block(height);
+            let alt_id = alt_block_id(&replaced["result"]["block_header"]);
+
+            // Point the new block at the previous new block, or at the block below
+            // the fork.
+            let prev_id = prev_id.replace(alt_id.clone()).unwrap_or_else(|| {
+                replaced["result"]["block_header"]["prev_hash"]
+                    .as_str()
+                    .expect("block has no previous ID")
+                    .to_string()
+            });
+            let response = chained_block(read_block(height - 1), &alt_id, &prev_id);
+            self.mock_block_response(height, response);
+        }
+    }
+
+    /// Mock empty blocks at `heights`, extending the blockchain past the
+    /// highest block mocked by default. Each new block is a copy of block
+    /// 2477661, which holds no transactions.
+    pub fn mock_empty_blocks(&self, heights: RangeInclusive<u64>) {
+        let mut prev_id = read_block(heights.start() - 1)["result"]["block_header"]["hash"]
+            .as_str()
+            .expect("block has no ID")
+            .to_string();
+        for height in heights {
+            let id = format!("{height:064x}");
+            let response = chained_block(read_block(2477661), &id, &prev_id);
+            self.mock_block_response(height, response);
+            prev_id = id;
+        }
+    }
+
+    /// Mock a request for the block at `height`, responding with `response`.
+    fn mock_block_response(&self, height: u64, response: Value) {
+        // Use ID to delete old mock.
+        if let Some(id) = self
+            .block_ids
+            .lock()
+            .expect("PoisonError when reading block mock ID")
+            .get(&height)
+        {
+            Mock::new(*id, self).delete();
+        };
+        let mock = self.mock(|when, then| {
+            when.path("/json_rpc").body(
+                r#"{"jsonrpc":"2.0","id":"0","method":"get_block","params":{"height":"#.to_owned()
+                    + &height.to_string()
+                    + "}}",
+            );
+            then.status(200)
+                .header("content-type", "application/json")
+                .json_body(response);
+        });
+        self.block_ids
+            .lock()
+            .expect("PoisonError when writing block mock ID")
+            .insert(height, mock.id);
+    }
+
+    pub fn mock_txpool_hashes(&self, response_path: &str) -> Mock<'_> {
         // Use ID to delete old mock.
         if let Some(id) = *self
             .txpool_hashes_id
@@ -327,7 +470,7 @@ impl MockDaemon {
         mock
     }
 
-    pub fn mock_txpool_transactions(&self, request_path: &str, response_path: &str) -> Mock {
+    pub fn mock_txpool_transactions(&self, request_path: &str, response_path: &str) -> Mock<'_> {
         // Use ID to delete old mock.
         if let Some(id) = *self
             .txpool_transactions_id
@@ -399,3 +542,46 @@ where
         assert_eq!(update.index(), SubIndex::new(1, 97));
     })
 }
+
+/// Read the daemon's response to a request for the block at `height`.
+fn read_block(height: u64) -> Value {
+    let path = format!("tests/rpc_resources/blocks/{height}/block.json");
+    serde_json::from_str(&fs::read_to_string(path).expect("failed to read block"))
+        .expect("failed to parse block")
+}
+
+/// ID of the block replacing the block with the given `header` in the
+/// alternative chain mocked by `MockDaemon::mock_alt_chain`.
+fn alt_block_id(header: &Value) -> String {
+    let id = header["hash"].as_str().expect("block has no ID");
+    if id.starts_with("aa") {
+        "bb".to_string() + &id[2..]
+    } else {
+        "aa".to_string() + &id[2..]
+    }
+}
+
+/// Give the block in `response` the ID `id`, and point it at the previous block
+/// `prev_id`.
+fn chained_block(mut response: Value, id: &str, prev_id: &str) -> Value {
+    let result = &mut response["result"];
+    result["block_header"]["hash"] = json!(id);
+
+    // The previous block ID follows the major version, minor version and timestamp
+    // varints.
+    let blob = result["blob"].as_str().expect("block has no blob");
+    let mut offset = 0;
+    for _ in 0..3 {
+        while u8::from_str_radix(&blob[offset..offset + 2], 16).expect("failed to parse blob")
+            & 0x80
+            != 0
+        {
+            offset += 2;
+        }
+        offset += 2;
+    }
+    let blob = blob[..offset].to_string() + prev_id + &blob[offset + 64..];
+    result["blob"] = json!(blob);
+    result["block_header"]["prev_hash"] = json!(prev_id);
+    response
+}
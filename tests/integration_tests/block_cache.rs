@@ -1,5 +1,7 @@
 use std::{
     fmt::{Debug, Display},
+    ops::RangeInclusive,
+    path::Path,
     time::Duration,
 };
 
@@ -8,7 +10,7 @@ use acceptxmr::{
         stores::{InMemory, Sled, Sqlite},
         InvoiceStorage,
     },
-    PaymentGatewayBuilder, SubIndex,
+    GatewayEvent, PaymentGatewayBuilder, PaymentGatewayStatus, SubIndex,
 };
 use test_case::test_case;
 use tokio::runtime::Runtime;
@@ -150,7 +152,7 @@ where
             .await
             .expect_err("should not have received an update, but did");
 
-        mock_daemon.mock_daemon_height(24776659);
+        mock_daemon.mock_daemon_height(2477659);
 
         let update = subscriber
             .recv_timeout(Duration::from_millis(5000))
@@ -164,3 +166,535 @@ where
         expected.assert_eq(&update);
     })
 }
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn fix_deep_reorg<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        let mut events = payment_gateway.subscribe_events();
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(70000000, 2, 7, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Wait for the payment in block 2477657.
+        mock_daemon.mock_daemon_height(2477659);
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.current_height() == 2477659 {
+                assert_eq!(update.amount_paid(), 37419570);
+                break;
+            }
+        }
+
+        // Reorg 11 blocks deep, past the bottom of the block cache.
+        mock_daemon.mock_alt_chain(2477649..=2477659);
+        mock_daemon.mock_daemon_height(2477660);
+
+        // The payment is rolled back and found again one block later in the new
+        // chain.
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.current_height(), 2477660);
+        assert_eq!(update.amount_paid(), 37419570);
+        assert_eq!(update.transfers().len(), 1);
+        assert_eq!(update.transfers()[0].height(), Some(2477658));
+
+        // The reorg is reported, along with the invoice whose payment moved.
+        let event = events
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for gateway event")
+            .expect("event channel is closed");
+        let GatewayEvent::Reorg {
+            fork_height,
+            old_blocks,
+            new_blocks,
+            invoices,
+        } = event
+        else {
+            panic!("expected reorg event, got {event:?}");
+        };
+        assert_eq!(fork_height, 2477649);
+        assert_eq!(old_blocks.len(), 10);
+        assert_eq!(new_blocks.len(), 11);
+        assert!(old_blocks
+            .iter()
+            .zip(&new_blocks)
+            .all(|(old, new)| old != new));
+        assert_eq!(invoices, vec![invoice_id]);
+
+        // The fork point was found, so nothing critical happened.
+        events
+            .recv_timeout(Duration::from_millis(1000))
+            .await
+            .expect_err("should not have received an event, but did");
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn reorg_beyond_window<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon, only
+    // tracking the hashes of blocks in the block cache.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .reorg_depth(10)
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        let mut events = payment_gateway.subscribe_events();
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(70000000, 2, 7, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        mock_daemon.mock_daemon_height(2477659);
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.current_height() == 2477659 {
+                break;
+            }
+        }
+
+        // Reorg 11 blocks deep, past every tracked block hash.
+        mock_daemon.mock_alt_chain(2477649..=2477659);
+        mock_daemon.mock_daemon_height(2477660);
+
+        let event = events
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for gateway event")
+            .expect("event channel is closed");
+        assert!(matches!(
+            event,
+            GatewayEvent::Reorg {
+                fork_height: 2477648,
+                ref invoices,
+                ..
+            } if invoices == &vec![invoice_id]
+        ));
+        let event = events
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for gateway event")
+            .expect("event channel is closed");
+        assert_eq!(
+            event,
+            GatewayEvent::ReorgBeyondWindow {
+                rescanned_from: 2477648,
+                reorg_depth: 10,
+            }
+        );
+        assert_eq!(payment_gateway.cache_height(), 2477659);
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn catch_up<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(70000000, 2, 20, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Get initial update.
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.current_height(), 2477657);
+        assert!(matches!(
+            payment_gateway.status(),
+            PaymentGatewayStatus::Running
+        ));
+
+        // Fall far behind the blockchain. The blocks needed aren't available yet,
+        // so catching up stalls.
+        mock_daemon.mock_daemon_height(2477700);
+        tokio::time::timeout(Duration::from_millis(5000), async {
+            while !matches!(
+                payment_gateway.status(),
+                PaymentGatewayStatus::CatchingUp {
+                    scanned_height: 2477656,
+                    tip_height: 2477699,
+                }
+            ) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for gateway to report catching up");
+
+        // Catch up to the tip in a single batch.
+        mock_daemon.mock_daemon_height(2477666);
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        // Both payments, in blocks 2477657 and 2477662, are found at once.
+        assert_eq!(update.current_height(), 2477666);
+        assert_eq!(update.transfers().len(), 2);
+        assert_eq!(update.amount_paid(), 74839140);
+        assert_eq!(update.confirmations(), Some(4));
+        assert!(matches!(
+            payment_gateway.status(),
+            PaymentGatewayStatus::Running
+        ));
+    })
+}
+
+#[test]
+fn restore_cache_snapshot() {
+    // Setup.
+    init_logger();
+    let temp_dir = new_temp_dir();
+    let cache_path = format!("{temp_dir}/cache");
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let build_payment_gateway = || {
+        PaymentGatewayBuilder::new(
+            PRIVATE_VIEW_KEY.to_string(),
+            PRIMARY_ADDRESS.to_string(),
+            InMemory::new(),
+        )
+        .daemon_url(mock_daemon.url(""))
+        .cache_path(cache_path.clone())
+        .seed(1)
+        .build()
+        .expect("failed to build payment gateway")
+    };
+
+    // Run the payment gateway and stop it, saving the caches.
+    let payment_gateway = build_payment_gateway();
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        assert_eq!(payment_gateway.cache_height(), 2477656);
+        payment_gateway
+            .stop()
+            .expect("failed to stop payment gateway");
+    });
+    assert!(Path::new(&cache_path).exists());
+
+    // A block is mined while the payment gateway is stopped.
+    mock_daemon.mock_daemon_height(2477658);
+    let headers_mock = mock_daemon.mock_block_headers_range(2477648..=2477657);
+    let tracked_headers_mock = mock_daemon.mock_block_headers_range(2477647..=2477656);
+    let requests = |heights: RangeInclusive<u64>| {
+        heights
+            .map(|height| mock_daemon.block_requests(height))
+            .collect::<Vec<_>>()
+    };
+    let reused_block_requests = requests(2477648..=2477656);
+    let new_block_requests = mock_daemon.block_requests(2477657);
+
+    // Only the new block is fetched on restart.
+    let payment_gateway = build_payment_gateway();
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        assert_eq!(payment_gateway.cache_height(), 2477657);
+    });
+    headers_mock.assert();
+    tracked_headers_mock.assert();
+    assert_eq!(requests(2477648..=2477656), reused_block_requests);
+    assert_eq!(mock_daemon.block_requests(2477657), new_block_requests + 1);
+}
+
+#[test]
+fn restore_cache_snapshot_refetches_stale_blocks_only() {
+    // Setup.
+    init_logger();
+    let temp_dir = new_temp_dir();
+    let cache_path = format!("{temp_dir}/cache");
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let build_payment_gateway = || {
+        PaymentGatewayBuilder::new(
+            PRIVATE_VIEW_KEY.to_string(),
+            PRIMARY_ADDRESS.to_string(),
+            InMemory::new(),
+        )
+        .daemon_url(mock_daemon.url(""))
+        .cache_path(cache_path.clone())
+        .seed(1)
+        .build()
+        .expect("failed to build payment gateway")
+    };
+
+    // Run the payment gateway and stop it, saving the caches.
+    let payment_gateway = build_payment_gateway();
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        payment_gateway
+            .stop()
+            .expect("failed to stop payment gateway");
+    });
+
+    // While the payment gateway is stopped, a block is mined and the ID of a
+    // block in the middle of the cache changes.
+    mock_daemon.mock_daemon_height(2477658);
+    mock_daemon.mock_alt_block_headers_range(2477648..=2477657, 2477652..=2477652);
+    mock_daemon.mock_alt_block_headers_range(2477647..=2477656, 2477652..=2477652);
+    let requests = |heights: RangeInclusive<u64>| {
+        heights
+            .map(|height| mock_daemon.block_requests(height))
+            .collect::<Vec<_>>()
+    };
+    let block_requests = requests(2477648..=2477657);
+
+    // Only the changed block and the new block are fetched on restart.
+    let payment_gateway = build_payment_gateway();
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        assert_eq!(payment_gateway.cache_height(), 2477657);
+    });
+    let refetched: Vec<u64> = (2477648..=2477657)
+        .zip(requests(2477648..=2477657).iter().zip(&block_requests))
+        .filter(|(_, (after, before))| after > before)
+        .map(|(height, _)| height)
+        .collect();
+    assert_eq!(refetched, vec![2477652, 2477657]);
+}
+
+#[test]
+fn reorg_while_stopped() {
+    // Setup.
+    init_logger();
+    let temp_dir = new_temp_dir();
+    let cache_path = format!("{temp_dir}/cache");
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let build_payment_gateway = |store| {
+        PaymentGatewayBuilder::new(
+            PRIVATE_VIEW_KEY.to_string(),
+            PRIMARY_ADDRESS.to_string(),
+            store,
+        )
+        // Faster scan rate so the update is received sooner.
+        .scan_interval(Duration::from_millis(100))
+        .daemon_url(mock_daemon.url(""))
+        .cache_path(cache_path.clone())
+        .reorg_depth(2)
+        .account_index(1)
+        .seed(1)
+        .build()
+        .expect("failed to build payment gateway")
+    };
+
+    // Wait for the payment in block 2477657, then stop the payment gateway.
+    let store = Sled::new(&temp_dir, "tree").expect("failed to create sled storage layer");
+    let payment_gateway = build_payment_gateway(store);
+    let invoice_id = rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        let invoice_id = payment_gateway
+            .new_invoice(70000000, 2, 7, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+        mock_daemon.mock_daemon_height(2477659);
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.current_height() == 2477659 {
+                assert_eq!(update.amount_paid(), 37419570);
+                break;
+            }
+        }
+        payment_gateway
+            .stop()
+            .expect("failed to stop payment gateway");
+        invoice_id
+    });
+    drop(payment_gateway);
+
+    // Reorg 2 blocks deep while the payment gateway is stopped.
+    mock_daemon.mock_alt_chain(2477657..=2477658);
+    mock_daemon.mock_block_headers_range(2477647..=2477656);
+    let tracked_headers_mock =
+        mock_daemon.mock_alt_block_headers_range(2477649..=2477658, 2477657..=2477658);
+
+    // The reorg is found on restart, and reported along with the invoice whose
+    // payment moved.
+    let store = Sled::new(&temp_dir, "tree").expect("failed to open sled storage layer");
+    let payment_gateway = build_payment_gateway(store);
+    let mut events = payment_gateway.subscribe_events();
+    let mut subscriber = payment_gateway.subscribe_all();
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        tracked_headers_mock.assert();
+
+        let event = events
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for gateway event")
+            .expect("event channel is closed");
+        let GatewayEvent::Reorg {
+            fork_height,
+            old_blocks,
+            new_blocks,
+            invoices,
+        } = event
+        else {
+            panic!("expected reorg event, got {event:?}");
+        };
+        assert_eq!(fork_height, 2477657);
+        assert_eq!(old_blocks.len(), 2);
+        assert_eq!(new_blocks.len(), 2);
+        assert!(old_blocks
+            .iter()
+            .zip(&new_blocks)
+            .all(|(old, new)| old != new));
+        assert_eq!(invoices, vec![invoice_id]);
+
+        // The payment is found again one block later in the new chain.
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.amount_paid() > 0 {
+                assert_eq!(update.amount_paid(), 37419570);
+                assert_eq!(update.transfers().len(), 1);
+                assert_eq!(update.transfers()[0].height(), Some(2477658));
+                break;
+            }
+        }
+    });
+}
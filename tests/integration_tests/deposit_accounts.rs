@@ -0,0 +1,154 @@
+use std::{
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+use acceptxmr::{
+    storage::{
+        stores::{InMemory, Sled, Sqlite},
+        InvoiceStorage,
+    },
+    DepositAccount, PaymentGatewayBuilder, SubIndex,
+};
+use test_case::test_case;
+use tokio::runtime::Runtime;
+
+use crate::common::{init_logger, new_temp_dir, MockDaemon, PRIMARY_ADDRESS, PRIVATE_VIEW_KEY};
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn deposit_account_balance<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Each user gets a stable subaddress.
+        let alice = payment_gateway
+            .deposit_account("alice")
+            .expect("failed to create deposit account");
+        assert_eq!(alice.index(), SubIndex::new(0, 97));
+        assert_eq!(alice.user_id(), "alice");
+        let bob = payment_gateway
+            .deposit_account("bob")
+            .expect("failed to create deposit account");
+        assert_ne!(alice.index(), bob.index());
+        assert_eq!(
+            payment_gateway
+                .deposit_account("alice")
+                .expect("failed to retrieve deposit account")
+                .id(),
+            alice.id()
+        );
+        assert_eq!(
+            payment_gateway
+                .get_deposit_account("carol")
+                .expect("failed to retrieve deposit account"),
+            None
+        );
+        assert_eq!(
+            payment_gateway
+                .balance("alice", 0)
+                .expect("failed to retrieve balance"),
+            0
+        );
+
+        let mut subscriber = payment_gateway
+            .subscribe(alice.id())
+            .expect("deposit account does not exist");
+
+        // Deposit to alice's subaddress.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for deposit account update")
+            .expect("subscription channel is closed");
+        let alice = DepositAccount::try_from(update).expect("update is not a deposit account");
+        assert_eq!(alice.ledger().len(), 1);
+        assert_eq!(alice.ledger()[0].amount(), 1468383460);
+        assert_eq!(alice.ledger()[0].confirmations(), 0);
+        assert_eq!(alice.balance(0), 1468383460);
+        assert_eq!(alice.balance(1), 0);
+        assert_eq!(
+            payment_gateway
+                .balance("alice", 0)
+                .expect("failed to retrieve balance"),
+            1468383460
+        );
+        assert_eq!(
+            payment_gateway
+                .balance("bob", 0)
+                .expect("failed to retrieve balance"),
+            0
+        );
+    })
+}
+
+#[test]
+fn deposit_accounts_persist() {
+    // Setup.
+    init_logger();
+    let temp_dir = new_temp_dir();
+
+    let store = Sled::new(&temp_dir, "tree").expect("failed to create sled storage layer");
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    .build()
+    .expect("failed to build payment gateway");
+    let alice = payment_gateway
+        .deposit_account("alice")
+        .expect("failed to create deposit account");
+    drop(payment_gateway);
+
+    // A new payment gateway using the same storage finds the same deposit account.
+    let store = Sled::new(&temp_dir, "tree").expect("failed to create sled storage layer");
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    .build()
+    .expect("failed to build payment gateway");
+    assert_eq!(
+        payment_gateway
+            .get_deposit_account("alice")
+            .expect("failed to retrieve deposit account"),
+        Some(alice)
+    );
+}
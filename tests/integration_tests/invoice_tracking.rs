@@ -8,7 +8,7 @@ use acceptxmr::{
         stores::{InMemory, Sled, Sqlite},
         InvoiceStorage,
     },
-    PaymentGatewayBuilder, SubIndex,
+    AcceptXmrError, InvoiceId, PaymentGatewayBuilder, SubIndex,
 };
 use test_case::test_case;
 use tokio::runtime::Runtime;
@@ -259,7 +259,7 @@ where
 #[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
 #[test_case(InMemory::new())]
 #[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
-fn timelock_rejection<'a, S, E, I>(store: S)
+fn timelocked_payment<'a, S, E, I>(store: S)
 where
     S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
     E: Debug + Display + Send,
@@ -326,11 +326,24 @@ where
             "tests/rpc_resources/transactions/txs_with_payment_timelock.json",
         );
 
-        // There shouldn't be any update.
-        subscriber
+        // The time locked transfer is recorded, but doesn't pay the invoice.
+        let update = subscriber
             .recv_timeout(Duration::from_millis(5000))
             .await
-            .expect_err("timeout waiting for invoice update");
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.amount_paid(), 0);
+        assert_eq!(update.confirmations(), None);
+        assert!(!update.is_confirmed());
+        assert_eq!(update.transfers().len(), 1);
+        let transfer = update.transfers()[0];
+        assert!(transfer.is_time_locked());
+        assert_eq!(transfer.height(), None);
+        assert_eq!(transfer.unlock_time(), 2844976);
+
+        // It can't be spent until the block it is locked until.
+        assert!(!update.is_spendable());
+        assert_eq!(update.spendable_amount(), 0);
     })
 }
 
@@ -593,3 +606,662 @@ where
         assert!(height_mock.hits() > 0);
     })
 }
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn track_multiple_accounts<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .accounts([1])
+    // Each account mixes its index into the seed. This one makes both accounts
+    // start at minor index 97, which the payment fixtures pay.
+    .seed(470)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add an invoice to each account.
+        let invoice_id = payment_gateway
+            .new_invoice(1, 5, 10, "invoice 1".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber_1 = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+        let invoice_id = payment_gateway
+            .new_invoice_for_account(1, 37419570, 0, 10, "invoice 2".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber_2 = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Accounts which aren't tracked can't be used.
+        assert!(matches!(
+            payment_gateway.new_invoice_for_account(2, 1, 5, 10, "invoice 3".to_string()),
+            Err(AcceptXmrError::UnknownAccount(2))
+        ));
+
+        // Get initial updates.
+        let update = subscriber_1
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        let mut expected_1 = MockInvoice::new(
+            Some(update.address().to_string()),
+            SubIndex::new(0, 97),
+            2477657,
+            1,
+            5,
+            10,
+            "invoice 1".to_string(),
+        );
+        expected_1.assert_eq(&update);
+
+        let update = subscriber_2
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        let mut expected_2 = MockInvoice::new(
+            Some(update.address().to_string()),
+            SubIndex::new(1, 97),
+            2477657,
+            37419570,
+            0,
+            10,
+            "invoice 2".to_string(),
+        );
+        expected_2.assert_eq(&update);
+
+        // Pay invoice in account 0.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+
+        let update = subscriber_1
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        expected_1.amount_paid = 1468383460;
+        expected_1.confirmations = Some(0);
+        expected_1.assert_eq(&update);
+
+        // Pay invoice in account 1.
+        let _txpool_hashes_mock =
+            mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment.json");
+
+        let update = subscriber_2
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        expected_2.amount_paid = 37419570;
+        expected_2.confirmations = Some(0);
+        expected_2.is_confirmed = true;
+        expected_2.assert_eq(&update);
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn watch_address<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Watch an address.
+        let watched_id = payment_gateway
+            .watch_address("donations".to_string())
+            .expect("failed to watch address");
+        assert_eq!(watched_id.sub_index, SubIndex::new(0, 97));
+        let mut subscriber = payment_gateway
+            .subscribe(watched_id)
+            .expect("watched address does not exist");
+
+        // Watched addresses are only updated when a transfer is received.
+        assert!(subscriber
+            .recv_timeout(Duration::from_millis(1000))
+            .await
+            .is_err());
+
+        // Send a payment.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for watched address update")
+            .expect("subscription channel is closed");
+        assert!(update.is_watched());
+        assert!(!update.is_expired());
+        assert_eq!(update.amount_paid(), 1468383460);
+        assert_eq!(update.transfers().len(), 1);
+        assert_eq!(update.transfers()[0].amount(), 1468383460);
+        assert_eq!(update.transfers()[0].height(), None);
+        assert_eq!(update.description(), "donations");
+
+        // Watched addresses can be removed like invoices.
+        assert!(payment_gateway
+            .remove_invoice(watched_id)
+            .expect("failed to remove watched address")
+            .is_some());
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn reissue_invoice<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(2_000_000_000, 0, 1, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Underpay it.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.amount_paid() > 0 {
+                assert_eq!(update.amount_paid(), 1468383460);
+                break;
+            }
+        }
+
+        // It can't be reissued before it expires.
+        assert!(matches!(
+            payment_gateway.reissue_invoice(invoice_id, 0, 10, "early".to_string()),
+            Err(AcceptXmrError::NotReissuable(id)) if id == invoice_id
+        ));
+
+        // Let it expire.
+        mock_daemon.mock_daemon_height(2477658);
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.is_expired() {
+                break;
+            }
+        }
+
+        // Reissue it for the remaining amount.
+        let new_invoice_id = payment_gateway
+            .reissue_invoice(invoice_id, 0, 10, "reissued invoice".to_string())
+            .expect("failed to reissue invoice");
+        assert_ne!(new_invoice_id.sub_index, invoice_id.sub_index);
+        let new_invoice = payment_gateway
+            .get_invoice(new_invoice_id)
+            .expect("failed to retrieve invoice")
+            .expect("reissued invoice does not exist");
+        assert_eq!(new_invoice.amount_requested(), 2_000_000_000 - 1468383460);
+        assert_eq!(new_invoice.predecessor(), Some(invoice_id));
+        assert_eq!(new_invoice.successor(), None);
+
+        // Wait for a few scans, to make sure the link isn't lost.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let old_invoice = payment_gateway
+            .get_invoice(invoice_id)
+            .expect("failed to retrieve invoice")
+            .expect("old invoice does not exist");
+        assert_eq!(old_invoice.successor(), Some(new_invoice_id));
+        assert_eq!(old_invoice.predecessor(), None);
+
+        // Invoices can only be reissued once.
+        assert!(matches!(
+            payment_gateway.reissue_invoice(invoice_id, 0, 10, "again".to_string()),
+            Err(AcceptXmrError::AlreadyReissued { successor, .. }) if successor == new_invoice_id
+        ));
+        let unknown_id = InvoiceId::new(SubIndex::new(0, 1), 0);
+        assert!(matches!(
+            payment_gateway.reissue_invoice(unknown_id, 0, 10, "unknown".to_string()),
+            Err(AcceptXmrError::InvoiceNotFound(id)) if id == unknown_id
+        ));
+    })
+}
+
+#[test]
+fn reissue_paid_invoice() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Pay an invoice in full, but let it expire before the payment is mined.
+        let invoice_id = payment_gateway
+            .new_invoice(1, 0, 1, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+        mock_daemon.mock_daemon_height(2477658);
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.is_expired() && update.amount_paid() > 0 {
+                break;
+            }
+        }
+
+        // Invoices paid in full can't be reissued.
+        assert!(matches!(
+            payment_gateway.reissue_invoice(invoice_id, 0, 10, "reissued invoice".to_string()),
+            Err(AcceptXmrError::NotReissuable(id)) if id == invoice_id
+        ));
+        assert_eq!(
+            payment_gateway
+                .get_invoice(invoice_id)
+                .expect("failed to retrieve invoice")
+                .expect("invoice does not exist")
+                .successor(),
+            None
+        );
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn open_invoice<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add an open-amount invoice.
+        let invoice_id = payment_gateway
+            .new_open_invoice(None, 0, 10, "tip jar".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Get initial update.
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert!(update.is_open_amount());
+        assert_eq!(update.amount_requested(), 0);
+        assert_eq!(update.confirmations(), None);
+        assert!(!update.is_confirmed());
+        assert_eq!(update.uri(), format!("monero:{}", update.address()));
+
+        // A payment in the txpool doesn't pay it.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.amount_paid(), 1468383460);
+        assert_eq!(update.confirmations(), None);
+        assert!(!update.is_confirmed());
+        assert!(!update.is_expired());
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn open_invoice_paid_once_mined<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add an open-amount invoice.
+        let invoice_id = payment_gateway
+            .new_open_invoice(None, 2, 10, "tip jar".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Get initial update.
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.confirmations(), None);
+
+        // The payment arrives in the txpool, without paying it.
+        let _txpool_hashes_mock =
+            mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment.json");
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.amount_paid(), 37419570);
+        assert_eq!(update.confirmations(), None);
+        assert!(!update.is_confirmed());
+
+        // Once mined, it pays the invoice, which is confirmed one block later.
+        mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes.json");
+        mock_daemon.mock_daemon_height(2477658);
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.amount_paid(), 37419570);
+        assert_eq!(update.confirmations(), Some(1));
+        assert!(!update.is_confirmed());
+
+        mock_daemon.mock_daemon_height(2477659);
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.confirmations(), Some(2));
+        assert!(update.is_confirmed());
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn dust_threshold<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon, ignoring
+    // outputs smaller than 2 millineros.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .dust_threshold(2_000_000_000)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(1, 0, 10, "test invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Get initial update.
+        subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+
+        // Send an output below the dust threshold.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+
+        // Dust doesn't trigger an update.
+        assert!(subscriber
+            .recv_timeout(Duration::from_millis(1000))
+            .await
+            .is_err());
+
+        // But it is recorded.
+        let invoice = payment_gateway
+            .get_invoice(invoice_id)
+            .expect("failed to retrieve invoice")
+            .expect("invoice does not exist");
+        assert_eq!(invoice.dust().len(), 1);
+        assert_eq!(invoice.dust()[0].amount(), 1468383460);
+        assert!(invoice.transfers().is_empty());
+        assert_eq!(invoice.amount_paid(), 0);
+        assert!(!invoice.is_confirmed());
+        payment_gateway
+            .stop()
+            .expect("failed to stop payment gateway");
+    });
+
+    // Invoices can set their own threshold.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .dust_threshold(2_000_000_000)
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        let invoice_id = payment_gateway
+            .new_invoice_with_dust_threshold(1, 0, 10, 1_000_000_000, "test invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        assert_eq!(invoice_id.sub_index, SubIndex::new(0, 97));
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.dust_threshold(), Some(1_000_000_000));
+        assert_eq!(update.amount_paid(), 1468383460);
+        assert!(update.dust().is_empty());
+    })
+}
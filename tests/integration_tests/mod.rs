@@ -1,3 +1,8 @@
 mod block_cache;
+mod deposit_accounts;
 mod invoice_tracking;
+mod recurring_invoices;
+mod rescan;
 mod scanning_thread_management;
+mod shared_scanner;
+mod zmq_notifications;
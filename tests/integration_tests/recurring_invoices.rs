@@ -0,0 +1,258 @@
+use std::{
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+use acceptxmr::{
+    storage::{
+        stores::{InMemory, Sled, Sqlite},
+        InvoiceStorage,
+    },
+    AcceptXmrError, BillingPeriod, GatewayEvent, PaymentGatewayBuilder, SubIndex,
+};
+use test_case::test_case;
+use tokio::runtime::Runtime;
+
+use crate::common::{init_logger, new_temp_dir, MockDaemon, PRIMARY_ADDRESS, PRIVATE_VIEW_KEY};
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn recurring_invoice_in_blocks<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add a recurring invoice billed every 2 blocks, on the same subaddress.
+        let invoice_id = payment_gateway
+            .new_recurring_invoice(
+                1_000_000_000,
+                0,
+                BillingPeriod::Blocks(2),
+                true,
+                "subscription".to_string(),
+            )
+            .expect("failed to add new recurring invoice to payment gateway for tracking");
+        assert_eq!(invoice_id.sub_index, SubIndex::new(0, 97));
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        let invoice = payment_gateway
+            .get_invoice(invoice_id)
+            .expect("failed to retrieve invoice")
+            .expect("invoice does not exist");
+        let recurrence = invoice.recurrence().expect("invoice is not recurring");
+        assert_eq!(recurrence.period_index(), 0);
+        assert_eq!(recurrence.period_start(), 2477657);
+        assert_eq!(recurrence.period_end(), 2477659);
+        assert_eq!(invoice.expiration_height(), 2477659);
+
+        // Move past the end of the first billing period.
+        let mut events = payment_gateway.subscribe_events();
+        let height_mock = mock_daemon.mock_daemon_height(2477659);
+        let ended = loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.recurrence().map_or(false, |r| r.has_ended()) {
+                break update;
+            }
+        };
+        assert!(height_mock.hits() > 0);
+
+        // The first period went unpaid.
+        assert!(ended.is_expired());
+        assert_eq!(ended.amount_paid(), 0);
+        let next_id = ended.successor().expect("next billing period not issued");
+        assert_eq!(next_id.sub_index, invoice_id.sub_index);
+        assert_eq!(next_id.creation_height, 2477659);
+        let event = events
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for gateway event")
+            .expect("subscription channel is closed");
+        assert_eq!(
+            event,
+            GatewayEvent::BillingPeriodUnpaid {
+                invoice_id,
+                period_index: 0,
+                amount_paid: 0,
+                successor: next_id,
+            }
+        );
+
+        let next = payment_gateway
+            .get_invoice(next_id)
+            .expect("failed to retrieve invoice")
+            .expect("next billing period does not exist");
+        let recurrence = next.recurrence().expect("invoice is not recurring");
+        assert_eq!(recurrence.period_index(), 1);
+        assert_eq!(recurrence.period_start(), 2477659);
+        assert_eq!(recurrence.period_end(), 2477661);
+        assert!(!recurrence.has_ended());
+        assert_eq!(next.predecessor(), Some(invoice_id));
+        assert_eq!(next.amount_requested(), 1_000_000_000);
+        assert_eq!(next.description(), "subscription");
+
+        // Pay the second period.
+        let mut subscriber = payment_gateway
+            .subscribe(next_id)
+            .expect("invoice does not exist");
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.amount_paid() > 0 {
+                assert_eq!(update.amount_paid(), 1468383460);
+                assert!(update.is_confirmed());
+                break;
+            }
+        }
+
+        // The payment belongs to the second period only.
+        let ended = payment_gateway
+            .get_invoice(invoice_id)
+            .expect("failed to retrieve invoice")
+            .expect("old invoice does not exist");
+        assert_eq!(ended.amount_paid(), 0);
+
+        // Removing the ended period doesn't release the shared subaddress.
+        payment_gateway
+            .remove_invoice(invoice_id)
+            .expect("failed to remove invoice");
+        let other_id = payment_gateway
+            .new_invoice(1, 0, 10, "other".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        assert_ne!(other_id.sub_index, invoice_id.sub_index);
+    })
+}
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn recurring_invoice_in_time<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Billing periods can't be empty.
+        assert!(matches!(
+            payment_gateway.new_recurring_invoice(
+                1,
+                0,
+                BillingPeriod::Time(Duration::ZERO),
+                false,
+                "empty".to_string()
+            ),
+            Err(AcceptXmrError::EmptyBillingPeriod)
+        ));
+
+        // Add a recurring invoice billed every second, on fresh subaddresses.
+        let invoice_id = payment_gateway
+            .new_recurring_invoice(
+                1_000_000_000,
+                0,
+                BillingPeriod::Time(Duration::from_secs(1)),
+                false,
+                "subscription".to_string(),
+            )
+            .expect("failed to add new recurring invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Wait for the first billing period to end.
+        let ended = loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.recurrence().map_or(false, |r| r.has_ended()) {
+                break update;
+            }
+        };
+        assert!(ended.is_expired());
+        let recurrence = ended.recurrence().expect("invoice is not recurring");
+        assert_eq!(recurrence.period_end() - recurrence.period_start(), 1);
+
+        let next_id = ended.successor().expect("next billing period not issued");
+        assert_ne!(next_id.sub_index, invoice_id.sub_index);
+        let next = payment_gateway
+            .get_invoice(next_id)
+            .expect("failed to retrieve invoice")
+            .expect("next billing period does not exist");
+        let next_recurrence = next.recurrence().expect("invoice is not recurring");
+        assert_eq!(next_recurrence.period_index(), 1);
+        assert_eq!(next_recurrence.period_start(), recurrence.period_end());
+        assert_eq!(
+            next_recurrence.billing_period(),
+            BillingPeriod::Time(Duration::from_secs(1))
+        );
+        assert!(!next_recurrence.reuses_subaddress());
+        assert_eq!(next.predecessor(), Some(invoice_id));
+    })
+}
@@ -0,0 +1,144 @@
+use std::{
+    fmt::{Debug, Display},
+    time::Duration,
+};
+
+use acceptxmr::{
+    storage::{
+        stores::{InMemory, Sled, Sqlite},
+        InvoiceStorage,
+    },
+    AcceptXmrError, InvoiceId, PaymentGatewayBuilder, RescanTarget, SubIndex,
+};
+use test_case::test_case;
+use tokio::runtime::Runtime;
+
+use crate::common::{init_logger, new_temp_dir, MockDaemon, PRIMARY_ADDRESS, PRIVATE_VIEW_KEY};
+
+#[test_case(Sled::new(&new_temp_dir(), "tree").unwrap())]
+#[test_case(InMemory::new())]
+#[test_case(Sqlite::new(":memory:", "invoices").unwrap())]
+fn rescan_recovers_missed_payment<'a, S, E, I>(store: S)
+where
+    S: InvoiceStorage<Error = E, Iter<'a> = I> + 'static,
+    E: Debug + Display + Send,
+    I: Iterator,
+{
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    // Faster scan rate so the update is received sooner.
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(70000000, 2, 7, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        assert_eq!(invoice_id.sub_index, SubIndex::new(1, 97));
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // The daemon serves block 2477657 without the payment in it.
+        mock_daemon.mock_incomplete_2477657();
+        mock_daemon.mock_daemon_height(2477659);
+        loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            assert_eq!(update.amount_paid(), 0);
+            if update.current_height() == 2477659 {
+                break;
+            }
+        }
+
+        // Once the daemon serves the real blocks, a rescan finds the payment. Blocks
+        // which haven't been scanned yet are left to the scanner.
+        mock_daemon.mock_block(2477657, "tests/rpc_resources/blocks/2477657/block.json");
+        let report = payment_gateway
+            .rescan(2477650..=2477700, &[RescanTarget::Invoice(invoice_id)])
+            .await
+            .expect("failed to rescan");
+        assert_eq!(report.heights(), Some(&(2477650..=2477658)));
+        assert_eq!(report.invoices().len(), 1);
+        let rescanned = &report.invoices()[0];
+        assert_eq!(rescanned.invoice().id(), invoice_id);
+        assert_eq!(rescanned.added().len(), 1);
+        assert_eq!(rescanned.added()[0].amount(), 37419570);
+        assert_eq!(rescanned.added()[0].height(), Some(2477657));
+        assert!(rescanned.removed().is_empty());
+
+        // The update is saved and published.
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.amount_paid(), 37419570);
+        assert_eq!(
+            payment_gateway
+                .get_invoice(invoice_id)
+                .expect("failed to get invoice")
+                .expect("invoice does not exist")
+                .amount_paid(),
+            37419570
+        );
+
+        // Rescanning again, by subaddress this time, changes nothing.
+        let report = payment_gateway
+            .rescan(
+                2477657..=2477658,
+                &[RescanTarget::Subaddress(invoice_id.sub_index)],
+            )
+            .await
+            .expect("failed to rescan");
+        assert!(report.invoices().is_empty());
+    })
+}
+
+#[test]
+fn rescan_unknown_invoice() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+
+    let invoice_id = InvoiceId::new(SubIndex::new(0, 1), 2477657);
+    let result = rt
+        .block_on(payment_gateway.rescan(2477650..=2477656, &[RescanTarget::Invoice(invoice_id)]));
+    assert!(matches!(
+        result,
+        Err(AcceptXmrError::InvoiceNotFound(id)) if id == invoice_id
+    ));
+}
@@ -1,5 +1,8 @@
+use std::{fs, time::Duration};
+
 use acceptxmr::{
-    storage::stores::Sled, AcceptXmrError, PaymentGatewayBuilder, PaymentGatewayStatus,
+    storage::{stores::Sled, InvoiceStorage},
+    AcceptXmrError, PaymentGatewayBuilder, PaymentGatewayStatus,
 };
 use tokio::runtime::Runtime;
 
@@ -106,3 +109,161 @@ fn stop_payment_gateway() {
         assert!(payment_gateway.stop().is_ok());
     })
 }
+
+#[test]
+fn resume_from_checkpoint() {
+    // Setup.
+    init_logger();
+    let temp_dir = new_temp_dir();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let store = Sled::new(&temp_dir, "tree name").expect("failed to create sled storage layer.");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it, and wait for a scan.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        let invoice_id = payment_gateway
+            .new_invoice(1, 5, 10, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+        subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+
+        assert!(payment_gateway.stop().is_ok());
+    });
+    drop(payment_gateway);
+
+    // The last block scanned was saved.
+    let store = Sled::new(&temp_dir, "tree name").expect("failed to open sled storage layer.");
+    let block: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string("tests/rpc_resources/blocks/2477656/block.json")
+            .expect("failed to read block"),
+    )
+    .expect("failed to parse block");
+    let block_id = hex::decode(
+        block["result"]["block_header"]["hash"]
+            .as_str()
+            .expect("block has no hash"),
+    )
+    .expect("failed to decode block hash");
+    let checkpoint = store
+        .checkpoint()
+        .expect("failed to read checkpoint")
+        .expect("no checkpoint was saved");
+    assert_eq!(checkpoint.height, 2477656);
+    assert_eq!(checkpoint.block_id[..], block_id[..]);
+
+    // Resume from the checkpoint.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        assert_eq!(payment_gateway.cache_height(), checkpoint.height);
+    });
+}
+
+#[test]
+fn resume_from_checkpoint_without_invoices() {
+    // Setup.
+    init_logger();
+    let temp_dir = new_temp_dir();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    mock_daemon.mock_daemon_height(2477665);
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let store = Sled::new(&temp_dir, "tree name").expect("failed to create sled storage layer.");
+
+    // Create payment gateway pointing at temp directory and mock daemon.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it without any invoices, and wait for the last mocked block to be
+    // scanned.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        mock_daemon.mock_daemon_height(2477666);
+        tokio::time::timeout(Duration::from_millis(5000), async {
+            while payment_gateway.cache_height() < 2477665 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timeout waiting for scan");
+
+        assert!(payment_gateway.stop().is_ok());
+    });
+    drop(payment_gateway);
+
+    let store = Sled::new(&temp_dir, "tree name").expect("failed to open sled storage layer.");
+    assert_eq!(
+        store.lowest_height().expect("failed to read invoices"),
+        None
+    );
+    assert_eq!(
+        store
+            .checkpoint()
+            .expect("failed to read checkpoint")
+            .expect("no checkpoint was saved")
+            .height,
+        2477665
+    );
+
+    // The blockchain grew while the payment gateway was stopped. It resumes
+    // from the checkpoint instead of skipping to the blockchain tip, whose
+    // blocks the mock daemon doesn't have.
+    mock_daemon.mock_daemon_height(2477670);
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        store,
+    )
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+        assert_eq!(payment_gateway.cache_height(), 2477665);
+    });
+}
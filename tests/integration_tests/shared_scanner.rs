@@ -0,0 +1,382 @@
+use std::time::Duration;
+
+use acceptxmr::{
+    storage::stores::{InMemory, Sqlite},
+    AcceptXmrError, PaymentGatewayBuilder, SharedScanner, SubIndex,
+};
+use tokio::runtime::Runtime;
+
+use crate::common::{init_logger, MockDaemon, MockInvoice, PRIMARY_ADDRESS, PRIVATE_VIEW_KEY};
+
+#[test]
+fn shared_scanner_tracks_many_gateways() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create a shared scanner pointing at mock daemon.
+    let scanner = SharedScanner::builder()
+        // Faster scan rate so the update is received sooner.
+        .scan_interval(Duration::from_millis(100))
+        .daemon_url(mock_daemon.url(""))
+        .build()
+        .expect("failed to build shared scanner");
+
+    // Create two payment gateways with different storage layers.
+    let payment_gateway_1 = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+    let payment_gateway_2 = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        Sqlite::new(":memory:", "invoices").expect("failed to create sqlite storage layer"),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        // Attach the first payment gateway before running, and the second after.
+        scanner
+            .attach(&payment_gateway_1)
+            .await
+            .expect("failed to attach payment gateway");
+        scanner.run().await.expect("failed to run shared scanner");
+        scanner
+            .attach(&payment_gateway_2)
+            .await
+            .expect("failed to attach payment gateway");
+        assert_eq!(scanner.attached_count().await, 2);
+
+        // Add an invoice to each payment gateway.
+        let invoice_id = payment_gateway_1
+            .new_invoice(1, 5, 10, "invoice 1".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber_1 = payment_gateway_1
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+        let invoice_id = payment_gateway_2
+            .new_invoice(37419570, 0, 10, "invoice 2".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber_2 = payment_gateway_2
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Get initial updates.
+        let update = subscriber_1
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        let mut expected_1 = MockInvoice::new(
+            Some(update.address().to_string()),
+            SubIndex::new(0, 97),
+            2477657,
+            1,
+            5,
+            10,
+            "invoice 1".to_string(),
+        );
+        expected_1.assert_eq(&update);
+
+        let update = subscriber_2
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        let mut expected_2 = MockInvoice::new(
+            Some(update.address().to_string()),
+            SubIndex::new(1, 97),
+            2477657,
+            37419570,
+            0,
+            10,
+            "invoice 2".to_string(),
+        );
+        expected_2.assert_eq(&update);
+
+        // Pay invoice of the first payment gateway.
+        let _txpool_hashes_mock = mock_daemon
+            .mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment_account_0.json");
+        let _transactions_mock = mock_daemon.mock_transactions(
+            "tests/rpc_resources/transactions/hashes_with_payment_account_0.json",
+            "tests/rpc_resources/transactions/txs_with_payment_account_0.json",
+        );
+
+        let update = subscriber_1
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        expected_1.amount_paid = 1468383460;
+        expected_1.confirmations = Some(0);
+        expected_1.assert_eq(&update);
+
+        // Pay invoice of the second payment gateway.
+        let _txpool_hashes_mock =
+            mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment.json");
+
+        let update = subscriber_2
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        expected_2.amount_paid = 37419570;
+        expected_2.confirmations = Some(0);
+        expected_2.is_confirmed = true;
+        expected_2.assert_eq(&update);
+
+        scanner.stop().expect("failed to stop shared scanner");
+    })
+}
+
+#[test]
+fn attach_and_detach() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let scanner = SharedScanner::builder()
+        .daemon_url(mock_daemon.url(""))
+        .build()
+        .expect("failed to build shared scanner");
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        scanner.run().await.expect("failed to run shared scanner");
+        scanner
+            .attach(&payment_gateway)
+            .await
+            .expect("failed to attach payment gateway");
+
+        // An attached payment gateway can't be attached again or run on its own.
+        assert!(matches!(
+            scanner.attach(&payment_gateway).await,
+            Err(AcceptXmrError::AlreadyRunning)
+        ));
+        assert!(matches!(
+            payment_gateway.run().await,
+            Err(AcceptXmrError::AlreadyRunning)
+        ));
+
+        // Once detached, it can be run on its own.
+        assert!(scanner.detach(&payment_gateway).await);
+        assert!(!scanner.detach(&payment_gateway).await);
+        assert_eq!(scanner.attached_count().await, 0);
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // A running payment gateway can't be attached.
+        assert!(matches!(
+            scanner.attach(&payment_gateway).await,
+            Err(AcceptXmrError::AlreadyRunning)
+        ));
+    })
+}
+
+#[test]
+fn attach_backfills_blocks_below_cache() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let scanner = SharedScanner::builder()
+        // Faster scan rate so the update is received sooner.
+        .scan_interval(Duration::from_millis(100))
+        .daemon_url(mock_daemon.url(""))
+        .build()
+        .expect("failed to build shared scanner");
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        scanner.run().await.expect("failed to run shared scanner");
+
+        // Add an invoice, then detach the payment gateway before block 2477657 is
+        // scanned.
+        scanner
+            .attach(&payment_gateway)
+            .await
+            .expect("failed to attach payment gateway");
+        let invoice_id = payment_gateway
+            .new_invoice(74839140, 0, 20, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        assert!(scanner.detach(&payment_gateway).await);
+
+        // The shared scanner moves on until the payment in block 2477657 is no longer
+        // in its block cache.
+        mock_daemon.mock_empty_blocks(2477666..=2477667);
+        mock_daemon.mock_daemon_height(2477668);
+        while scanner.cache_height() < 2477667 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // Reattaching finds both payments: the one in block 2477657 by rescanning
+        // it, and the one in block 2477662 from the block cache.
+        scanner
+            .attach(&payment_gateway)
+            .await
+            .expect("failed to attach payment gateway");
+        let invoice = payment_gateway
+            .get_invoice(invoice_id)
+            .expect("failed to get invoice")
+            .expect("invoice does not exist");
+        assert_eq!(invoice.amount_paid(), 74839140);
+        assert_eq!(invoice.current_height(), 2477668);
+
+        scanner.stop().expect("failed to stop shared scanner");
+    })
+}
+
+#[test]
+fn resume_from_checkpoint_without_invoices() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    mock_daemon.mock_daemon_height(2477665);
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let scanner = SharedScanner::builder()
+        .scan_interval(Duration::from_millis(100))
+        .daemon_url(mock_daemon.url(""))
+        .build()
+        .expect("failed to build shared scanner");
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        // Scan up to the last mocked block for a payment gateway without any
+        // invoices.
+        scanner
+            .attach(&payment_gateway)
+            .await
+            .expect("failed to attach payment gateway");
+        scanner.run().await.expect("failed to run shared scanner");
+        mock_daemon.mock_daemon_height(2477666);
+        tokio::time::timeout(Duration::from_millis(5000), async {
+            while scanner.cache_height() < 2477665 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timeout waiting for scan");
+        scanner.stop().expect("failed to stop shared scanner");
+
+        // The blockchain grew while the shared scanner was stopped. It resumes
+        // from the payment gateway's checkpoint instead of skipping to the
+        // blockchain tip, whose blocks the mock daemon doesn't have.
+        mock_daemon.mock_daemon_height(2477670);
+        scanner.run().await.expect("failed to run shared scanner");
+        assert_eq!(scanner.cache_height(), 2477665);
+        assert_eq!(payment_gateway.cache_height(), 2477665);
+    })
+}
+
+#[test]
+fn attach_scans_cached_txpool() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    let scanner = SharedScanner::builder()
+        // Faster scan rate so the update is received sooner.
+        .scan_interval(Duration::from_millis(100))
+        .daemon_url(mock_daemon.url(""))
+        .build()
+        .expect("failed to build shared scanner");
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .daemon_url(mock_daemon.url(""))
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    rt.block_on(async {
+        scanner.run().await.expect("failed to run shared scanner");
+
+        // Add an invoice, then detach the payment gateway before it is paid.
+        scanner
+            .attach(&payment_gateway)
+            .await
+            .expect("failed to attach payment gateway");
+        let invoice_id = payment_gateway
+            .new_invoice(37419570, 0, 10, "invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+        assert!(scanner.detach(&payment_gateway).await);
+
+        // Wait until the shared scanner has cached the payment in the txpool. The
+        // txpool is only fetched again once the previous update has finished.
+        let txpool_hashes_mock =
+            mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment.json");
+        tokio::time::timeout(Duration::from_millis(5000), async {
+            while txpool_hashes_mock.hits() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timeout waiting for txpool update");
+
+        // The payment is no longer new to the shared scanner, but is found for the
+        // reattached payment gateway.
+        scanner
+            .attach(&payment_gateway)
+            .await
+            .expect("failed to attach payment gateway");
+        let update = loop {
+            let update = subscriber
+                .recv_timeout(Duration::from_millis(5000))
+                .await
+                .expect("timeout waiting for invoice update")
+                .expect("subscription channel is closed");
+            if update.amount_paid() > 0 {
+                break update;
+            }
+        };
+        assert_eq!(update.amount_paid(), 37419570);
+        assert_eq!(update.confirmations(), Some(0));
+
+        scanner.stop().expect("failed to stop shared scanner");
+    })
+}
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use acceptxmr::{storage::stores::InMemory, PaymentGatewayBuilder, SubIndex};
+use tokio::{runtime::Runtime, time::sleep};
+
+use crate::common::{init_logger, MockDaemon, MockInvoice, PRIMARY_ADDRESS, PRIVATE_VIEW_KEY};
+
+#[test]
+fn zmq_notification_triggers_scan() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Stand in for the daemon's ZMQ publisher.
+    let context = zmq::Context::new();
+    let publisher = context
+        .socket(zmq::PUB)
+        .expect("failed to create ZMQ publisher");
+    publisher
+        .bind("tcp://127.0.0.1:*")
+        .expect("failed to bind ZMQ publisher");
+    let zmq_url = publisher
+        .get_last_endpoint()
+        .expect("failed to get ZMQ publisher endpoint")
+        .expect("ZMQ publisher endpoint is not valid UTF-8");
+
+    // Create payment gateway pointing at mock daemon and ZMQ publisher.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .zmq_url(zmq_url)
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(37419570, 0, 10, "test invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // New invoices are picked up without waiting for a notification.
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+
+        let mut expected = MockInvoice::new(
+            Some(update.address().to_string()),
+            SubIndex::new(1, 97),
+            2477657,
+            37419570,
+            0,
+            10,
+            "test invoice".to_string(),
+        );
+        expected.assert_eq(&update);
+
+        // Give the payment gateway time to connect to the publisher.
+        sleep(Duration::from_millis(500)).await;
+
+        // Add transfer to txpool. While connected, the daemon isn't polled, so the
+        // transfer goes unnoticed.
+        let _txpool_hashes_mock =
+            mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment.json");
+        assert!(subscriber
+            .recv_timeout(Duration::from_millis(1000))
+            .await
+            .is_err());
+
+        // Announce the transfer. Keep announcing until it's noticed, in case the
+        // subscription hasn't reached the publisher yet.
+        let update = loop {
+            publisher
+                .send(r#"json-full-txpool_add:[{"id":"..."}]"#, 0)
+                .expect("failed to publish ZMQ notification");
+            if let Ok(update) = subscriber.recv_timeout(Duration::from_millis(200)).await {
+                break update.expect("subscription channel is closed");
+            }
+        };
+
+        expected.amount_paid = 37419570;
+        expected.confirmations = Some(0);
+        expected.is_confirmed = true;
+        expected.assert_eq(&update);
+    })
+}
+
+#[test]
+fn zmq_falls_back_to_polling() {
+    // Setup.
+    init_logger();
+    let mock_daemon = MockDaemon::new_mock_daemon();
+    let rt = Runtime::new().expect("failed to create tokio runtime");
+
+    // Create payment gateway pointing at mock daemon, and at a ZMQ publisher
+    // that doesn't exist.
+    let payment_gateway = PaymentGatewayBuilder::new(
+        PRIVATE_VIEW_KEY.to_string(),
+        PRIMARY_ADDRESS.to_string(),
+        InMemory::new(),
+    )
+    .scan_interval(Duration::from_millis(100))
+    .daemon_url(mock_daemon.url(""))
+    .zmq_url("tcp://127.0.0.1:1".to_string())
+    .account_index(1)
+    .seed(1)
+    .build()
+    .expect("failed to build payment gateway");
+
+    // Run it.
+    rt.block_on(async {
+        payment_gateway
+            .run()
+            .await
+            .expect("failed to run payment gateway");
+
+        // Add the invoice.
+        let invoice_id = payment_gateway
+            .new_invoice(37419570, 0, 10, "test invoice".to_string())
+            .expect("failed to add new invoice to payment gateway for tracking");
+        let mut subscriber = payment_gateway
+            .subscribe(invoice_id)
+            .expect("invoice does not exist");
+
+        // Get initial update.
+        subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+
+        // Add transfer to txpool. It's found by polling.
+        let _txpool_hashes_mock =
+            mock_daemon.mock_txpool_hashes("tests/rpc_resources/txpools/hashes_with_payment.json");
+        let update = subscriber
+            .recv_timeout(Duration::from_millis(5000))
+            .await
+            .expect("timeout waiting for invoice update")
+            .expect("subscription channel is closed");
+        assert_eq!(update.amount_paid(), 37419570);
+    })
+}